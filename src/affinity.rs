@@ -0,0 +1,58 @@
+use anyhow::{anyhow, ensure, Result};
+
+// hex bitmask, bit n is cpu n, e.g. "ff" or "0xf0"
+pub fn parse_mask(mask: &str) -> Result<Vec<usize>> {
+    let digits = mask.trim_start_matches("0x").trim_start_matches("0X");
+    ensure!(!digits.is_empty(), "empty cpu affinity mask");
+
+    let mut cpus = Vec::new();
+
+    for (i, c) in digits.chars().rev().enumerate() {
+        let nibble = c.to_digit(16).ok_or_else(|| anyhow!("invalid hex digit {:?} in cpu affinity mask", c))?;
+
+        for bit in 0..4 {
+            if nibble & (1 << bit) != 0 {
+                cpus.push(i * 4 + bit);
+            }
+        }
+    }
+
+    ensure!(!cpus.is_empty(), "cpu affinity mask selects no cpu");
+    Ok(cpus)
+}
+
+// inclusive range, e.g. "0-7"
+pub fn parse_range(range: &str) -> Result<Vec<usize>> {
+    let (start, end) = range.split_once('-').ok_or_else(|| anyhow!("cpu affinity range must be <start>-<end>"))?;
+    let start: usize = start.trim().parse()?;
+    let end: usize = end.trim().parse()?;
+    ensure!(start <= end, "cpu affinity range start {} is greater than end {}", start, end);
+
+    Ok((start..=end).collect())
+}
+
+// threads created afterwards inherit the mask, this covers the ggml compute threads of the context
+#[cfg(target_os = "linux")]
+pub fn set_current_thread(cpus: &[usize]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                warn!("cpu {} exceeds the cpu set size {}, ignored", cpu, libc::CPU_SETSIZE);
+                continue;
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+
+        let res = libc::pthread_setaffinity_np(libc::pthread_self(), std::mem::size_of::<libc::cpu_set_t>(), &set);
+
+        if res != 0 {
+            warn!("set cpu affinity failed: {}", std::io::Error::from_raw_os_error(res));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_current_thread(_cpus: &[usize]) {}
@@ -1,26 +1,50 @@
-use crate::{CompletionsTask, EmbeddingTask};
+use crate::infer::RequestControl;
+use crate::metadata;
+use crate::quality;
+use crate::request_log::{RequestLog, RequestLogEntry, TokenUsage};
+use crate::metrics::Metrics;
+use crate::sampler::{self, SamplerParams, SamplerStage};
+use crate::soft_prompt::SoftPrompt;
+use crate::{CompletionsEvent, CompletionsTask, EmbeddingTask, MaxTokens};
 use anyhow::{anyhow, ensure, Result};
-use async_openai::types::{Base64Embedding, Base64EmbeddingVector, ChatChoice, ChatChoiceStream, ChatCompletionMessageToolCall, ChatCompletionResponseMessage, ChatCompletionStreamResponseDelta, ChatCompletionToolType, Choice, CreateBase64EmbeddingResponse, CreateEmbeddingResponse, Embedding, EmbeddingInput, EmbeddingUsage, EncodingFormat, FinishReason, FunctionCall, Prompt, Role};
-use axum::body::Body;
-use axum::extract::State;
-use axum::http::StatusCode;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage, ChatCompletionRequestSystemMessageContent};
+use async_openai::types::{Base64Embedding, Base64EmbeddingVector, ChatChoice, ChatChoiceStream, ChatCompletionMessageToolCall, ChatCompletionResponseMessage, ChatCompletionStreamResponseDelta, ChatCompletionToolType, Choice, CreateBase64EmbeddingResponse, CreateEmbeddingResponse, Embedding, EmbeddingInput, EmbeddingUsage, EncodingFormat, FinishReason, FunctionCall, Prompt, PromptTokensDetails, Role};
+use axum::body::{Body, Bytes};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response, Sse};
-use axum::routing::post;
-use axum::{Json, Router};
+use axum::routing::{get, post};
+use axum::{middleware, Extension, Json, Router};
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use chrono::Utc;
-use futures_util::StreamExt;
+use flume::TrySendError;
+use futures_util::{Stream, StreamExt};
 use llama_cpp_2::model::{AddBos, LlamaModel, Special};
 use llama_cpp_2::token::LlamaToken;
 use llama_cpp_sys_2::{hibiki_body_to_chat_params, hibiki_common_chat_params_free, hibiki_common_chat_parse, hibiki_common_chat_templates_free, hibiki_common_chat_templates_from_model, hibiki_get_common_chat_params_format, hibiki_get_common_chat_params_prompt, hibiki_get_common_chat_params_prompt_length, HibikiCommonChatFormat, HibikiCommonChatParams, HibikiCommonChatTemplates};
-use serde::Deserialize;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
+use std::future::Future;
 use std::io::Write;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::ptr::null;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, watch, Notify};
+use tokio::task::JoinSet;
+use uuid::Uuid;
 use log::__private_api::loc;
 
 struct ChatTemplates {
@@ -32,19 +56,37 @@ unsafe impl Send for ChatTemplates {}
 unsafe impl Sync for ChatTemplates {}
 
 impl ChatTemplates {
-    fn from_model(model: &LlamaModel, tmpl: Option<&str>) -> Self {
+    fn from_model(model: &LlamaModel, tmpl: Option<&str>) -> Result<Self> {
         let cstr;
 
         let tmpl_cstr_ptr = if let Some(tmpl) = tmpl {
-            cstr = CString::new(tmpl).unwrap();
+            cstr = CString::new(tmpl)?;
             cstr.as_bytes_with_nul().as_ptr()
         } else {
             null()
         };
 
-        ChatTemplates {
-            inner: unsafe { hibiki_common_chat_templates_from_model(model.as_ptr(), tmpl_cstr_ptr as *const i8) },
-        }
+        let inner = unsafe { hibiki_common_chat_templates_from_model(model.as_ptr(), tmpl_cstr_ptr as *const i8) };
+        ensure!(!inner.is_null(), "failed to parse chat template");
+
+        Ok(ChatTemplates { inner })
+    }
+
+    // renders a sample conversation so that a malformed template fails at startup instead of at request time
+    fn validate(&self) -> Result<()> {
+        let body = serde_json::json!({
+            "messages": [
+                {"role": "system", "content": "You are a helpful assistant."},
+                {"role": "user", "content": "Hello"}
+            ]
+        });
+
+        let params = body_json_to_chat_params(self, body.to_string().as_str());
+        ensure!(!params.inner.is_null(), "failed to render chat template");
+
+        let prompt = params.get_prompt()?;
+        debug!("chat template sample prompt: {:?}", prompt);
+        Ok(())
     }
 
     #[allow(unused)]
@@ -133,346 +175,2463 @@ fn output_parse(msg: &str, format: HibikiCommonChatFormat) -> Result<CommonChatM
     }
 }
 
+// everything that decides the output of a seeded request, compared in full so that
+// a hash collision can't hand one prompt the output of another
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    model_name: String,
+    input_tokens: Vec<i32>,
+    // floats don't implement Eq, the debug output covers every sampling parameter
+    sampler_params: String,
+    maximum_tokens: MaxTokens,
+    soft_prompt: Option<String>,
+    // sorted
+    stop_token_ids: Vec<u32>,
+}
+
+// a non-streaming request that identical requests can subscribe to instead of running inference again
+struct InflightRequest {
+    // output so far, updated together with the broadcast so late subscribers miss nothing
+    generation: Generation,
+    // dropped when the request finishes
+    tx: Option<broadcast::Sender<CompletionsEvent>>,
+    failed: bool,
+}
+
+// removes the in-flight entry even when the leading request is dropped with its client,
+// subscribers of an unfinished request see it failed instead of waiting forever
+struct InflightGuard<'a> {
+    inflight_requests: &'a DashMap<RequestKey, Arc<Mutex<InflightRequest>>>,
+    key: RequestKey,
+    inflight: Arc<Mutex<InflightRequest>>,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.inflight_requests.remove(&self.key);
+        let mut inflight = self.inflight.lock().unwrap();
+
+        if inflight.tx.take().is_some() {
+            inflight.failed = true;
+        }
+    }
+}
+
 struct Context<Task> {
     model: Arc<LlamaModel>,
     model_name: String,
     backend_bridge: flume::Sender<Task>,
     kv_cache_size_pre_task: u32,
     chat_template: Option<Arc<ChatTemplates>>,
+    sse_heartbeat: Option<Duration>,
+    non_streaming_timeout: Option<Duration>,
+    chunked_responses: bool,
+    // a draft model is loaded, the speculative handlers can't score prompt tokens
+    speculative: bool,
+    // greedy non-streaming generations with a seed
+    response_cache: Option<Mutex<LruCache<RequestKey, Generation>>>,
+    max_tokens_per_second: Option<f32>,
+    context_limit: Option<ContextLimit>,
+    max_embedding_batch_size: Option<usize>,
+    soft_prompts: HashMap<String, Arc<SoftPrompt>>,
+    compaction: Option<Compaction>,
+    fim_template: Option<String>,
+    system_prompt: Option<SystemPrompt>,
+    inflight_requests: DashMap<RequestKey, Arc<Mutex<InflightRequest>>>,
+    metrics: Arc<Metrics>,
+    // active limit read by the inference loop, the context only has slots for max_parallel_tasks
+    parallel_tasks: Arc<AtomicU32>,
+    max_parallel_tasks: u32,
+    // reported as the created time of the model list
+    started_at: i64,
+    // streaming completions by request id, for pause and resume
+    request_controls: DashMap<String, Arc<RequestControl>>,
+    resumed: Arc<Notify>,
 }
 
-async fn completion_req_to_task(
-    req: async_openai::types::CreateCompletionRequest,
-    model: Arc<LlamaModel>,
-    callback: flume::Sender<LlamaToken>,
-) -> Result<CompletionsTask> {
-    tokio::task::spawn_blocking(move || {
-        let input_tokens = match req.prompt {
-            Prompt::String(prompt) => {
-                model.str_to_token(&prompt, AddBos::Always)?
+// removes the request from the controls once its stream is dropped
+struct ControlRegistration {
+    ctx: Arc<Context<CompletionsTask>>,
+    request_id: String,
+    control: Arc<RequestControl>,
+}
+
+impl ControlRegistration {
+    fn new(ctx: Arc<Context<CompletionsTask>>, request_id: &RequestId) -> Result<Self> {
+        let control = Arc::new(RequestControl::new(ctx.resumed.clone()));
+
+        // the request id comes from the client, a second stream must not take over the controls of the first
+        match ctx.request_controls.entry(request_id.to_string()) {
+            Entry::Occupied(_) => {
+                return Err(ApiError::Conflict(format!("a streaming completion with request id {} is already running", request_id)).into());
             }
-            _ => return Err(anyhow!("Only string prompts are supported")),
-        };
+            Entry::Vacant(entry) => {
+                entry.insert(control.clone());
+            }
+        }
 
-        let task = CompletionsTask {
-            to_api: callback,
-            maximum_tokens: req.max_tokens,
-            input_token_list: input_tokens,
-            frequency_penalty : req.frequency_penalty,
-            presence_penalty:  req.presence_penalty,
-            seed: req.seed,
-            temperature: req.temperature,
-            top_p: req.top_p
+        let registration = ControlRegistration {
+            ctx,
+            request_id: request_id.to_string(),
+            control,
         };
-        Result::<_, anyhow::Error>::Ok(task)
-    }).await?
-}
+        Ok(registration)
+    }
 
-async fn tokens_to_string(
-    tokens: Vec<LlamaToken>,
-    model: Arc<LlamaModel>
-) -> Result<String> {
-    tokio::task::spawn_blocking(move || {
-        let out = model.tokens_to_str(&tokens, Special::Plaintext)?;
-        Result::<_, anyhow::Error>::Ok(out)
-    }).await?
+    fn heartbeat<T>(&self) -> StreamChunk<T> {
+        if self.control.is_paused() {
+            StreamChunk::Paused
+        } else {
+            StreamChunk::Heartbeat
+        }
+    }
 }
 
-async fn send_to_backend<Task: Send + Sync + 'static>(
-    task: Task,
-    ctx: &Context<Task>
-) -> Result<()> {
-    ctx.backend_bridge.send_async(task).await?;
-    Ok(())
+impl Drop for ControlRegistration {
+    fn drop(&mut self) {
+        self.ctx.request_controls.remove_if(&self.request_id, |_, control| Arc::ptr_eq(control, &self.control));
+        // a paused sequence finds the closed stream once it runs again
+        self.control.resume();
+    }
 }
-// ret: (task, chat_template_format)
-async fn chat_completion_req_to_task(
-    req: async_openai::types::CreateChatCompletionRequest,
-    model: Arc<LlamaModel>,
-    callback: flume::Sender<LlamaToken>,
-    template: Arc<ChatTemplates>
-) -> Result<(CompletionsTask, HibikiCommonChatFormat)> {
-    tokio::task::spawn_blocking(move || {
-        let req_json = serde_json::to_string(&req)?;
-        let params = body_json_to_chat_params(&template, req_json.as_str());
-        debug!("body_json_to_chat_params finished");
-
-        let prompt = params.get_prompt()?;
-        debug!("prompt: {:?}", prompt);
 
-        let format = params.get_chat_format();
-
-        let input_tokens = model.str_to_token(&prompt, AddBos::Always)?;
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    // a request body that failed validation, answered in the openai error format
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+    },
+    NotFound(String),
+    Conflict(String),
+    ServiceUnavailable {
+        message: String,
+        // seconds, sent as the Retry-After header
+        retry_after: u64,
+    },
+}
 
-        let task = CompletionsTask {
-            to_api: callback,
-            #[allow(deprecated)]
-            maximum_tokens: req.max_tokens,
-            input_token_list: input_tokens,
-            frequency_penalty : req.frequency_penalty,
-            presence_penalty:  req.presence_penalty,
-            seed: req.seed,
-            temperature: req.temperature,
-            top_p: req.top_p
-        };
-        Result::<_, anyhow::Error>::Ok((task, format))
-    }).await?
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(msg) => write!(f, "{}", msg),
+            ApiError::InvalidRequest { message, .. } => write!(f, "{}", message),
+            ApiError::NotFound(msg) => write!(f, "{}", msg),
+            ApiError::Conflict(msg) => write!(f, "{}", msg),
+            ApiError::ServiceUnavailable { message, .. } => write!(f, "{}", message),
+        }
+    }
 }
 
-async fn v1_chat_completions(
-    State(ctx): State<Arc<Context<CompletionsTask>>>,
-    Json(req): Json<async_openai::types::CreateChatCompletionRequest>
-) -> Response {
-    debug!("v1_chat_completions: {:?}", req);
+impl std::error::Error for ApiError {}
 
-    let is_stream = req.stream.unwrap_or(false);
-    let (tx, rx) = flume::unbounded();
-    let chat_completion_id = rand::random::<u64>().to_string();
+// Retry-After in integer seconds, repeated in the body for clients that only read the body
+fn retry_after_response(status: StatusCode, message: &str, retry_after: u64) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "server_error",
+            "retry_after": retry_after,
+        }
+    });
+
+    Response::builder()
+        .status(status)
+        .header(RETRY_AFTER, retry_after)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
 
-    let fut = async {
-        if is_stream {
-            ensure!(req.tools.is_none());
+fn error_response(e: &anyhow::Error) -> Response {
+    let status = match e.downcast_ref::<ApiError>() {
+        Some(ApiError::ServiceUnavailable { message, retry_after }) => {
+            return retry_after_response(StatusCode::SERVICE_UNAVAILABLE, message, *retry_after);
         }
+        Some(ApiError::InvalidRequest { message, param }) => {
+            let body = serde_json::json!({
+                "error": {
+                    "message": message,
+                    "type": "invalid_request_error",
+                    "param": param,
+                }
+            });
 
-        let (task, format) = chat_completion_req_to_task(req, ctx.model.clone(), tx, ctx.chat_template.as_ref().unwrap().clone()).await?;
-        debug!("chat_completion_req_to_task finished");
-        let prompt_tokens = task.input_token_list.len() as u32;
-        ensure!(prompt_tokens < ctx.kv_cache_size_pre_task, "Prompt too large, prompt tokens len: {prompt_tokens}");
-        send_to_backend(task, &*ctx).await?;
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+        }
+        Some(ApiError::BadRequest(_)) => StatusCode::BAD_REQUEST,
+        Some(ApiError::NotFound(_)) => StatusCode::NOT_FOUND,
+        Some(ApiError::Conflict(_)) => StatusCode::CONFLICT,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
 
-        let resp = if is_stream {
-            let mut single_token_bytes = Vec::new();
+    Response::builder()
+        .status(status)
+        .body(Body::from(e.to_string()))
+        .unwrap()
+}
 
-            let out_stream = rx.into_stream()
-                .map(move |token| {
-                    let token_bytes = ctx.model.token_to_bytes(token, Special::Plaintext)?;
-                    single_token_bytes.extend_from_slice(&token_bytes);
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
 
-                    let text = match String::from_utf8(single_token_bytes.clone()) {
-                        Ok(v) => v,
-                        Err(_) => return Ok(None)
-                    };
+#[derive(Clone)]
+struct RequestId(String);
 
-                    single_token_bytes.clear();
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-                    if log::max_level() >= log::Level::Debug {
-                        print!("{}", text);
-                        std::io::stdout().flush()?;
-                    }
+// a valid uuid sent by the client is kept, so its logs can be correlated with ours
+async fn request_id_layer(mut req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/v1/") {
+        return next.run(req).await;
+    }
 
-                    let chat_completion_resp = async_openai::types::CreateChatCompletionStreamResponse {
-                        id: chat_completion_id.clone(),
-                        choices: vec![
-                            ChatChoiceStream {
-                                index: 0,
-                                #[allow(deprecated)]
-                                delta: ChatCompletionStreamResponseDelta {
-                                    content: Some(text),
-                                    refusal: None,
-                                    tool_calls: None,
-                                    role: Some(Role::Assistant),
-                                    function_call: None
-                                },
-                                finish_reason: None,
-                                logprobs: None,
-                            }
-                        ],
-                        created: Utc::now().timestamp() as u32,
-                        model: ctx.model_name.clone(),
-                        service_tier: None,
-                        system_fingerprint: None,
-                        object: String::from("chat.completion.chunk"),
-                        usage: None
-                    };
+    let request_id = req.headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| Uuid::parse_str(v).ok())
+        .unwrap_or_else(Uuid::new_v4)
+        .to_string();
 
-                    let event = axum::response::sse::Event::default()
-                        .json_data(&chat_completion_resp)?;
+    req.extensions_mut().insert(RequestId(request_id.clone()));
 
-                    Result::<_, anyhow::Error>::Ok(Some(event))
-                })
-                .filter_map(|v| async {
-                    v.transpose()
-                })
-                .chain(futures_util::stream::once(async {
-                    debug!("v1_chat_completions stream end");
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(REQUEST_ID_HEADER.clone(), HeaderValue::from_str(&request_id).unwrap());
+    resp
+}
 
-                    let event = axum::response::sse::Event::default()
-                        .data("[DONE]");
-                    Ok(event)
-                }));
+// same as the default body limit of the Json extractor
+const REQUEST_LOG_BODY_LIMIT: usize = 2 * 1024 * 1024;
 
-            Sse::new(out_stream)
-                .keep_alive(
-                    axum::response::sse::KeepAlive::new()
-                        .interval(Duration::from_secs(1))
-                        .text("keep-alive-text"),
-                )
-                .into_response()
-        } else {
-            let mut out_tokens = Vec::new();
-            while let Ok(token) = rx.recv_async().await {
-                out_tokens.push(token);
+// marks a json body sent while it's generated, e.g. a --chunked-responses completion
+#[derive(Clone, Copy)]
+struct StreamedBody;
 
-                if log::max_level() >= log::Level::Debug {
-                    let token_bytes = ctx.model.token_to_bytes(token, Special::Plaintext)?;
-                    let s = String::from_utf8_lossy(&token_bytes);
-                    let mut lock = std::io::stdout().lock();
-                    lock.write_all(s.as_bytes())?;
-                    lock.flush()?;
-                }
-            }
+fn is_stream_response(resp: &Response) -> bool {
+    let streamed_content_type = resp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream") || v.starts_with("application/x-ndjson"));
 
-            let completion_tokens = out_tokens.len() as u32;
-            let text = tokens_to_string(out_tokens, ctx.model.clone()).await?;
-            let chat_msg = output_parse(text.as_str(), format)?;
-            debug!("chat_msg: {:?}", chat_msg);
+    streamed_content_type || resp.extensions().get::<StreamedBody>().is_some()
+}
 
-            let chat_completion_resp = async_openai::types::CreateChatCompletionResponse {
-                id: chat_completion_id,
-                choices: vec![
-                    ChatChoice {
-                        finish_reason: {
-                            if !chat_msg.tool_calls.is_empty() {
-                                Some(FinishReason::ToolCalls)
-                            } else {
-                                None
-                            }
-                        },
-                        index: 0,
-                        #[allow(deprecated)]
-                        message: ChatCompletionResponseMessage {
-                            content: Some(chat_msg.content),
-                            refusal: None,
-                            tool_calls: {
-                                let list = chat_msg.tool_calls.into_iter().map(|tool_call| {
-                                    ChatCompletionMessageToolCall {
-                                        id: {
-                                            if tool_call.id.is_empty() {
-                                                format!("call_def_{}", rand::random::<u32>())
-                                            } else {
-                                                tool_call.id
-                                            }
-                                        },
-                                        r#type: ChatCompletionToolType::Function,
-                                        function: FunctionCall {
-                                            name: tool_call.name,
-                                            arguments: tool_call.arguments,
-                                        }
-                                    }
-                                })
-                                .collect::<Vec<_>>();
+// the latency is measured until the response headers, streaming bodies are never buffered
+async fn request_log_layer(State(log): State<Arc<RequestLog>>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/v1/") {
+        return next.run(req).await;
+    }
 
-                                Some(list)
-                            },
-                            role: Role::Assistant,
-                            function_call: None,
-                            audio: None
-                        },
-                        logprobs: None,
-                    }
-                ],
-                created: Utc::now().timestamp() as u32,
-                model: ctx.model_name.clone(),
-                service_tier: None,
-                system_fingerprint: None,
-                object: String::from("chat.completion"),
-                usage: Some(async_openai::types::CompletionUsage{
-                    prompt_tokens,
-                    completion_tokens,
-                    total_tokens: prompt_tokens + completion_tokens,
-                    prompt_tokens_details: None,
-                    completion_tokens_details: None
-                })
-            };
+    let start = Instant::now();
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
 
-            let body = serde_json::to_vec(&chat_completion_resp)?;
-            Response::new(Body::from(body))
-        };
+    let (parts, body) = req.into_parts();
 
-        Result::<_, anyhow::Error>::Ok(resp)
+    let request_body = match axum::body::to_bytes(body, REQUEST_LOG_BODY_LIMIT).await {
+        Ok(body) => body,
+        Err(e) => return error_response(&ApiError::BadRequest(e.to_string()).into()),
     };
 
-    match fut.await {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("v1_caht_completions error: {:?}", e);
+    let resp = next.run(Request::from_parts(parts, Body::from(request_body.clone()))).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let usage = resp.extensions().get::<TokenUsage>().copied();
+
+    let entry = RequestLogEntry::new(
+        request_id,
+        method,
+        path,
+        &request_body,
+        resp.status().as_u16(),
+        latency_ms,
+        usage,
+    );
+
+    if !log.full_body {
+        log.write(entry);
+        return resp;
+    }
 
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(e.to_string()))
-                .unwrap()
+    if is_stream_response(&resp) {
+        log.write(entry.with_bodies(&request_body, None));
+        return resp;
+    }
+
+    let (parts, body) = resp.into_parts();
+
+    let response_body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            log.write(entry.with_bodies(&request_body, None));
+            return error_response(&anyhow!(e));
         }
+    };
+
+    log.write(entry.with_bodies(&request_body, Some(&response_body)));
+    Response::from_parts(parts, Body::from(response_body))
+}
+
+// --log-requests, one info line when a request arrives and one when its response is ready
+#[derive(Clone, Copy)]
+pub struct LogRequests {
+    // prompts may contain PII, they are only logged when asked for
+    pub content: bool,
+}
+
+fn body_field(body: &serde_json::Value, name: &str) -> String {
+    match body.get(name) {
+        None | Some(serde_json::Value::Null) => String::from("-"),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
     }
 }
 
-async fn v1_completions(
-    State(ctx): State<Arc<Context<CompletionsTask>>>,
-    Json(req): Json<async_openai::types::CreateCompletionRequest>
-) -> Response {
-    debug!("v1_completions: {:?}", req);
+async fn log_requests_layer(State(config): State<LogRequests>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/v1/") {
+        return next.run(req).await;
+    }
 
-    let is_stream = req.stream.unwrap_or(false);
-    let (tx, rx) = flume::unbounded();
-    let completion_id = rand::random::<u64>().to_string();
+    let start = Instant::now();
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string()).unwrap_or_default();
+    let client = req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_string())
+        .unwrap_or_else(|| String::from("-"));
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
 
-    let fut = async {
-        let task = completion_req_to_task(req, ctx.model.clone(), tx).await?;
-        let prompt_tokens = task.input_token_list.len() as u32;
-        ensure!(prompt_tokens < ctx.kv_cache_size_pre_task, "Prompt too large");
-        send_to_backend(task, &*ctx).await?;
+    let (parts, body) = req.into_parts();
 
-        let resp = if is_stream {
-            let mut single_token_bytes = Vec::new();
+    let request_body = match axum::body::to_bytes(body, REQUEST_LOG_BODY_LIMIT).await {
+        Ok(body) => body,
+        Err(e) => return error_response(&ApiError::BadRequest(e.to_string()).into()),
+    };
+    let fields = serde_json::from_slice::<serde_json::Value>(&request_body).unwrap_or_default();
+
+    info!(
+        "[{}] request: method={} path={} client={} model={} max_tokens={} stream={}",
+        request_id,
+        method,
+        path,
+        client,
+        body_field(&fields, "model"),
+        body_field(&fields, "max_tokens"),
+        fields.get("stream").and_then(|v| v.as_bool()).unwrap_or(false)
+    );
+
+    if config.content {
+        for name in ["prompt", "messages", "input"] {
+            if let Some(content) = fields.get(name) {
+                info!("[{}] request {}: {}", request_id, name, content);
+            }
+        }
+    }
 
-            let out_stream = rx.into_stream()
-                .map(move |token| {
-                    let token_bytes = ctx.model.token_to_bytes(token, Special::Plaintext)?;
-                    single_token_bytes.extend_from_slice(&token_bytes);
+    let resp = next.run(Request::from_parts(parts, Body::from(request_body))).await;
+    let usage = resp.extensions().get::<TokenUsage>().copied();
+    let status = resp.status();
 
-                    let text = match String::from_utf8(single_token_bytes.clone()) {
-                        Ok(v) => v,
-                        Err(_) => return Ok(None)
-                    };
+    // streams report their finish reason in the last chunk, long after the response started
+    let (resp, finish_reason) = if is_stream_response(&resp) || !status.is_success() {
+        (resp, String::from("-"))
+    } else {
+        let (parts, body) = resp.into_parts();
 
-                    single_token_bytes.clear();
+        let response_body = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(body) => body,
+            Err(e) => return error_response(&anyhow!(e)),
+        };
 
-                    let completion_resp = async_openai::types::CreateCompletionResponse {
-                        id: completion_id.clone(),
-                        choices: vec![Choice{
-                            text,
-                            index: 0,
-                            logprobs: None,
-                            finish_reason: None
-                        }],
-                        created: Utc::now().timestamp() as u32,
-                        model: ctx.model_name.clone(),
-                        system_fingerprint: None,
-                        object: "text_completion".to_string(),
-                        usage: None
-                    };
+        let finish_reason = serde_json::from_slice::<serde_json::Value>(&response_body)
+            .ok()
+            .and_then(|v| v.get("choices")?.get(0).cloned())
+            .map(|choice| body_field(&choice, "finish_reason"))
+            .unwrap_or_else(|| String::from("-"));
 
-                    let event = axum::response::sse::Event::default()
-                        .json_data(&completion_resp)?;
+        (Response::from_parts(parts, Body::from(response_body)), finish_reason)
+    };
 
-                    Result::<_, anyhow::Error>::Ok(Some(event))
+    info!(
+        "[{}] response: status={} prompt_tokens={} completion_tokens={} finish_reason={} latency_ms={}",
+        request_id,
+        status.as_u16(),
+        usage.map(|u| u.prompt_tokens.to_string()).unwrap_or_else(|| String::from("-")),
+        usage.map(|u| u.completion_tokens.to_string()).unwrap_or_else(|| String::from("-")),
+        finish_reason,
+        start.elapsed().as_millis()
+    );
+    resp
+}
+
+#[derive(Clone)]
+struct ConcurrencyLimit {
+    metrics: Arc<Metrics>,
+    max_concurrent_requests: Option<usize>,
+    parallel_tasks: Arc<AtomicU32>,
+}
+
+struct ConcurrentRequest {
+    metrics: Arc<Metrics>,
+    // set once the request is admitted, a rejected request isn't counted in the mean duration
+    start: Option<Instant>,
+}
+
+impl ConcurrentRequest {
+    fn new(metrics: Arc<Metrics>) -> (Self, usize) {
+        let n = metrics.concurrent_requests.fetch_add(1, Ordering::Relaxed);
+        (ConcurrentRequest { metrics, start: None }, n)
+    }
+}
+
+impl Drop for ConcurrentRequest {
+    fn drop(&mut self) {
+        self.metrics.concurrent_requests.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(start) = self.start {
+            self.metrics.record_request_duration(start.elapsed());
+        }
+    }
+}
+
+// rejects before the request reaches the inference queue, a streaming request is in flight until its body ends
+async fn concurrency_limit_layer(State(limit): State<ConcurrencyLimit>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/v1/") {
+        return next.run(req).await;
+    }
+
+    let (mut guard, n) = ConcurrentRequest::new(limit.metrics.clone());
+
+    if let Some(max) = limit.max_concurrent_requests.filter(|max| n >= *max) {
+        drop(guard);
+
+        let retry_after = limit.metrics.retry_after(n - max, limit.parallel_tasks.load(Ordering::Relaxed));
+        return retry_after_response(StatusCode::SERVICE_UNAVAILABLE, "server at capacity", retry_after);
+    }
+    guard.start = Some(Instant::now());
+
+    let (parts, body) = next.run(req).await.into_parts();
+
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+static BUDGET_WARNING_HEADER: HeaderName = HeaderName::from_static("x-budget-warning");
+
+// only new generation requests are checked, a running stream finishes even if it crosses the budget
+async fn token_budget_layer(State(metrics): State<Arc<Metrics>>, req: Request, next: Next) -> Response {
+    let budget = match &metrics.token_budget {
+        Some(budget) if req.method() == Method::POST && req.uri().path().starts_with("/v1/") => budget,
+        _ => return next.run(req).await,
+    };
+
+    let used = budget.used();
+
+    if used >= budget.per_hour {
+        let retry_after = budget.retry_after().as_secs_f64().ceil() as u64;
+        return retry_after_response(StatusCode::from_u16(budget.exhausted_status).unwrap(), "token budget exhausted", retry_after.max(1));
+    }
+
+    let mut resp = next.run(req).await;
+
+    if used * 5 >= budget.per_hour * 4 {
+        resp.headers_mut().insert(&BUDGET_WARNING_HEADER, HeaderValue::from_static("80%"));
+    }
+    resp
+}
+
+// outermost first: request id, concurrency limit, token budget, --log-requests, request log
+fn api_layers(
+    router: Router,
+    request_log: Option<Arc<RequestLog>>,
+    log_requests: Option<LogRequests>,
+    limit: ConcurrencyLimit,
+) -> Router {
+    let router = match request_log {
+        None => router,
+        Some(log) => router.layer(middleware::from_fn_with_state(log, request_log_layer)),
+    };
+
+    let router = match log_requests {
+        None => router,
+        Some(config) => router.layer(middleware::from_fn_with_state(config, log_requests_layer)),
+    };
+
+    let router = if limit.metrics.token_budget.is_some() {
+        router.layer(middleware::from_fn_with_state(limit.metrics.clone(), token_budget_layer))
+    } else {
+        router
+    };
+
+    router
+        .layer(middleware::from_fn_with_state(limit, concurrency_limit_layer))
+        .layer(middleware::from_fn(request_id_layer))
+}
+
+// request fields beyond the openai api
+#[derive(Deserialize, Debug, Default, Clone)]
+struct SamplingExtension {
+    top_k: Option<i32>,
+    min_p: Option<f32>,
+    top_a: Option<f32>,
+    top_n_sigma: Option<f32>,
+    typical_p: Option<f32>,
+    dry_multiplier: Option<f32>,
+    sampler_order: Option<Vec<String>>,
+    confidence_threshold: Option<f32>,
+    presence_penalty_context_window: Option<u32>,
+    penalty_reset_on_newline: Option<bool>,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: Option<u32>,
+}
+
+impl SamplingExtension {
+    fn to_sampler_params(
+        &self,
+        frequency_penalty: Option<f32>,
+        presence_penalty: Option<f32>,
+        seed: Option<i64>,
+        temperature: Option<f32>,
+        top_p: Option<f32>,
+    ) -> Result<SamplerParams> {
+        let sampler_order = match &self.sampler_order {
+            None => None,
+            Some(order) => {
+                let order = order.iter()
+                    .map(|stage| stage.parse::<SamplerStage>())
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+                Some(order)
+            }
+        };
+
+        let params = SamplerParams {
+            frequency_penalty,
+            presence_penalty,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            presence_penalty_context_window: self.presence_penalty_context_window,
+            penalty_reset_on_newline: self.penalty_reset_on_newline,
+            seed,
+            temperature,
+            top_p,
+            top_k: self.top_k,
+            min_p: self.min_p,
+            top_a: self.top_a,
+            top_n_sigma: self.top_n_sigma,
+            typical_p: self.typical_p,
+            dry_multiplier: self.dry_multiplier,
+            sampler_order,
+            confidence_threshold: self.confidence_threshold,
+        };
+        Ok(params)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionRequest {
+    #[serde(flatten)]
+    inner: async_openai::types::CreateCompletionRequest,
+    #[serde(flatten)]
+    sampling: SamplingExtension,
+    soft_prompt_id: Option<String>,
+    // candidates generated with independent seeds, the one with the fewest repeated n-grams is returned
+    best_n: Option<u32>,
+    // false skips speculative decoding, e.g. for short completions where drafting costs more than it saves
+    use_speculative: Option<bool>,
+    // throttles a stream for clients that can't take tokens faster
+    max_tokens_per_second: Option<f32>,
+    // ids that end the generation like the end-of-generation token
+    stop_token_ids: Option<Vec<u32>>,
+    // adds usage.timing to a non-streaming response
+    include_timing: Option<bool>,
+    // wrap the prompt for models without a chat template, tokenized on their own and counted as prompt tokens
+    pre_prompt: Option<String>,
+    post_prompt: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ChatCompletionRequest {
+    #[serde(flatten)]
+    inner: async_openai::types::CreateChatCompletionRequest,
+    #[serde(flatten)]
+    sampling: SamplingExtension,
+    soft_prompt_id: Option<String>,
+    // candidates generated with independent seeds, the one with the fewest repeated n-grams is returned
+    best_n: Option<u32>,
+    // false skips speculative decoding, e.g. for short completions where drafting costs more than it saves
+    use_speculative: Option<bool>,
+    // throttles a stream for clients that can't take tokens faster
+    max_tokens_per_second: Option<f32>,
+    // ids that end the generation like the end-of-generation token
+    stop_token_ids: Option<Vec<u32>>,
+    // adds usage.timing to a non-streaming response
+    include_timing: Option<bool>,
+}
+
+// yields None when no token arrived within the heartbeat interval,
+// the caller sends an sse comment that clients ignore but keeps proxies from closing the connection
+// token_gap only delays tokens that arrive faster than the rate, the inference loop keeps running meanwhile
+// the generation is updated as events arrive, for the finish chunk sent at the end of the stream
+fn heartbeat_token_stream(
+    rx: flume::Receiver<CompletionsEvent>,
+    heartbeat: Duration,
+    token_gap: Option<Duration>,
+    generation: Arc<Mutex<Generation>>,
+) -> impl Stream<Item = Option<LlamaToken>> {
+    let interval = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat, heartbeat);
+
+    // (min gap between two tokens, earliest time of the next token)
+    let throttle = token_gap.map(|gap| (gap, tokio::time::Instant::now()));
+    // a token held back by the throttle
+    let held: Option<LlamaToken> = None;
+
+    futures_util::stream::unfold((rx, interval, throttle, held, generation), |(rx, mut interval, mut throttle, mut held, generation)| async move {
+        loop {
+            if let (Some(token), Some((gap, next))) = (held, throttle.as_mut()) {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(*next) => {
+                        *next += *gap;
+                        interval.reset();
+                        return Some((Some(token), (rx, interval, throttle, None, generation)));
+                    }
+                    _ = interval.tick() => return Some((None, (rx, interval, throttle, held, generation)))
+                }
+            }
+
+            tokio::select! {
+                res = rx.recv_async() => {
+                    let event = res.ok()?;
+                    generation.lock().unwrap().push(event);
+
+                    match event {
+                        CompletionsEvent::Token(token) => {
+                            if let Some((gap, next)) = throttle.as_mut() {
+                                let now = tokio::time::Instant::now();
+
+                                if now < *next {
+                                    held = Some(token);
+                                    continue;
+                                }
+                                *next = now + *gap;
+                            }
+
+                            interval.reset();
+                            return Some((Some(token), (rx, interval, throttle, held, generation)));
+                        }
+                        CompletionsEvent::Started(_) |
+                        CompletionsEvent::PromptCached(_) |
+                        CompletionsEvent::Injected(_) |
+                        CompletionsEvent::Confident(_) |
+                        CompletionsEvent::Length |
+                        CompletionsEvent::PromptLogprob(_) => continue,
+                        // the status line is already sent, the finish chunk aborts the body instead
+                        CompletionsEvent::Failed => return None,
+                    }
+                }
+                _ = interval.tick() => return Some((None, (rx, interval, throttle, held, generation)))
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    stream_format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum StreamFormat {
+    Sse,
+    Ndjson,
+}
+
+impl StreamFormat {
+    fn from_request(headers: &HeaderMap, query: &StreamQuery) -> Self {
+        if query.stream_format.as_deref() == Some("ndjson") {
+            return StreamFormat::Ndjson;
+        }
+
+        let accept_ndjson = headers.get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.contains("application/x-ndjson"));
+
+        if accept_ndjson {
+            StreamFormat::Ndjson
+        } else {
+            StreamFormat::Sse
+        }
+    }
+}
+
+enum StreamChunk<T> {
+    Data(T),
+    Heartbeat,
+    // heartbeat of a paused generation
+    Paused,
+    // the last data chunk, built as json because async_openai has no confidence finish_reason
+    Finish(serde_json::Value),
+    Done,
+}
+
+fn stream_response<T: Serialize + Send + 'static>(
+    format: StreamFormat,
+    chunks: impl Stream<Item = Result<StreamChunk<T>>> + Send + 'static,
+) -> Response {
+    match format {
+        StreamFormat::Sse => {
+            let events = chunks.map(|chunk| {
+                let event = match chunk? {
+                    StreamChunk::Data(data) => axum::response::sse::Event::default().json_data(&data)?,
+                    StreamChunk::Finish(value) => axum::response::sse::Event::default().json_data(&value)?,
+                    StreamChunk::Heartbeat => axum::response::sse::Event::default().comment("ping"),
+                    StreamChunk::Paused => axum::response::sse::Event::default().comment("paused"),
+                    StreamChunk::Done => axum::response::sse::Event::default().data("[DONE]"),
+                };
+                Result::<_, anyhow::Error>::Ok(event)
+            });
+
+            Sse::new(events).into_response()
+        }
+        StreamFormat::Ndjson => {
+            let lines = chunks.filter_map(|chunk| async move {
+                let line = match chunk {
+                    Ok(StreamChunk::Data(data)) => serde_json::to_vec(&data),
+                    Ok(StreamChunk::Finish(value)) => serde_json::to_vec(&value),
+                    Ok(StreamChunk::Heartbeat) | Ok(StreamChunk::Paused) => return None,
+                    // the finish chunk carries the finish_reason, the body just ends
+                    Ok(StreamChunk::Done) => return None,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                let res = line.map(|mut line| {
+                    line.push(b'\n');
+                    line
+                });
+                Some(res.map_err(anyhow::Error::from))
+            });
+
+            Response::builder()
+                .header(CONTENT_TYPE, "application/x-ndjson")
+                .body(Body::from_stream(lines))
+                .unwrap()
+        }
+    }
+}
+
+fn check_stop_token_ids(ids: Option<Vec<u32>>, model: &LlamaModel) -> Result<HashSet<u32>> {
+    let ids = ids.unwrap_or_default();
+    let n_vocab = model.n_vocab() as u32;
+
+    if let Some(id) = ids.iter().find(|id| **id >= n_vocab) {
+        let message = format!("Field 'stop_token_ids' has token id {} outside the vocabulary of {} tokens", id, n_vocab);
+        return Err(invalid_request(Some("stop_token_ids"), message));
+    }
+    Ok(ids.into_iter().collect())
+}
+
+async fn completion_req_to_task(
+    req: CompletionRequest,
+    model: Arc<LlamaModel>,
+    callback: flume::Sender<CompletionsEvent>,
+    request_id: String,
+) -> Result<CompletionsTask> {
+    tokio::task::spawn_blocking(move || {
+        let sampler_params = req.sampling.to_sampler_params(
+            req.inner.frequency_penalty,
+            req.inner.presence_penalty,
+            req.inner.seed,
+            req.inner.temperature,
+            req.inner.top_p,
+        )?;
+        let disable_speculative = !req.use_speculative.unwrap_or(true);
+        let stop_token_ids = check_stop_token_ids(req.stop_token_ids, &model)?;
+        let (pre_prompt, post_prompt) = (req.pre_prompt, req.post_prompt);
+        let req = req.inner;
+
+        let prompt = match req.prompt {
+            Prompt::String(prompt) => prompt,
+            _ => return Err(anyhow!("Only string prompts are supported")),
+        };
+
+        // the bos token goes before the pre_prompt
+        let mut input_tokens = match &pre_prompt {
+            Some(pre_prompt) => model.str_to_token(pre_prompt, AddBos::Always)?,
+            None => Vec::new(),
+        };
+
+        let add_bos = if pre_prompt.is_some() { AddBos::Never } else { AddBos::Always };
+        input_tokens.extend(model.str_to_token(&prompt, add_bos)?);
+
+        if let Some(post_prompt) = &post_prompt {
+            input_tokens.extend(model.str_to_token(post_prompt, AddBos::Never)?);
+        }
+
+        let task = CompletionsTask {
+            to_api: callback,
+            maximum_tokens: MaxTokens::from(req.max_tokens),
+            input_token_list: input_tokens,
+            sampler_params,
+            soft_prompt: None,
+            injections: None,
+            history_len: None,
+            request_id,
+            control: None,
+            prompt_logprobs: false,
+            disable_speculative,
+            enqueued_at: Instant::now(),
+            stop_token_ids,
+        };
+        Result::<_, anyhow::Error>::Ok(task)
+    }).await?
+}
+
+async fn tokens_to_string(
+    tokens: Vec<LlamaToken>,
+    model: Arc<LlamaModel>
+) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let out = model.tokens_to_str(&tokens, Special::Plaintext)?;
+        Result::<_, anyhow::Error>::Ok(out)
+    }).await?
+}
+
+fn find_soft_prompt<Task>(ctx: &Context<Task>, soft_prompt_id: Option<&str>) -> Result<Option<Arc<SoftPrompt>>> {
+    let id = match soft_prompt_id {
+        None => return Ok(None),
+        Some(id) => id,
+    };
+
+    let soft_prompt = ctx.soft_prompts.get(id)
+        .ok_or_else(|| ApiError::BadRequest(format!("unknown soft prompt: {}", id)))?;
+
+    // the embeddings are copied into the batch as is, a wrong width would read out of bounds
+    if soft_prompt.n_embd != ctx.model.n_embd() as usize {
+        let msg = format!(
+            "soft prompt {} has embedding size {} but the model expects {}",
+            id,
+            soft_prompt.n_embd,
+            ctx.model.n_embd()
+        );
+        return Err(ApiError::BadRequest(msg).into());
+    }
+    Ok(Some(soft_prompt.clone()))
+}
+
+fn send_to_backend<Task: Send + Sync + 'static>(
+    task: Task,
+    ctx: &Context<Task>
+) -> Result<()> {
+    try_enqueue(&ctx.backend_bridge, task, &ctx.metrics, &ctx.parallel_tasks)
+}
+
+// rejects the task instead of blocking when the inference queue is full, also used by the grpc api
+pub fn try_enqueue<Task>(
+    backend_bridge: &flume::Sender<Task>,
+    task: Task,
+    metrics: &Metrics,
+    parallel_tasks: &AtomicU32,
+) -> Result<()> {
+    match backend_bridge.try_send(task) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(queue_full(backend_bridge, metrics, parallel_tasks)),
+        Err(TrySendError::Disconnected(_)) => Err(anyhow!("backend channel disconnected")),
+    }
+}
+
+fn queue_full<Task>(backend_bridge: &flume::Sender<Task>, metrics: &Metrics, parallel_tasks: &AtomicU32) -> anyhow::Error {
+    metrics.queue_dropped.fetch_add(1, Ordering::Relaxed);
+
+    let retry_after = metrics.retry_after(backend_bridge.len(), parallel_tasks.load(Ordering::Relaxed));
+
+    ApiError::ServiceUnavailable {
+        message: String::from("inference queue is full"),
+        retry_after,
+    }.into()
+}
+
+// only requests with an explicit seed are deterministic enough to share the output, -1 asks for a random one
+fn request_key(model_name: &str, task: &CompletionsTask) -> Option<RequestKey> {
+    sampler::effective_seed(task.sampler_params.seed)?;
+
+    let mut stop_token_ids = task.stop_token_ids.iter().copied().collect::<Vec<_>>();
+    stop_token_ids.sort();
+
+    let key = RequestKey {
+        model_name: model_name.to_string(),
+        input_tokens: task.input_token_list.iter().map(|t| t.0).collect(),
+        sampler_params: format!("{:?}", task.sampler_params),
+        maximum_tokens: task.maximum_tokens,
+        soft_prompt: task.soft_prompt.as_ref().map(|p| p.name.clone()),
+        stop_token_ids,
+    };
+    Some(key)
+}
+
+#[derive(Clone, Default)]
+struct Generation {
+    tokens: Vec<LlamaToken>,
+    prompt_tokens_cached: u32,
+    confidence_token: Option<LlamaToken>,
+    // cut off by the non-streaming response timeout
+    timed_out: bool,
+    // ended at max_tokens or at the end of the kv cache of the task
+    reached_length: bool,
+    timing: GenerationTiming,
+    // returned from --response-cache-size without inference
+    cache_hit: bool,
+    failed: bool,
+}
+
+impl Generation {
+    fn push(&mut self, event: CompletionsEvent) {
+        self.timing.record(event);
+
+        match event {
+            CompletionsEvent::Token(token) => self.tokens.push(token),
+            CompletionsEvent::PromptCached(n) => self.prompt_tokens_cached = n,
+            CompletionsEvent::Started(_) | CompletionsEvent::Injected(_) | CompletionsEvent::PromptLogprob(_) => (),
+            CompletionsEvent::Confident(token) => self.confidence_token = Some(token),
+            CompletionsEvent::Length => self.reached_length = true,
+            CompletionsEvent::Failed => self.failed = true,
+        }
+    }
+
+    // "confidence" and "timeout" aren't openai values, the rest are
+    fn finish_reason(&self) -> &'static str {
+        if self.timed_out {
+            "timeout"
+        } else if self.confidence_token.is_some() {
+            "confidence"
+        } else if self.reached_length {
+            "length"
+        } else {
+            "stop"
+        }
+    }
+}
+
+// measured from the dequeue of the task by the inference loop, so the queue wait isn't included
+#[derive(Clone, Copy, Default)]
+struct GenerationTiming {
+    started_at: Option<Instant>,
+    first_token_at: Option<Instant>,
+    last_token_at: Option<Instant>,
+}
+
+impl GenerationTiming {
+    fn record(&mut self, event: CompletionsEvent) {
+        match event {
+            CompletionsEvent::Started(at) => self.started_at = Some(at),
+            CompletionsEvent::Token(_) => {
+                let now = Instant::now();
+                self.first_token_at.get_or_insert(now);
+                self.last_token_at = Some(now);
+            }
+            _ => (),
+        }
+    }
+
+    fn since_start_ms(&self, at: Option<Instant>) -> Option<u64> {
+        Some(at?.saturating_duration_since(self.started_at?).as_millis() as u64)
+    }
+
+    // the prefill ends with the first token
+    fn usage_timing(&self) -> serde_json::Value {
+        let decode_ms = self.first_token_at
+            .zip(self.last_token_at)
+            .map(|(first, last)| last.saturating_duration_since(first).as_millis() as u64);
+
+        serde_json::json!({
+            "prefill_ms": self.since_start_ms(self.first_token_at),
+            "decode_ms": decode_ms,
+        })
+    }
+
+    // null fields when no token was generated
+    fn summary(&self) -> TimingSummary {
+        TimingSummary {
+            time_to_first_token_ms: self.since_start_ms(self.first_token_at),
+            total_generation_time_ms: self.since_start_ms(self.last_token_at),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TimingSummary {
+    time_to_first_token_ms: Option<u64>,
+    total_generation_time_ms: Option<u64>,
+}
+
+static CACHE_HEADER: HeaderName = HeaderName::from_static("x-cache");
+static TIME_TO_FIRST_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-time-to-first-token-ms");
+static TOTAL_GENERATION_TIME_HEADER: HeaderName = HeaderName::from_static("x-total-generation-time-ms");
+
+impl TimingSummary {
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        if let Some(ms) = self.time_to_first_token_ms {
+            headers.insert(&TIME_TO_FIRST_TOKEN_HEADER, HeaderValue::from(ms));
+        }
+
+        if let Some(ms) = self.total_generation_time_ms {
+            headers.insert(&TOTAL_GENERATION_TIME_HEADER, HeaderValue::from(ms));
+        }
+    }
+}
+
+// a copy of the task with its own seed and channel, for the candidates of best_n
+fn fork_task(task: &CompletionsTask, seed: i64, to_api: flume::Sender<CompletionsEvent>) -> CompletionsTask {
+    CompletionsTask {
+        to_api,
+        input_token_list: task.input_token_list.clone(),
+        sampler_params: SamplerParams {
+            seed: Some(seed),
+            ..task.sampler_params.clone()
+        },
+        maximum_tokens: task.maximum_tokens,
+        soft_prompt: task.soft_prompt.clone(),
+        injections: None,
+        history_len: task.history_len,
+        request_id: task.request_id.clone(),
+        control: task.control.clone(),
+        prompt_logprobs: task.prompt_logprobs,
+        disable_speculative: task.disable_speculative,
+        enqueued_at: Instant::now(),
+        stop_token_ids: task.stop_token_ids.clone(),
+    }
+}
+
+// every candidate is a full generation queued at once
+const MAX_BEST_N: u32 = 16;
+
+fn check_best_n(best_n: u32, is_stream: bool) -> Result<()> {
+    if best_n == 0 || best_n > MAX_BEST_N {
+        return Err(ApiError::BadRequest(format!("best_n must be between 1 and {}", MAX_BEST_N)).into());
+    }
+
+    if best_n > 1 && is_stream {
+        return Err(ApiError::BadRequest(String::from("best_n can't be combined with stream")).into());
+    }
+    Ok(())
+}
+
+fn invalid_request(param: Option<&str>, message: String) -> anyhow::Error {
+    ApiError::InvalidRequest {
+        message,
+        param: param.map(String::from),
+    }.into()
+}
+
+fn number_field(body: &serde_json::Value, param: &str) -> Result<Option<f64>> {
+    match body.get(param) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(v) => v.as_f64()
+            .map(Some)
+            .ok_or_else(|| invalid_request(Some(param), format!("Field '{}' must be a number, got {}", param, v))),
+    }
+}
+
+// checked on the raw body before it is parsed, so an out of range field is reported by name instead of as a serde error
+pub fn validate_request(body: &serde_json::Value, model_name: &str, kv_cache_size_pre_task: u32) -> Result<()> {
+    if !body.is_object() {
+        return Err(invalid_request(None, String::from("Request body must be a JSON object")));
+    }
+
+    match body.get("model") {
+        Some(serde_json::Value::String(model)) if model != model_name => {
+            return Err(invalid_request(Some("model"), format!("Model '{}' does not exist, this server serves '{}'", model, model_name)));
+        }
+        _ => (),
+    }
+
+    if let Some(temperature) = number_field(body, "temperature")? {
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(invalid_request(Some("temperature"), format!("Field 'temperature' must be in [0, 2], got {}", temperature)));
+        }
+    }
+
+    if let Some(top_p) = number_field(body, "top_p")? {
+        if top_p <= 0.0 || top_p > 1.0 {
+            return Err(invalid_request(Some("top_p"), format!("Field 'top_p' must be in (0, 1], got {}", top_p)));
+        }
+    }
+
+    if let Some(rate) = number_field(body, "max_tokens_per_second")? {
+        if rate < MIN_TOKENS_PER_SECOND as f64 {
+            return Err(invalid_request(
+                Some("max_tokens_per_second"),
+                format!("Field 'max_tokens_per_second' must be >= {}, got {}", MIN_TOKENS_PER_SECOND, rate),
+            ));
+        }
+    }
+
+    for param in ["frequency_penalty", "presence_penalty"] {
+        if let Some(penalty) = number_field(body, param)? {
+            if !(-2.0..=2.0).contains(&penalty) {
+                return Err(invalid_request(Some(param), format!("Field '{}' must be in [-2, 2], got {}", param, penalty)));
+            }
+        }
+    }
+
+    // llama.cpp divides the logits by the repeat penalty
+    if let Some(penalty) = number_field(body, "repeat_penalty")? {
+        if penalty <= 0.0 {
+            return Err(invalid_request(Some("repeat_penalty"), format!("Field 'repeat_penalty' must be > 0, got {}", penalty)));
+        }
+    }
+
+    // the penalties sampler allocates the whole window up front, no sequence is longer than the context of a task
+    if let Some(last_n) = number_field(body, "repeat_last_n")? {
+        if last_n < 0.0 || last_n.fract() != 0.0 || last_n > kv_cache_size_pre_task as f64 {
+            return Err(invalid_request(
+                Some("repeat_last_n"),
+                format!("Field 'repeat_last_n' must be an integer in [0, {}], got {}", kv_cache_size_pre_task, last_n),
+            ));
+        }
+    }
+
+    for param in ["max_tokens", "max_completion_tokens"] {
+        if let Some(max_tokens) = number_field(body, param)? {
+            let fill_context = max_tokens == -1.0;
+
+            if !fill_context && (max_tokens <= 0.0 || max_tokens.fract() != 0.0 || max_tokens > u32::MAX as f64) {
+                return Err(invalid_request(Some(param), format!("Field '{}' must be an integer > 0 or -1, got {}", param, max_tokens)));
+            }
+        }
+    }
+
+    match body.get("seed") {
+        None | Some(serde_json::Value::Null) => (),
+        Some(seed) if seed.as_i64().is_some() => (),
+        Some(seed) => return Err(invalid_request(Some("seed"), format!("Field 'seed' must be an integer within the i64 range, got {}", seed))),
+    }
+
+    // a single choice is generated, best_n picks among candidates instead
+    if let Some(n) = number_field(body, "n")? {
+        if n != 1.0 {
+            return Err(invalid_request(Some("n"), format!("Field 'n' must be 1, got {}", n)));
+        }
+    }
+    Ok(())
+}
+
+// request fields of other servers accepted under their openai name, listed in /v1/model/info
+const COMPATIBILITY_ALIASES: &[&str] = &["num_predict"];
+
+// request fields of the completions and chat completions endpoints, listed in /v1/model/info
+const SUPPORTED_PARAMETERS: &[&str] = &[
+    "model",
+    "prompt",
+    "messages",
+    "max_tokens",
+    "max_completion_tokens",
+    "temperature",
+    "top_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "seed",
+    "stream",
+    "stream_options",
+    "n",
+    "tools",
+    "tool_choice",
+    "top_k",
+    "min_p",
+    "top_a",
+    "top_n_sigma",
+    "typical_p",
+    "dry_multiplier",
+    "sampler_order",
+    "confidence_threshold",
+    "presence_penalty_context_window",
+    "penalty_reset_on_newline",
+    "repeat_penalty",
+    "repeat_last_n",
+    "soft_prompt_id",
+    "best_n",
+    "use_speculative",
+    "max_tokens_per_second",
+    "stop_token_ids",
+    "include_timing",
+    "pre_prompt",
+    "post_prompt",
+];
+
+// llama-server clients send num_predict, max_tokens wins when both are set
+fn apply_field_aliases(body: &mut serde_json::Value, request_id: &RequestId) {
+    let body = match body.as_object_mut() {
+        Some(body) => body,
+        None => return,
+    };
+
+    if let Some(num_predict) = body.remove("num_predict") {
+        if !body.contains_key("max_tokens") {
+            debug!("[{}] num_predict is deprecated, use max_tokens instead", request_id);
+            body.insert(String::from("max_tokens"), num_predict);
+        }
+    }
+}
+
+// max_tokens = -1 generates until the context is full, the same as an omitted max_tokens,
+// the openai types only take unsigned values so the field is dropped before parsing
+fn strip_fill_context_max_tokens(body: &mut serde_json::Value) {
+    if let Some(body) = body.as_object_mut() {
+        body.retain(|k, v| !(matches!(k.as_str(), "max_tokens" | "max_completion_tokens") && v.as_f64() == Some(-1.0)));
+    }
+}
+
+fn parse_request<T: serde::de::DeserializeOwned>(body: serde_json::Value) -> Result<T> {
+    serde_json::from_value(body).map_err(|e| {
+        let msg = e.to_string();
+
+        // serde names the field only in its message, e.g. "missing field `prompt`"
+        let param = msg.strip_prefix("missing field `")
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field);
+
+        invalid_request(param, msg.clone())
+    })
+}
+
+fn parse_body(body: &[u8]) -> Result<serde_json::Value> {
+    serde_json::from_slice(body).map_err(|e| invalid_request(None, format!("Invalid JSON body: {}", e)))
+}
+
+// slower streams would hold a token longer than any client waits for it
+pub const MIN_TOKENS_PER_SECOND: f32 = 0.1;
+
+// min gap between two streamed tokens, for the rate asked for lowered to the --max-tokens-per-second cap
+fn stream_token_gap(ctx: &Context<CompletionsTask>, requested: Option<f32>) -> Result<Option<Duration>> {
+    let rate = match (requested, ctx.max_tokens_per_second) {
+        (Some(rate), Some(cap)) => rate.min(cap),
+        (Some(rate), None) => rate,
+        (None, _) => return Ok(None),
+    };
+
+    let gap = Duration::try_from_secs_f32(1.0 / rate)
+        .map_err(|_| invalid_request(Some("max_tokens_per_second"), format!("Field 'max_tokens_per_second' is out of range, got {}", rate)))?;
+    Ok(Some(gap))
+}
+
+// best_n = 1 runs the task as is
+async fn generate_best(
+    task: CompletionsTask,
+    rx: flume::Receiver<CompletionsEvent>,
+    ctx: &Arc<Context<CompletionsTask>>,
+    best_n: u32,
+) -> Result<Generation> {
+    if best_n <= 1 {
+        return generate(task, rx, ctx).await;
+    }
+
+    let base_seed = task.sampler_params.seed.unwrap_or_else(rand::random);
+    // dropped on the first error or with the request, which aborts the remaining candidates
+    let mut join_set = JoinSet::new();
+
+    for i in 0..best_n {
+        let (tx, rx) = flume::unbounded();
+        let task = fork_task(&task, base_seed.wrapping_add(i as i64), tx);
+        let ctx = ctx.clone();
+
+        join_set.spawn(async move {
+            (i, generate(task, rx, &ctx).await)
+        });
+    }
+
+    let mut candidates = Vec::with_capacity(best_n as usize);
+
+    while let Some(res) = join_set.join_next().await {
+        let (i, generation) = res?;
+        candidates.push((i, generation?));
+    }
+
+    // in seed order, so a tie always picks the same candidate
+    candidates.sort_by_key(|(i, _)| *i);
+    let mut candidates = candidates.into_iter().map(|(_, g)| g).collect::<Vec<_>>();
+
+    let best = quality::best_candidate(candidates.iter().map(|g| g.tokens.as_slice())).unwrap();
+    debug!("[{}] best_n selected candidate {} of {}", task.request_id, best, best_n);
+    Ok(candidates.swap_remove(best))
+}
+
+// dropping rx at the deadline ends the sequence in the inference loop
+async fn recv_generation(
+    rx: flume::Receiver<CompletionsEvent>,
+    model: &LlamaModel,
+    deadline: Option<tokio::time::Instant>,
+    mut on_event: impl FnMut(CompletionsEvent),
+) -> Result<Generation> {
+    let mut generation = Generation::default();
+
+    loop {
+        let res = match deadline {
+            None => rx.recv_async().await,
+            Some(deadline) => match tokio::time::timeout_at(deadline, rx.recv_async()).await {
+                Ok(res) => res,
+                Err(_) => {
+                    generation.timed_out = true;
+                    break;
+                }
+            }
+        };
+
+        let event = match res {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+
+        generation.push(event);
+        on_event(event);
+
+        if let (CompletionsEvent::Token(token), true) = (event, log::max_level() >= log::Level::Debug) {
+            let token_bytes = model.token_to_bytes(token, Special::Plaintext)?;
+            let s = String::from_utf8_lossy(&token_bytes);
+            let mut lock = std::io::stdout().lock();
+            lock.write_all(s.as_bytes())?;
+            lock.flush()?;
+        }
+    }
+
+    ensure!(!generation.failed, "inference of the request failed");
+    Ok(generation)
+}
+
+async fn subscribe_inflight(inflight: &Mutex<InflightRequest>) -> Result<Generation> {
+    let (mut generation, mut sub_rx) = {
+        let inflight = inflight.lock().unwrap();
+
+        match &inflight.tx {
+            Some(tx) => (inflight.generation.clone(), tx.subscribe()),
+            None => {
+                ensure!(!inflight.failed, "in-flight request failed");
+                return Ok(inflight.generation.clone());
+            }
+        }
+    };
+
+    loop {
+        match sub_rx.recv().await {
+            Ok(event) => generation.push(event),
+            Err(broadcast::error::RecvError::Closed) => break,
+            Err(broadcast::error::RecvError::Lagged(_)) => return Err(anyhow!("in-flight request subscriber lagged")),
+        }
+    }
+
+    let inflight = inflight.lock().unwrap();
+    ensure!(!inflight.failed, "in-flight request failed");
+    generation.timed_out = inflight.generation.timed_out;
+    Ok(generation)
+}
+
+// runs a non-streaming task, identical in-flight requests share a single inference
+async fn generate(
+    task: CompletionsTask,
+    rx: flume::Receiver<CompletionsEvent>,
+    ctx: &Context<CompletionsTask>,
+) -> Result<Generation> {
+    let deadline = ctx.non_streaming_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+    let key = match request_key(&ctx.model_name, &task) {
+        None => {
+            send_to_backend(task, ctx)?;
+            return recv_generation(rx, &ctx.model, deadline, |_| ()).await;
+        }
+        Some(key) => key
+    };
+
+    let response_cache = ctx.response_cache.as_ref().filter(|_| is_greedy(&task.sampler_params));
+
+    if let Some(generation) = response_cache.and_then(|cache| cache.lock().unwrap().get(&key).cloned()) {
+        debug!("[{}] response cache hit", task.request_id);
+
+        // nothing was decoded for this request, the timing of the original one doesn't apply
+        return Ok(Generation {
+            cache_hit: true,
+            timing: GenerationTiming::default(),
+            prompt_tokens_cached: 0,
+            ..generation
+        });
+    }
+
+    let (inflight, is_subscriber) = match ctx.inflight_requests.entry(key.clone()) {
+        Entry::Occupied(entry) => (entry.get().clone(), true),
+        Entry::Vacant(entry) => {
+            // one extra slot for the prompt cache event
+            let (tx, _) = broadcast::channel(ctx.kv_cache_size_pre_task as usize + 1);
+
+            let inflight = Arc::new(Mutex::new(InflightRequest {
+                generation: Generation::default(),
+                tx: Some(tx),
+                failed: false,
+            }));
+
+            entry.insert(inflight.clone());
+            (inflight, false)
+        }
+    };
+
+    if is_subscriber {
+        debug!("subscribe to in-flight request");
+        return subscribe_inflight(&inflight).await;
+    }
+
+    let _guard = InflightGuard {
+        inflight_requests: &ctx.inflight_requests,
+        key: key.clone(),
+        inflight: inflight.clone(),
+    };
+
+    let res = async {
+        send_to_backend(task, ctx)?;
+
+        recv_generation(rx, &ctx.model, deadline, |event| {
+            let mut inflight = inflight.lock().unwrap();
+            inflight.generation.push(event);
+
+            if let Some(tx) = &inflight.tx {
+                let _ = tx.send(event);
+            }
+        }).await
+    }.await;
+
+    {
+        let mut inflight = inflight.lock().unwrap();
+        inflight.failed = res.is_err();
+        inflight.generation.timed_out = res.as_ref().is_ok_and(|g| g.timed_out);
+        inflight.tx = None;
+    }
+
+    // a timed out generation is cut short by the load of the moment, not by the request
+    if let (Some(cache), Ok(generation)) = (response_cache, &res) {
+        if !generation.timed_out {
+            cache.lock().unwrap().put(key, generation.clone());
+        }
+    }
+    res
+}
+
+// only requests without randomness in the sampled tokens are cached, a seed alone still samples from the distribution
+fn is_greedy(params: &SamplerParams) -> bool {
+    params.temperature.is_some_and(|t| t <= 0.0) || params.top_k == Some(1)
+}
+
+// number of leading prompt tokens that render the messages up to the last assistant turn,
+// re-submitted histories share them with the prompt of the previous turn
+fn chat_history_len(
+    req: &async_openai::types::CreateChatCompletionRequest,
+    model: &LlamaModel,
+    template: &ChatTemplates,
+    input_tokens: &[LlamaToken],
+) -> Result<Option<usize>> {
+    let last_assistant = req.messages.iter()
+        .rposition(|m| matches!(m, ChatCompletionRequestMessage::Assistant(_)));
+
+    let last_assistant = match last_assistant {
+        None => return Ok(None),
+        Some(idx) => idx,
+    };
+
+    let mut history_req = req.clone();
+    history_req.messages.truncate(last_assistant + 1);
+
+    let params = body_json_to_chat_params(template, serde_json::to_string(&history_req)?.as_str());
+    ensure!(!params.inner.is_null(), "failed to render chat history");
+    let history_tokens = model.str_to_token(&params.get_prompt()?, AddBos::Always)?;
+
+    // the generation prompt rendered after the history differs from the next message, only the common part counts
+    let history_len = history_tokens.iter()
+        .zip(input_tokens)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if history_len == 0 {
+        return Ok(None);
+    }
+    Ok(Some(history_len))
+}
+
+// ret: (task, chat_template_format)
+async fn chat_completion_req_to_task(
+    req: ChatCompletionRequest,
+    model: Arc<LlamaModel>,
+    callback: flume::Sender<CompletionsEvent>,
+    template: Arc<ChatTemplates>,
+    request_id: String,
+) -> Result<(CompletionsTask, HibikiCommonChatFormat)> {
+    tokio::task::spawn_blocking(move || {
+        let sampler_params = req.sampling.to_sampler_params(
+            req.inner.frequency_penalty,
+            req.inner.presence_penalty,
+            req.inner.seed,
+            req.inner.temperature,
+            req.inner.top_p,
+        )?;
+        let disable_speculative = !req.use_speculative.unwrap_or(true);
+        let stop_token_ids = check_stop_token_ids(req.stop_token_ids, &model)?;
+        let req = req.inner;
+
+        // the openai sdk sends max_completion_tokens, it wins over the deprecated max_tokens
+        #[allow(deprecated)]
+        let max_tokens = match (req.max_completion_tokens, req.max_tokens) {
+            (Some(max_tokens), _) => Some(max_tokens),
+            (None, Some(max_tokens)) => {
+                debug!("[{}] max_tokens is deprecated, use max_completion_tokens instead", request_id);
+                Some(max_tokens)
+            }
+            (None, None) => None,
+        };
+
+        let req_json = serde_json::to_string(&req)?;
+        let params = body_json_to_chat_params(&template, req_json.as_str());
+        debug!("[{}] body_json_to_chat_params finished", request_id);
+
+        let prompt = params.get_prompt()?;
+        debug!("[{}] prompt: {:?}", request_id, prompt);
+
+        let format = params.get_chat_format();
+
+        let input_tokens = model.str_to_token(&prompt, AddBos::Always)?;
+        let history_len = chat_history_len(&req, &model, &template, &input_tokens)?;
+        debug!("[{}] chat history tokens len: {:?}", request_id, history_len);
+
+        let task = CompletionsTask {
+            to_api: callback,
+            maximum_tokens: MaxTokens::from(max_tokens),
+            input_token_list: input_tokens,
+            sampler_params,
+            soft_prompt: None,
+            injections: None,
+            history_len,
+            request_id,
+            control: None,
+            prompt_logprobs: false,
+            disable_speculative,
+            enqueued_at: Instant::now(),
+            stop_token_ids,
+        };
+        Result::<_, anyhow::Error>::Ok((task, format))
+    }).await?
+}
+
+pub const DEFAULT_COMPACTION_PROMPT_TEMPLATE: &str = "Summarize the following conversation concisely. Keep facts, decisions and open questions.\n\n{conversation}\n\nSummary:";
+
+// maximum length of the summary that replaces the compacted messages
+const COMPACTION_SUMMARY_TOKENS: u32 = 512;
+
+// operator cap on the context of a single request, kv_cache_size_pre_task is already capped to it
+#[derive(Clone, Copy)]
+pub struct ContextLimit {
+    pub size: u32,
+    // tokens kept free for generation, longer prompts are rejected before inference
+    pub safety_margin: u32,
+}
+
+fn check_context_limit<Task>(ctx: &Context<Task>, prompt_tokens: u32) -> Result<()> {
+    let limit = match ctx.context_limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let max_prompt_tokens = limit.size.saturating_sub(limit.safety_margin);
+
+    if prompt_tokens > max_prompt_tokens {
+        let message = format!(
+            "prompt has {} tokens, the context size limit {} allows at most {}",
+            prompt_tokens,
+            limit.size,
+            max_prompt_tokens
+        );
+        return Err(invalid_request(Some("prompt"), message));
+    }
+    Ok(())
+}
+
+pub struct Compaction {
+    // fraction of kv_cache_size_pre_task the prompt may fill before it is compacted
+    pub threshold: f32,
+    // {conversation} is replaced with the compacted messages
+    pub prompt_template: String,
+}
+
+fn message_transcript(message: &ChatCompletionRequestMessage) -> Result<String> {
+    let value = serde_json::to_value(message)?;
+    let role = value["role"].as_str().unwrap_or_default();
+
+    let content = match &value["content"] {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Array(parts) => parts.iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    };
+    Ok(format!("{}: {}", role, content))
+}
+
+// the conversation is stateless, so the oldest half of the non-system messages is summarized on every request that needs it
+async fn compact_conversation(
+    ctx: &Context<CompletionsTask>,
+    compaction: &Compaction,
+    mut req: ChatCompletionRequest,
+    request_id: &RequestId,
+) -> Result<Option<ChatCompletionRequest>> {
+    let messages = &req.inner.messages;
+    let n_system = messages.iter()
+        .take_while(|m| matches!(m, ChatCompletionRequestMessage::System(_)))
+        .count();
+
+    // the latest message is always kept
+    let n_compact = (messages.len() - n_system) / 2;
+
+    if n_compact == 0 {
+        return Ok(None);
+    }
+
+    let transcript = messages[n_system..n_system + n_compact].iter()
+        .map(message_transcript)
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
+
+    let prompt = compaction.prompt_template.replace("{conversation}", &transcript);
+    let model = ctx.model.clone();
+    let input_tokens = tokio::task::spawn_blocking(move || model.str_to_token(&prompt, AddBos::Always)).await??;
+    ensure!((input_tokens.len() as u32) < ctx.kv_cache_size_pre_task, "conversation too large to compact");
+
+    info!("[{}] compacting {} messages, {} tokens", request_id, n_compact, input_tokens.len());
+
+    let (tx, rx) = flume::unbounded();
+
+    let task = CompletionsTask {
+        to_api: tx,
+        input_token_list: input_tokens,
+        sampler_params: SamplerParams::default(),
+        maximum_tokens: MaxTokens::Finite(COMPACTION_SUMMARY_TOKENS),
+        soft_prompt: None,
+        injections: None,
+        history_len: None,
+        request_id: request_id.to_string(),
+        control: None,
+        prompt_logprobs: false,
+        disable_speculative: false,
+        enqueued_at: Instant::now(),
+        stop_token_ids: HashSet::new(),
+    };
+
+    send_to_backend(task, ctx)?;
+    let generation = recv_generation(rx, &ctx.model, None, |_| ()).await?;
+    let summary = tokens_to_string(generation.tokens, ctx.model.clone()).await?;
+
+    let summary_message = ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+        content: ChatCompletionRequestSystemMessageContent::Text(format!("Summary of the earlier conversation: {}", summary.trim())),
+        name: None,
+    });
+
+    req.inner.messages.splice(n_system..n_system + n_compact, [summary_message]);
+    Ok(Some(req))
+}
+
+pub const DEFAULT_FIM_TEMPLATE: &str = "{fim_prefix}{prefix}{fim_suffix}{suffix}{fim_middle}";
+
+#[derive(Deserialize)]
+struct FimMessage {
+    prefix: String,
+    suffix: String,
+}
+
+// single pass, so placeholders inside the prefix or suffix are left as they are
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    'outer: while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        for (name, value) in values {
+            if let Some(after) = rest.strip_prefix('{').and_then(|r| r.strip_prefix(name)).and_then(|r| r.strip_prefix('}')) {
+                out.push_str(value);
+                rest = after;
+                continue 'outer;
+            }
+        }
+
+        out.push('{');
+        rest = &rest[1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn fim_tokens(model: &LlamaModel) -> Result<[String; 3]> {
+    let vocab = unsafe { llama_cpp_sys_2::llama_model_get_vocab(model.as_ptr()) };
+
+    let tokens = unsafe {
+        [
+            llama_cpp_sys_2::llama_vocab_fim_pre(vocab),
+            llama_cpp_sys_2::llama_vocab_fim_suf(vocab),
+            llama_cpp_sys_2::llama_vocab_fim_mid(vocab),
+        ]
+    };
+
+    if tokens.iter().any(|t| *t == llama_cpp_sys_2::LLAMA_TOKEN_NULL) {
+        return Err(ApiError::BadRequest(String::from("model has no FIM tokens")).into());
+    }
+
+    let mut out = tokens.map(|_| String::new());
+
+    for (text, token) in out.iter_mut().zip(tokens) {
+        let bytes = model.token_to_bytes(LlamaToken(token), Special::Tokenize)?;
+        *text = String::from_utf8(bytes)?;
+    }
+    Ok(out)
+}
+
+// forced on every request by --system-prompt
+pub struct SystemPrompt {
+    pub content: String,
+    // requests with their own system message are refused instead of getting it after the forced one
+    pub strict: bool,
+}
+
+// chat requests get it as the first message, completion prompts start with it,
+// it is part of the prompt so usage.prompt_tokens counts it
+fn inject_system_prompt(body: &mut serde_json::Value, system_prompt: &SystemPrompt) -> Result<()> {
+    match body.get_mut("prompt") {
+        Some(serde_json::Value::String(prompt)) => {
+            prompt.insert_str(0, &system_prompt.content);
+            return Ok(());
+        }
+        Some(serde_json::Value::Array(prompts)) => {
+            // token ids can't be prefixed with text, they would skip the system prompt
+            if !prompts.iter().all(|p| p.is_string()) {
+                return Err(invalid_request(Some("prompt"), String::from("Token id prompts are not allowed, this server sets its own system prompt")));
+            }
+
+            for prompt in prompts.iter_mut() {
+                if let serde_json::Value::String(prompt) = prompt {
+                    prompt.insert_str(0, &system_prompt.content);
+                }
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let messages = match body.get_mut("messages").and_then(|messages| messages.as_array_mut()) {
+        Some(messages) => messages,
+        None => return Ok(()),
+    };
+
+    if system_prompt.strict && messages.iter().any(|m| m["role"] == "system" || m["role"] == "developer") {
+        return Err(invalid_request(Some("messages"), String::from("System messages are not allowed, this server sets its own system prompt")));
+    }
+
+    messages.insert(0, serde_json::json!({"role": "system", "content": system_prompt.content}));
+    Ok(())
+}
+
+// a user message {"role": "user", "type": "fim", "prefix": ..., "suffix": ...} becomes a plain user message
+// with the rendered fim template as content, the chat template is applied as usual afterwards
+fn rewrite_fim_messages(body: &mut serde_json::Value, model: &LlamaModel, fim_template: &str) -> Result<bool> {
+    let messages = match body.get_mut("messages").and_then(|messages| messages.as_array_mut()) {
+        Some(messages) => messages,
+        None => return Ok(false),
+    };
+
+    let mut fim_tokens_text = None;
+
+    for message in messages.iter_mut() {
+        if message["role"] != "user" || message["type"] != "fim" {
+            continue;
+        }
+
+        let fim: FimMessage = serde_json::from_value(message.clone())
+            .map_err(|e| ApiError::BadRequest(format!("invalid fim message: {}", e)))?;
+
+        if fim_tokens_text.is_none() {
+            fim_tokens_text = Some(fim_tokens(model)?);
+        }
+        let [fim_prefix, fim_suffix, fim_middle] = fim_tokens_text.as_ref().unwrap();
+
+        let content = render_template(fim_template, &[
+            ("fim_prefix", fim_prefix.as_str()),
+            ("fim_suffix", fim_suffix.as_str()),
+            ("fim_middle", fim_middle.as_str()),
+            ("prefix", fim.prefix.as_str()),
+            ("suffix", fim.suffix.as_str()),
+        ]);
+
+        *message = serde_json::json!({"role": "user", "content": content});
+    }
+    Ok(fim_tokens_text.is_some())
+}
+
+// "confidence" is not an openai finish reason, so it is patched into the serialized response
+// finish reasons the openai types have no variant for
+// usage.timing is patched in the same way
+fn response_body(
+    resp: &impl Serialize,
+    confidence_token: Option<LlamaToken>,
+    timed_out: bool,
+    usage_timing: Option<serde_json::Value>,
+) -> Result<Vec<u8>> {
+    if confidence_token.is_none() && !timed_out && usage_timing.is_none() {
+        return Ok(serde_json::to_vec(resp)?);
+    }
+
+    let mut value = serde_json::to_value(resp)?;
+
+    if let Some(timing) = usage_timing {
+        value["usage"]["timing"] = timing;
+    }
+
+    if let Some(token) = confidence_token {
+        value["choices"][0]["finish_reason"] = serde_json::Value::from("confidence");
+        value["choices"][0]["confidence_token_id"] = serde_json::Value::from(token.0);
+    }
+
+    if timed_out {
+        value["choices"][0]["finish_reason"] = serde_json::Value::from("timeout");
+    }
+    Ok(serde_json::to_vec(&value)?)
+}
+
+// the json of a --chunked-responses completion, the text is the last field of the choice so that
+// every token is appended to the open string, the rest of the choice and the usage follow the last token
+struct ChunkedCompletion {
+    rx: flume::Receiver<CompletionsEvent>,
+    deadline: Option<tokio::time::Instant>,
+    model: Arc<LlamaModel>,
+    generation: Generation,
+    // bytes of a utf-8 character split across tokens
+    pending: Vec<u8>,
+    prompt_tokens: u32,
+    include_timing: bool,
+    done: bool,
+}
+
+fn escape_json_str(s: &str) -> String {
+    let quoted = serde_json::Value::from(s).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+impl ChunkedCompletion {
+    fn head(completion_id: &str, model_name: &str) -> String {
+        format!(
+            r#"{{"id":{},"object":"text_completion","created":{},"model":{},"system_fingerprint":null,"choices":[{{"index":0,"logprobs":null,"text":""#,
+            serde_json::Value::from(completion_id),
+            Utc::now().timestamp(),
+            serde_json::Value::from(model_name),
+        )
+    }
+
+    fn tail(&self) -> Result<String> {
+        // an error aborts the body, a failed generation gets no finish_reason
+        ensure!(!self.generation.failed, "inference of the request failed");
+
+        let mut finish_reason = serde_json::Value::from(self.generation.finish_reason()).to_string();
+
+        if let Some(token) = self.generation.confidence_token {
+            finish_reason = format!(r#"{},"confidence_token_id":{}"#, finish_reason, token.0);
+        }
+
+        let completion_tokens = self.generation.tokens.len() as u32;
+
+        let mut usage = serde_json::to_value(async_openai::types::CompletionUsage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens,
+            total_tokens: self.prompt_tokens + completion_tokens,
+            prompt_tokens_details: Some(PromptTokensDetails {
+                audio_tokens: None,
+                cached_tokens: Some(self.generation.prompt_tokens_cached),
+            }),
+            completion_tokens_details: None
+        })?;
+
+        if self.include_timing {
+            usage["timing"] = self.generation.timing.usage_timing();
+        }
+
+        Ok(format!(
+            r#"{}","finish_reason":{}}}],"usage":{}}}"#,
+            escape_json_str(&String::from_utf8_lossy(&self.pending)),
+            finish_reason,
+            usage,
+        ))
+    }
+
+    // None once the tail is sent
+    async fn next_chunk(&mut self) -> Option<Result<String>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let res = match self.deadline {
+                None => self.rx.recv_async().await,
+                Some(deadline) => match tokio::time::timeout_at(deadline, self.rx.recv_async()).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        self.generation.timed_out = true;
+                        self.done = true;
+                        return Some(self.tail());
+                    }
+                }
+            };
+
+            let event = match res {
+                Ok(event) => event,
+                Err(_) => {
+                    self.done = true;
+                    return Some(self.tail());
+                }
+            };
+
+            self.generation.push(event);
+
+            let token = match event {
+                CompletionsEvent::Token(token) => token,
+                CompletionsEvent::Failed => {
+                    self.done = true;
+                    return Some(Err(anyhow!("inference of the request failed")));
+                }
+                _ => continue,
+            };
+
+            let token_bytes = match self.model.token_to_bytes(token, Special::Plaintext) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            self.pending.extend_from_slice(&token_bytes);
+
+            if let Ok(text) = std::str::from_utf8(&self.pending) {
+                let chunk = escape_json_str(text);
+                self.pending.clear();
+                return Some(Ok(chunk));
+            }
+        }
+    }
+}
+
+fn chunked_completion_response(
+    ctx: &Context<CompletionsTask>,
+    rx: flume::Receiver<CompletionsEvent>,
+    completion_id: &str,
+    prompt_tokens: u32,
+    include_timing: bool,
+) -> Response {
+    let completion = ChunkedCompletion {
+        rx,
+        deadline: ctx.non_streaming_timeout.map(|timeout| tokio::time::Instant::now() + timeout),
+        model: ctx.model.clone(),
+        generation: Generation::default(),
+        pending: Vec::new(),
+        prompt_tokens,
+        include_timing,
+        done: false,
+    };
+
+    let head = ChunkedCompletion::head(completion_id, &ctx.model_name);
+    let head = futures_util::stream::once(std::future::ready(Result::<_, anyhow::Error>::Ok(head)));
+
+    // the receiver is dropped with the body, which ends the sequence in the inference loop
+    let chunks = futures_util::stream::unfold(completion, |mut completion| async move {
+        let chunk = completion.next_chunk().await?;
+        Some((chunk, completion))
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .extension(StreamedBody)
+        .body(Body::from_stream(head.chain(chunks)))
+        .unwrap()
+}
+
+// the finish_reason of the generation on the first choice, with the token that ended a confident generation.
+// a failed generation has no finish chunk, the error ends the stream before the usage and [DONE]
+// the timing summary is an extra field of the chunk, clients that don't know it skip it
+fn with_finish_reason(resp: &impl Serialize, generation: &Generation) -> Result<serde_json::Value> {
+    ensure!(!generation.failed, "inference of the request failed");
+
+    let mut value = serde_json::to_value(resp)?;
+    value["choices"][0]["finish_reason"] = serde_json::Value::from(generation.finish_reason());
+    value["timing"] = serde_json::to_value(generation.timing.summary())?;
+
+    if let Some(token) = generation.confidence_token {
+        value["choices"][0]["confidence_token_id"] = serde_json::Value::from(token.0);
+    }
+    Ok(value)
+}
+
+// the last data chunk before the usage and [DONE], with an empty delta like the one of openai
+fn chat_finish_chunk(id: String, model: String, generation: &Generation) -> Result<serde_json::Value> {
+    let chunk = async_openai::types::CreateChatCompletionStreamResponse {
+        id,
+        choices: vec![
+            ChatChoiceStream {
+                index: 0,
+                #[allow(deprecated)]
+                delta: ChatCompletionStreamResponseDelta {
+                    content: None,
+                    refusal: None,
+                    tool_calls: None,
+                    role: None,
+                    function_call: None
+                },
+                finish_reason: None,
+                logprobs: None,
+            }
+        ],
+        created: Utc::now().timestamp() as u32,
+        model,
+        service_tier: None,
+        system_fingerprint: None,
+        object: String::from("chat.completion.chunk"),
+        usage: None
+    };
+    with_finish_reason(&chunk, generation)
+}
+
+fn completion_finish_chunk(id: String, model: String, generation: &Generation) -> Result<serde_json::Value> {
+    let chunk = async_openai::types::CreateCompletionResponse {
+        id,
+        choices: vec![Choice{
+            text: String::new(),
+            index: 0,
+            logprobs: None,
+            finish_reason: None
+        }],
+        created: Utc::now().timestamp() as u32,
+        model,
+        system_fingerprint: None,
+        object: "text_completion".to_string(),
+        usage: None
+    };
+    with_finish_reason(&chunk, generation)
+}
+
+// usage chunk of stream_options.include_usage, the completion tokens are counted as the stream receives them
+fn stream_usage(prompt_tokens: u32, completion_tokens: u32) -> async_openai::types::CompletionUsage {
+    async_openai::types::CompletionUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+        prompt_tokens_details: None,
+        completion_tokens_details: None
+    }
+}
+
+// built when the stream reaches it, after every chunk before it was sent
+fn optional_chunk<T>(include: bool, build: impl FnOnce() -> T) -> impl Stream<Item = Result<StreamChunk<T>>> {
+    futures_util::stream::iter(include.then_some(build)).map(|build| Ok(StreamChunk::Data(build())))
+}
+
+async fn v1_chat_completions(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Query(query): Query<StreamQuery>,
+    body: Bytes,
+) -> Response {
+    let stream_format = StreamFormat::from_request(&headers, &query);
+
+    let (tx, rx) = flume::unbounded();
+    let chat_completion_id = rand::random::<u64>().to_string();
+
+    let fut = async {
+        let mut body = parse_body(&body)?;
+        apply_field_aliases(&mut body, &request_id);
+        validate_request(&body, &ctx.model_name, ctx.kv_cache_size_pre_task)?;
+        strip_fill_context_max_tokens(&mut body);
+
+        if let Some(system_prompt) = &ctx.system_prompt {
+            inject_system_prompt(&mut body, system_prompt)?;
+        }
+
+        // fim messages aren't openai messages, they are rewritten before the request is parsed
+        let is_fim = rewrite_fim_messages(&mut body, &ctx.model, ctx.fim_template.as_deref().unwrap())?;
+
+        let req: ChatCompletionRequest = parse_request(body)?;
+        debug!("[{}] v1_chat_completions: {:?}", request_id, req);
+
+        let is_stream = req.inner.stream.unwrap_or(false);
+        let best_n = req.best_n.unwrap_or(1);
+        let include_timing = req.include_timing.unwrap_or(false);
+        let include_usage = req.inner.stream_options.as_ref().is_some_and(|options| options.include_usage);
+        check_best_n(best_n, is_stream)?;
+        let token_gap = stream_token_gap(&ctx, req.max_tokens_per_second)?;
+
+        if is_stream {
+            ensure!(req.inner.tools.is_none());
+        }
+
+        let soft_prompt = find_soft_prompt(&ctx, req.soft_prompt_id.as_deref())?;
+        let template = ctx.chat_template.as_ref().unwrap().clone();
+        let (mut task, mut format) = chat_completion_req_to_task(req.clone(), ctx.model.clone(), tx.clone(), template.clone(), request_id.to_string()).await?;
+        debug!("[{}] chat_completion_req_to_task finished", request_id);
+
+        let compacted = match &ctx.compaction {
+            Some(compaction) if task.input_token_list.len() as f32 >= compaction.threshold * ctx.kv_cache_size_pre_task as f32 => {
+                compact_conversation(&ctx, compaction, req, &request_id).await?
+            }
+            _ => None,
+        };
+
+        // the token stream ends when every sender is dropped
+        match compacted {
+            Some(req) => {
+                (task, format) = chat_completion_req_to_task(req, ctx.model.clone(), tx, template, request_id.to_string()).await?;
+                debug!("[{}] compacted prompt tokens len: {}", request_id, task.input_token_list.len());
+            }
+            None => drop(tx),
+        }
+
+        if is_fim && log::max_level() >= log::Level::Debug {
+            let prompt = ctx.model.tokens_to_str(&task.input_token_list, Special::Tokenize)?;
+            debug!("[{}] fim prompt: {:?}, tokens len: {}", request_id, prompt, task.input_token_list.len());
+        }
+
+        let prompt_tokens = task.input_token_list.len() as u32;
+        let virtual_tokens = soft_prompt.as_ref().map(|p| p.n_tokens as u32).unwrap_or(0);
+        check_context_limit(&ctx, prompt_tokens + virtual_tokens)?;
+        ensure!(prompt_tokens + virtual_tokens < ctx.kv_cache_size_pre_task, "Prompt too large, prompt tokens len: {prompt_tokens}");
+        task.soft_prompt = soft_prompt;
+
+        let resp = if is_stream {
+            let registration = ControlRegistration::new(ctx.clone(), &request_id)?;
+            task.control = Some(registration.control.clone());
+            send_to_backend(task, &*ctx)?;
+
+            let mut single_token_bytes = Vec::new();
+            let generation = Arc::new(Mutex::new(Generation::default()));
+            let finish_chunk = {
+                let (id, model, generation) = (chat_completion_id.clone(), ctx.model_name.clone(), generation.clone());
+                move || chat_finish_chunk(id, model, &generation.lock().unwrap())
+            };
+            let completion_tokens = Arc::new(AtomicU32::new(0));
+
+            let usage_chunk = {
+                let (id, model, completion_tokens) = (chat_completion_id.clone(), ctx.model_name.clone(), completion_tokens.clone());
+
+                move || async_openai::types::CreateChatCompletionStreamResponse {
+                    id,
+                    choices: Vec::new(),
+                    created: Utc::now().timestamp() as u32,
+                    model,
+                    service_tier: None,
+                    system_fingerprint: None,
+                    object: String::from("chat.completion.chunk"),
+                    usage: Some(stream_usage(prompt_tokens, completion_tokens.load(Ordering::Relaxed)))
+                }
+            };
+
+            let chunks = heartbeat_token_stream(rx, ctx.sse_heartbeat.unwrap(), token_gap, generation.clone())
+                .map(move |token| {
+                    let token = match token {
+                        Some(token) => token,
+                        None => return Ok(Some(registration.heartbeat()))
+                    };
+                    completion_tokens.fetch_add(1, Ordering::Relaxed);
+
+                    let token_bytes = ctx.model.token_to_bytes(token, Special::Plaintext)?;
+                    single_token_bytes.extend_from_slice(&token_bytes);
+
+                    let text = match String::from_utf8(single_token_bytes.clone()) {
+                        Ok(v) => v,
+                        Err(_) => return Ok(None)
+                    };
+
+                    single_token_bytes.clear();
+
+                    if log::max_level() >= log::Level::Debug {
+                        print!("{}", text);
+                        std::io::stdout().flush()?;
+                    }
+
+                    let chat_completion_resp = async_openai::types::CreateChatCompletionStreamResponse {
+                        id: chat_completion_id.clone(),
+                        choices: vec![
+                            ChatChoiceStream {
+                                index: 0,
+                                #[allow(deprecated)]
+                                delta: ChatCompletionStreamResponseDelta {
+                                    content: Some(text),
+                                    refusal: None,
+                                    tool_calls: None,
+                                    role: Some(Role::Assistant),
+                                    function_call: None
+                                },
+                                finish_reason: None,
+                                logprobs: None,
+                            }
+                        ],
+                        created: Utc::now().timestamp() as u32,
+                        model: ctx.model_name.clone(),
+                        service_tier: None,
+                        system_fingerprint: None,
+                        object: String::from("chat.completion.chunk"),
+                        usage: None
+                    };
+
+                    Result::<_, anyhow::Error>::Ok(Some(StreamChunk::Data(chat_completion_resp)))
                 })
                 .filter_map(|v| async {
                     v.transpose()
                 })
-                .chain(futures_util::stream::once(async {
-                    let event = axum::response::sse::Event::default()
-                        .data("[DONE]");
-                    Ok(event)
+                .chain(futures_util::stream::once(async move {
+                    finish_chunk().map(StreamChunk::Finish)
+                }))
+                .chain(optional_chunk(include_usage, usage_chunk))
+                .chain(futures_util::stream::once({
+                    let request_id = request_id.clone();
+
+                    async move {
+                        debug!("[{}] v1_chat_completions stream end", request_id);
+                        Ok(StreamChunk::Done)
+                    }
                 }));
-            Sse::new(out_stream).into_response()
+
+            stream_response(stream_format, chunks)
         } else {
-            let mut out_tokens = Vec::new();
-            while let Ok(token) = rx.recv_async().await {
-                out_tokens.push(token);
+            let generation = generate_best(task, rx, &ctx, best_n).await?;
+
+            let completion_tokens = generation.tokens.len() as u32;
+            let prompt_tokens_cached = generation.prompt_tokens_cached;
+            let confidence_token = generation.confidence_token;
+            let timed_out = generation.timed_out;
+            let cache_hit = generation.cache_hit;
+            let timing = generation.timing.summary();
+            let usage_timing = include_timing.then(|| generation.timing.usage_timing());
+            let text = tokens_to_string(generation.tokens, ctx.model.clone()).await?;
+            let chat_msg = output_parse(text.as_str(), format)?;
+            debug!("[{}] chat_msg: {:?}", request_id, chat_msg);
+
+            let chat_completion_resp = async_openai::types::CreateChatCompletionResponse {
+                id: chat_completion_id,
+                choices: vec![
+                    ChatChoice {
+                        finish_reason: {
+                            if !chat_msg.tool_calls.is_empty() {
+                                Some(FinishReason::ToolCalls)
+                            } else {
+                                None
+                            }
+                        },
+                        index: 0,
+                        #[allow(deprecated)]
+                        message: ChatCompletionResponseMessage {
+                            content: Some(chat_msg.content),
+                            refusal: None,
+                            tool_calls: {
+                                let list = chat_msg.tool_calls.into_iter().map(|tool_call| {
+                                    ChatCompletionMessageToolCall {
+                                        id: {
+                                            if tool_call.id.is_empty() {
+                                                format!("call_def_{}", rand::random::<u32>())
+                                            } else {
+                                                tool_call.id
+                                            }
+                                        },
+                                        r#type: ChatCompletionToolType::Function,
+                                        function: FunctionCall {
+                                            name: tool_call.name,
+                                            arguments: tool_call.arguments,
+                                        }
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+
+                                Some(list)
+                            },
+                            role: Role::Assistant,
+                            function_call: None,
+                            audio: None
+                        },
+                        logprobs: None,
+                    }
+                ],
+                created: Utc::now().timestamp() as u32,
+                model: ctx.model_name.clone(),
+                service_tier: None,
+                system_fingerprint: None,
+                object: String::from("chat.completion"),
+                usage: Some(async_openai::types::CompletionUsage{
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                    prompt_tokens_details: Some(PromptTokensDetails {
+                        audio_tokens: None,
+                        cached_tokens: Some(prompt_tokens_cached),
+                    }),
+                    completion_tokens_details: None
+                })
+            };
+
+            let body = response_body(&chat_completion_resp, confidence_token, timed_out, usage_timing)?;
+            let mut resp = Response::new(Body::from(body));
+            resp.extensions_mut().insert(TokenUsage { prompt_tokens, completion_tokens });
+            timing.insert_headers(resp.headers_mut());
+
+            if cache_hit {
+                resp.headers_mut().insert(&CACHE_HEADER, HeaderValue::from_static("HIT"));
             }
+            resp
+        };
+
+        Result::<_, anyhow::Error>::Ok(resp)
+    };
+
+    match fut.await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("[{}] v1_caht_completions error: {:?}", request_id, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn v1_completions(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Query(query): Query<StreamQuery>,
+    body: Bytes,
+) -> Response {
+    let stream_format = StreamFormat::from_request(&headers, &query);
+
+    let (tx, rx) = flume::unbounded();
+    let completion_id = rand::random::<u64>().to_string();
+
+    let fut = async {
+        let mut body = parse_body(&body)?;
+        apply_field_aliases(&mut body, &request_id);
+        validate_request(&body, &ctx.model_name, ctx.kv_cache_size_pre_task)?;
+        strip_fill_context_max_tokens(&mut body);
+
+        if let Some(system_prompt) = &ctx.system_prompt {
+            inject_system_prompt(&mut body, system_prompt)?;
+        }
+
+        let req: CompletionRequest = parse_request(body)?;
+        debug!("[{}] v1_completions: {:?}", request_id, req);
+
+        let is_stream = req.inner.stream.unwrap_or(false);
+        let best_n = req.best_n.unwrap_or(1);
+        let include_timing = req.include_timing.unwrap_or(false);
+        let include_usage = req.inner.stream_options.as_ref().is_some_and(|options| options.include_usage);
+        check_best_n(best_n, is_stream)?;
+        let token_gap = stream_token_gap(&ctx, req.max_tokens_per_second)?;
+        let soft_prompt = find_soft_prompt(&ctx, req.soft_prompt_id.as_deref())?;
+        let mut task = completion_req_to_task(req, ctx.model.clone(), tx, request_id.to_string()).await?;
+        let prompt_tokens = task.input_token_list.len() as u32;
+        let virtual_tokens = soft_prompt.as_ref().map(|p| p.n_tokens as u32).unwrap_or(0);
+        check_context_limit(&ctx, prompt_tokens + virtual_tokens)?;
+        ensure!(prompt_tokens + virtual_tokens < ctx.kv_cache_size_pre_task, "Prompt too large");
+        task.soft_prompt = soft_prompt;
+
+        let resp = if is_stream {
+            let registration = ControlRegistration::new(ctx.clone(), &request_id)?;
+            task.control = Some(registration.control.clone());
+            send_to_backend(task, &*ctx)?;
+
+            let mut single_token_bytes = Vec::new();
+            let generation = Arc::new(Mutex::new(Generation::default()));
+            let finish_chunk = {
+                let (id, model, generation) = (completion_id.clone(), ctx.model_name.clone(), generation.clone());
+                move || completion_finish_chunk(id, model, &generation.lock().unwrap())
+            };
+            let completion_tokens = Arc::new(AtomicU32::new(0));
+
+            let usage_chunk = {
+                let (id, model, completion_tokens) = (completion_id.clone(), ctx.model_name.clone(), completion_tokens.clone());
+
+                move || async_openai::types::CreateCompletionResponse {
+                    id,
+                    choices: Vec::new(),
+                    created: Utc::now().timestamp() as u32,
+                    model,
+                    system_fingerprint: None,
+                    object: "text_completion".to_string(),
+                    usage: Some(stream_usage(prompt_tokens, completion_tokens.load(Ordering::Relaxed)))
+                }
+            };
+
+            let chunks = heartbeat_token_stream(rx, ctx.sse_heartbeat.unwrap(), token_gap, generation.clone())
+                .map(move |token| {
+                    let token = match token {
+                        Some(token) => token,
+                        None => return Ok(Some(registration.heartbeat()))
+                    };
+                    completion_tokens.fetch_add(1, Ordering::Relaxed);
+
+                    let token_bytes = ctx.model.token_to_bytes(token, Special::Plaintext)?;
+                    single_token_bytes.extend_from_slice(&token_bytes);
+
+                    let text = match String::from_utf8(single_token_bytes.clone()) {
+                        Ok(v) => v,
+                        Err(_) => return Ok(None)
+                    };
+
+                    single_token_bytes.clear();
+
+                    let completion_resp = async_openai::types::CreateCompletionResponse {
+                        id: completion_id.clone(),
+                        choices: vec![Choice{
+                            text,
+                            index: 0,
+                            logprobs: None,
+                            finish_reason: None
+                        }],
+                        created: Utc::now().timestamp() as u32,
+                        model: ctx.model_name.clone(),
+                        system_fingerprint: None,
+                        object: "text_completion".to_string(),
+                        usage: None
+                    };
+
+                    Result::<_, anyhow::Error>::Ok(Some(StreamChunk::Data(completion_resp)))
+                })
+                .filter_map(|v| async {
+                    v.transpose()
+                })
+                .chain(futures_util::stream::once(async move {
+                    finish_chunk().map(StreamChunk::Finish)
+                }))
+                .chain(optional_chunk(include_usage, usage_chunk))
+                .chain(futures_util::stream::once(async {
+                    Ok(StreamChunk::Done)
+                }));
+
+            stream_response(stream_format, chunks)
+        } else if ctx.chunked_responses && best_n == 1 {
+            // identical in-flight requests aren't shared, there is no complete generation to hand over
+            send_to_backend(task, &*ctx)?;
+            chunked_completion_response(&ctx, rx, &completion_id, prompt_tokens, include_timing)
+        } else {
+            let generation = generate_best(task, rx, &ctx, best_n).await?;
 
-            let completion_tokens = out_tokens.len() as u32;
-            let text = tokens_to_string(out_tokens, ctx.model.clone()).await?;
+            let completion_tokens = generation.tokens.len() as u32;
+            let prompt_tokens_cached = generation.prompt_tokens_cached;
+            let confidence_token = generation.confidence_token;
+            let timed_out = generation.timed_out;
+            let cache_hit = generation.cache_hit;
+            let timing = generation.timing.summary();
+            let usage_timing = include_timing.then(|| generation.timing.usage_timing());
+            let text = tokens_to_string(generation.tokens, ctx.model.clone()).await?;
 
             let completion_resp = async_openai::types::CreateCompletionResponse {
                 id: completion_id,
@@ -490,99 +2649,438 @@ async fn v1_completions(
                     prompt_tokens,
                     completion_tokens,
                     total_tokens: prompt_tokens + completion_tokens,
-                    prompt_tokens_details: None,
+                    prompt_tokens_details: Some(PromptTokensDetails {
+                        audio_tokens: None,
+                        cached_tokens: Some(prompt_tokens_cached),
+                    }),
                     completion_tokens_details: None
                 })
             };
 
-            let body = serde_json::to_vec(&completion_resp)?;
-            Response::new(Body::from(body))
+            let body = response_body(&completion_resp, confidence_token, timed_out, usage_timing)?;
+            let mut resp = Response::new(Body::from(body));
+            resp.extensions_mut().insert(TokenUsage { prompt_tokens, completion_tokens });
+            timing.insert_headers(resp.headers_mut());
+
+            if cache_hit {
+                resp.headers_mut().insert(&CACHE_HEADER, HeaderValue::from_static("HIT"));
+            }
+            resp
+        };
+        Result::<_, anyhow::Error>::Ok(resp)
+    };
+
+    match fut.await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("[{}] v1_completions error: {:?}", request_id, e);
+            error_response(&e)
+        }
+    }
+}
+
+// an input longer than the kv cache of a task is truncated to fit, ret: (tokens, truncated)
+pub fn embedding_input_tokens(model: &LlamaModel, input: &str, kv_cache_size_pre_task: u32) -> Result<(Vec<LlamaToken>, bool)> {
+    let mut input_tokens = model.str_to_token(input, AddBos::Never)?;
+    let truncated = input_tokens.len() > kv_cache_size_pre_task as usize;
+    input_tokens.truncate(kv_cache_size_pre_task as usize);
+    Ok((input_tokens, truncated))
+}
+
+// ret: (tasks, indices of the truncated inputs)
+async fn embedding_req_to_task(
+    req: async_openai::types::CreateEmbeddingRequest,
+    model: Arc<LlamaModel>,
+    callback: flume::Sender<(usize, Vec<f32>)>,
+    kv_cache_size_pre_task: u32,
+    max_batch_size: usize,
+) -> Result<(Vec<EmbeddingTask>, Vec<usize>)> {
+    let prompts = match req.input {
+        EmbeddingInput::String(prompt) => vec![prompt],
+        EmbeddingInput::StringArray(prompts) => prompts,
+        _ => return Err(anyhow!("Only string prompts are supported")),
+    };
+
+    if prompts.len() > max_batch_size {
+        let msg = format!("at most {} inputs are allowed, got {}", max_batch_size, prompts.len());
+        return Err(ApiError::BadRequest(msg).into());
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let mut tasks = Vec::with_capacity(prompts.len());
+        let mut truncated = Vec::new();
+
+        for (index, prompt) in prompts.into_iter().enumerate() {
+            let (input_tokens, is_truncated) = embedding_input_tokens(&model, &prompt, kv_cache_size_pre_task)?;
+
+            if is_truncated {
+                truncated.push(index);
+            }
+
+            tasks.push(EmbeddingTask {
+                to_api: callback.clone(),
+                index,
+                input_token_list: input_tokens,
+            });
+        }
+
+        Result::<_, anyhow::Error>::Ok((tasks, truncated))
+    }).await?
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum WsControl {
+    Stop,
+    Inject { tokens: Vec<i32> },
+}
+
+enum WsInput {
+    Event(Option<CompletionsEvent>),
+    Message(Option<Result<Message, axum::Error>>),
+}
+
+async fn ws_send(socket: &mut WebSocket, value: serde_json::Value) -> Result<()> {
+    socket.send(Message::Text(value.to_string().into())).await?;
+    Ok(())
+}
+
+// the first frame is the completion request, afterwards the client may send control frames while tokens are streamed back
+async fn completions_ws(socket: &mut WebSocket, ctx: &Context<CompletionsTask>, request_id: &RequestId) -> Result<()> {
+    let mut body = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => parse_body(text.as_bytes())?,
+        _ => return Ok(()),
+    };
+    apply_field_aliases(&mut body, request_id);
+    validate_request(&body, &ctx.model_name, ctx.kv_cache_size_pre_task)?;
+    strip_fill_context_max_tokens(&mut body);
+
+    if let Some(system_prompt) = &ctx.system_prompt {
+        inject_system_prompt(&mut body, system_prompt)?;
+    }
+
+    let req: CompletionRequest = parse_request(body)?;
+    debug!("[{}] v1_completions_ws: {:?}", request_id, req);
+
+    let (tx, rx) = flume::unbounded();
+    let (inject_tx, inject_rx) = flume::unbounded();
+
+    let soft_prompt = find_soft_prompt(ctx, req.soft_prompt_id.as_deref())?;
+    let mut task = completion_req_to_task(req, ctx.model.clone(), tx, request_id.to_string()).await?;
+    let prompt_tokens = task.input_token_list.len() as u32;
+    let virtual_tokens = soft_prompt.as_ref().map(|p| p.n_tokens as u32).unwrap_or(0);
+    check_context_limit(ctx, prompt_tokens + virtual_tokens)?;
+    ensure!(prompt_tokens + virtual_tokens < ctx.kv_cache_size_pre_task, "Prompt too large");
+    task.soft_prompt = soft_prompt;
+    task.injections = Some(inject_rx);
+
+    send_to_backend(task, ctx)?;
+
+    let n_vocab = ctx.model.n_vocab();
+    let mut single_token_bytes = Vec::new();
+    let mut completion_tokens = 0;
+    let mut injected_tokens = 0;
+    // generated token count at the last injection
+    let mut last_injection = None;
+    let mut finish_reason = "stop";
+
+    loop {
+        // the socket can't be borrowed by recv and send at once, so the branches are handled after select
+        let input = tokio::select! {
+            event = rx.recv_async() => WsInput::Event(event.ok()),
+            msg = socket.recv() => WsInput::Message(msg),
+        };
+
+        match input {
+            WsInput::Event(None) => break,
+            WsInput::Event(Some(CompletionsEvent::Token(token))) => {
+                completion_tokens += 1;
+                single_token_bytes.extend_from_slice(&ctx.model.token_to_bytes(token, Special::Plaintext)?);
+
+                if let Ok(text) = String::from_utf8(single_token_bytes.clone()) {
+                    single_token_bytes.clear();
+                    ws_send(socket, serde_json::json!({"text": text})).await?;
+                }
+            }
+            WsInput::Event(Some(CompletionsEvent::Injected(n))) => injected_tokens += n,
+            WsInput::Event(Some(CompletionsEvent::Confident(_))) => finish_reason = "confidence",
+            WsInput::Event(Some(CompletionsEvent::Length)) => finish_reason = "length",
+            WsInput::Event(Some(CompletionsEvent::Failed)) => return Err(anyhow!("inference of the request failed")),
+            WsInput::Event(Some(CompletionsEvent::Started(_) | CompletionsEvent::PromptCached(_) | CompletionsEvent::PromptLogprob(_))) => (),
+            WsInput::Message(msg) => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                };
+
+                match serde_json::from_str::<WsControl>(&text) {
+                    // dropping the receiver ends the sequence on the next token
+                    Ok(WsControl::Stop) => break,
+                    Ok(WsControl::Inject { tokens }) => {
+                        if last_injection == Some(completion_tokens) {
+                            ws_send(socket, serde_json::json!({"error": "at most one injection per generated token"})).await?;
+                            continue;
+                        }
+
+                        if tokens.is_empty() || tokens.iter().any(|t| *t < 0 || *t >= n_vocab) {
+                            ws_send(socket, serde_json::json!({"error": "invalid injected token ids"})).await?;
+                            continue;
+                        }
+
+                        last_injection = Some(completion_tokens);
+                        let _ = inject_tx.send(tokens.into_iter().map(LlamaToken).collect());
+                    }
+                    Err(e) => ws_send(socket, serde_json::json!({"error": e.to_string()})).await?,
+                }
+            }
+        }
+    }
+
+    let usage = serde_json::json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "injected_tokens": injected_tokens,
+        "total_tokens": prompt_tokens + completion_tokens + injected_tokens,
+    });
+
+    ws_send(socket, serde_json::json!({"finish_reason": finish_reason, "usage": usage})).await?;
+    Ok(())
+}
+
+async fn v1_completions_ws(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Extension(request_id): Extension<RequestId>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(|mut socket| async move {
+        if let Err(e) = completions_ws(&mut socket, &ctx, &request_id).await {
+            warn!("[{}] websocket completions error: {:?}", request_id, e);
+            let _ = ws_send(&mut socket, serde_json::json!({"error": e.to_string()})).await;
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct PerplexityRequest {
+    text: String,
+    // distance between the starts of two windows, defaults to the largest stride
+    stride: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct PerplexityResponse {
+    perplexity: f64,
+    // from the second token on, the first has no context
+    token_logprobs: Vec<f32>,
+}
+
+// windows of kv_cache_size_pre_task - 1 tokens, each token is scored by the first window that reaches it
+async fn perplexity(
+    ctx: &Context<CompletionsTask>,
+    req: PerplexityRequest,
+    request_id: &RequestId,
+) -> Result<PerplexityResponse> {
+    let model = ctx.model.clone();
+    let text = req.text;
+    let tokens = tokio::task::spawn_blocking(move || model.str_to_token(&text, AddBos::Always)).await??;
+
+    if tokens.len() < 2 {
+        return Err(ApiError::BadRequest(String::from("text must have at least 2 tokens")).into());
+    }
+
+    if ctx.speculative {
+        return Err(ApiError::BadRequest(String::from("perplexity is not supported with speculative decoding")).into());
+    }
+
+    let window = ctx.kv_cache_size_pre_task as usize - 1;
+    // windows overlap by at least one token, the first token of a window only serves as context
+    let max_stride = window - 1;
+    let stride = req.stride.map(|s| s as usize).unwrap_or(max_stride);
+
+    if stride == 0 || stride > max_stride {
+        return Err(ApiError::BadRequest(format!("stride must be between 1 and {}", max_stride)).into());
+    }
+
+    // (channel, logprobs to skip because an earlier window scored them)
+    let mut windows = Vec::new();
+    let mut scored_end = 1;
+    let mut begin = 0;
+
+    loop {
+        let end = min(begin + window, tokens.len());
+        let (tx, rx) = flume::unbounded();
+
+        let task = CompletionsTask {
+            to_api: tx,
+            input_token_list: tokens[begin..end].to_vec(),
+            sampler_params: SamplerParams::default(),
+            maximum_tokens: MaxTokens::FillContext,
+            soft_prompt: None,
+            injections: None,
+            history_len: None,
+            request_id: request_id.to_string(),
+            control: None,
+            prompt_logprobs: true,
+            disable_speculative: false,
+            enqueued_at: Instant::now(),
+            stop_token_ids: HashSet::new(),
         };
-        Result::<_, anyhow::Error>::Ok(resp)
-    };
 
-    match fut.await {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("v1_completions error: {:?}", e);
+        send_to_backend(task, ctx)?;
+        windows.push((rx, scored_end - (begin + 1)));
+        scored_end = end;
 
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(e.to_string()))
-                .unwrap()
+        if end == tokens.len() {
+            break;
         }
+        begin += stride;
     }
-}
+    debug!("[{}] perplexity of {} tokens in {} windows", request_id, tokens.len(), windows.len());
 
-async fn embedding_req_to_task(
-    req: async_openai::types::CreateEmbeddingRequest,
-    model: Arc<LlamaModel>,
-    callback: flume::Sender<Vec<f32>>,
-) -> Result<Vec<EmbeddingTask>> {
-    tokio::task::spawn_blocking(move || {
-        let input_tokens = match req.input {
-            EmbeddingInput::String(prompt) => {
-                vec![model.str_to_token(&prompt, AddBos::Never)?]
-            },
-            EmbeddingInput::StringArray(prompts) => {
-                let mut token_list = Vec::with_capacity(prompts.len());
-
-                for prompt in prompts {
-                    token_list.push(model.str_to_token(&prompt, AddBos::Never)?);
+    let mut token_logprobs = Vec::with_capacity(tokens.len() - 1);
+
+    for (rx, skip) in windows {
+        let mut n = 0;
+
+        while let Ok(event) = rx.recv_async().await {
+            if let CompletionsEvent::PromptLogprob(logprob) = event {
+                if n >= skip {
+                    token_logprobs.push(logprob);
                 }
-                token_list
-            },
-            _ => return Err(anyhow!("Only string prompts are supported")),
-        };
+                n += 1;
+            }
+        }
+    }
+
+    ensure!(token_logprobs.len() == tokens.len() - 1, "backend returned {} of {} token logprobs", token_logprobs.len(), tokens.len() - 1);
+
+    let mean = token_logprobs.iter().map(|l| *l as f64).sum::<f64>() / token_logprobs.len() as f64;
+
+    Ok(PerplexityResponse {
+        perplexity: (-mean).exp(),
+        token_logprobs,
+    })
+}
+
+async fn v1_perplexity(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<PerplexityRequest>,
+) -> Response {
+    match perplexity(&ctx, req, &request_id).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(e) => {
+            error!("[{}] v1_perplexity error: {:?}", request_id, e);
+            error_response(&e)
+        }
+    }
+}
+
+// a paused sequence keeps its kv cache slot, so a pause nobody resumes ends on its own
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn find_control(ctx: &Context<CompletionsTask>, request_id: &str) -> Result<Arc<RequestControl>> {
+    let control = ctx.request_controls.get(request_id)
+        .ok_or_else(|| ApiError::NotFound(format!("no streaming completion with request id {}", request_id)))?;
+    Ok(control.clone())
+}
+
+async fn v1_completions_pause(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Path(request_id): Path<String>,
+) -> Response {
+    match find_control(&ctx, &request_id) {
+        Ok(control) => {
+            let pause = control.pause();
+            info!("[{}] paused", request_id);
+
+            tokio::spawn(async move {
+                tokio::time::sleep(PAUSE_TIMEOUT).await;
 
-        let tasks = input_tokens.into_iter()
-            .map(|tokens| {
-                EmbeddingTask {
-                    to_api: callback.clone(),
-                    input_token_list: tokens
+                if control.resume_pause(pause) {
+                    warn!("[{}] pause timed out after {:?}, resuming", request_id, PAUSE_TIMEOUT);
                 }
-            })
-            .collect::<Vec<_>>();
+            });
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&e),
+    }
+}
 
-        Result::<_, anyhow::Error>::Ok(tasks)
-    }).await?
+async fn v1_completions_resume(
+    State(ctx): State<Arc<Context<CompletionsTask>>>,
+    Path(request_id): Path<String>,
+) -> Response {
+    match find_control(&ctx, &request_id) {
+        Ok(control) => {
+            control.resume();
+            info!("[{}] resumed", request_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&e),
+    }
 }
 
 async fn v1_embedding(
     State(ctx): State<Arc<Context<EmbeddingTask>>>,
+    Extension(request_id): Extension<RequestId>,
     Json(req): Json<async_openai::types::CreateEmbeddingRequest>
 ) -> Response {
     let fut = async {
         let (tx, rx) = flume::unbounded();
         let format = req.encoding_format.clone().unwrap_or(EncodingFormat::Float);
-        let tasks= embedding_req_to_task(req, ctx.model.clone(), tx).await?;
 
+        let (tasks, truncated) = embedding_req_to_task(
+            req,
+            ctx.model.clone(),
+            tx,
+            ctx.kv_cache_size_pre_task,
+            ctx.max_embedding_batch_size.unwrap(),
+        ).await?;
+
+        let n_inputs = tasks.len();
         let mut total_tokens = 0;
+
+        // all inputs or none, a batch enqueued in part would keep running after the 503.
+        // the backend skips the inputs of a request that is gone, in case another request fills the queue in between
+        if let Some(capacity) = ctx.backend_bridge.capacity() {
+            if n_inputs > capacity {
+                return Err(invalid_request(Some("input"), format!("{} inputs exceed the inference queue capacity of {}", n_inputs, capacity)));
+            }
+
+            if ctx.backend_bridge.len() + n_inputs > capacity {
+                return Err(queue_full(&ctx.backend_bridge, &ctx.metrics, &ctx.parallel_tasks));
+            }
+        }
+
         for task in tasks {
-            let input_tokens = task.input_token_list.len() as u32;
-            ensure!(input_tokens <= ctx.kv_cache_size_pre_task, "input tokens too large, input tokens len: {input_tokens}");
+            total_tokens += task.input_token_list.len() as u32;
+            send_to_backend(task, &*ctx)?;
+        }
 
-            send_to_backend(task, &*ctx).await?;
-            total_tokens += input_tokens;
+        // the backend may finish the inputs in any order
+        let mut embeddings_list = vec![Vec::new(); n_inputs];
+
+        while let Ok((index, embeddings)) = rx.recv_async().await {
+            embeddings_list[index] = embeddings;
         }
+        // the channel also closes when the batch of an input failed to decode
+        ensure!(embeddings_list.iter().all(|embeddings| !embeddings.is_empty()), "inference of the request failed");
 
         let out = match format {
             EncodingFormat::Float => {
                 let resp = CreateEmbeddingResponse  {
                     object: String::from("list"),
                     model: ctx.model_name.clone(),
-                    data: {
-                        let mut out = Vec::new();
-
-                        while let Ok(embeddings) = rx.recv_async().await  {
-                            out.push(Embedding {
-                                index: out.len() as u32,
-                                object: String::from("embedding"),
-                                embedding: embeddings
-                            });
-                        }
-                        out
-                    },
+                    data: embeddings_list.into_iter()
+                        .enumerate()
+                        .map(|(index, embeddings)| Embedding {
+                            index: index as u32,
+                            object: String::from("embedding"),
+                            embedding: embeddings
+                        })
+                        .collect(),
                     usage: EmbeddingUsage {
                         prompt_tokens: total_tokens,
                         total_tokens
@@ -595,26 +3093,22 @@ async fn v1_embedding(
                 let resp = CreateBase64EmbeddingResponse {
                     object: String::from("list"),
                     model: ctx.model_name.clone(),
-                    data: {
-                        let mut out = Vec::new();
-
-                        while let Ok(embeddings) = rx.recv_async().await {
-                            out.push(Base64Embedding {
-                                index: out.len() as u32,
-                                object: String::from("embedding"),
-                                embedding: Base64EmbeddingVector({
-                                    let mut buff: Vec<u8> = Vec::with_capacity(embeddings.len() * 4);
-
-                                    for x in embeddings {
-                                        buff.extend_from_slice(&x.to_le_bytes());
-                                    }
-
-                                    BASE64_STANDARD.encode(&buff)
-                                })
-                            });
-                        }
-                        out
-                    },
+                    data: embeddings_list.into_iter()
+                        .enumerate()
+                        .map(|(index, embeddings)| Base64Embedding {
+                            index: index as u32,
+                            object: String::from("embedding"),
+                            embedding: Base64EmbeddingVector({
+                                let mut buff: Vec<u8> = Vec::with_capacity(embeddings.len() * 4);
+
+                                for x in embeddings {
+                                    buff.extend_from_slice(&x.to_le_bytes());
+                                }
+
+                                BASE64_STANDARD.encode(&buff)
+                            })
+                        })
+                        .collect(),
                     usage: EmbeddingUsage {
                         prompt_tokens: total_tokens,
                         total_tokens
@@ -624,10 +3118,17 @@ async fn v1_embedding(
             }
         };
 
-        let resp = Response::builder()
+        let mut builder = Response::builder()
             .status(StatusCode::OK)
             .header("content-type", "application/json")
-            .body(Body::from(out))?;
+            .extension(TokenUsage { prompt_tokens: total_tokens, completion_tokens: 0 });
+
+        if !truncated.is_empty() {
+            let indices = truncated.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+            builder = builder.header("X-Truncated-Inputs", format!("[{}]", indices));
+        }
+
+        let resp = builder.body(Body::from(out))?;
 
         Result::<_, anyhow::Error>::Ok(resp)
     };
@@ -635,69 +3136,771 @@ async fn v1_embedding(
     match fut.await {
         Ok(resp) => resp,
         Err(e) => {
-            error!("v1_embedding error: {:?}", e);
+            error!("[{}] v1_embedding error: {:?}", request_id, e);
+            error_response(&e)
+        }
+    }
+}
 
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(e.to_string()))
-                .unwrap()
+#[derive(Serialize)]
+struct ModelInfo {
+    model: String,
+    quantization: String,
+    quantization_version: Option<u32>,
+    bits_per_weight: f32,
+    // null when the model has no such token
+    bos_token_id: Option<i32>,
+    eos_token_id: Option<i32>,
+    fim_prefix_token_id: Option<i32>,
+    fim_suffix_token_id: Option<i32>,
+    fim_middle_token_id: Option<i32>,
+    fim_pad_token_id: Option<i32>,
+    // null without --context-size-limit
+    max_context_length_enforced: Option<u32>,
+    compatibility_aliases: &'static [&'static str],
+    supported_parameters: &'static [&'static str],
+}
+
+async fn v1_model_info<Task>(State(ctx): State<Arc<Context<Task>>>) -> Json<ModelInfo> {
+    let vocab = unsafe { llama_cpp_sys_2::llama_model_get_vocab(ctx.model.as_ptr()) };
+    let token_id = |token: llama_cpp_sys_2::llama_token| Some(token).filter(|t| *t != llama_cpp_sys_2::LLAMA_TOKEN_NULL);
+
+    // llama.cpp reads the fim tokens from tokenizer.ggml.fim_*_token_id, or finds them in the vocabulary by their usual text
+    let info = unsafe {
+        ModelInfo {
+            model: ctx.model_name.clone(),
+            quantization: metadata::quantization_summary(&ctx.model),
+            quantization_version: metadata::quantization_version(&ctx.model),
+            bits_per_weight: metadata::bits_per_weight(&ctx.model),
+            bos_token_id: token_id(llama_cpp_sys_2::llama_vocab_bos(vocab)),
+            eos_token_id: token_id(llama_cpp_sys_2::llama_vocab_eos(vocab)),
+            fim_prefix_token_id: token_id(llama_cpp_sys_2::llama_vocab_fim_pre(vocab)),
+            fim_suffix_token_id: token_id(llama_cpp_sys_2::llama_vocab_fim_suf(vocab)),
+            fim_middle_token_id: token_id(llama_cpp_sys_2::llama_vocab_fim_mid(vocab)),
+            fim_pad_token_id: token_id(llama_cpp_sys_2::llama_vocab_fim_pad(vocab)),
+            max_context_length_enforced: ctx.context_limit.map(|limit| limit.size),
+            compatibility_aliases: COMPATIBILITY_ALIASES,
+            supported_parameters: SUPPORTED_PARAMETERS,
+        }
+    };
+    Json(info)
+}
+
+#[derive(Serialize)]
+struct ModelObject {
+    id: String,
+    object: &'static str,
+    created: i64,
+    owned_by: &'static str,
+    // null when the file type is unknown, so clients can tell it apart from a server without the field
+    quantization: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+async fn v1_models<Task>(State(ctx): State<Arc<Context<Task>>>) -> Json<ModelList> {
+    let model = ModelObject {
+        id: ctx.model_name.clone(),
+        object: "model",
+        created: ctx.started_at,
+        owned_by: "hibiki",
+        quantization: metadata::quantization_name(&ctx.model),
+    };
+
+    Json(ModelList {
+        object: "list",
+        data: vec![model],
+    })
+}
+
+const MAX_VOCABULARY_PAGE: u32 = 10000;
+
+// bos and eos carry the control attribute as well
+const SPECIAL_TOKEN_ATTRS: llama_cpp_sys_2::llama_token_attr = llama_cpp_sys_2::LLAMA_TOKEN_ATTR_CONTROL | llama_cpp_sys_2::LLAMA_TOKEN_ATTR_UNKNOWN;
+
+#[derive(Deserialize)]
+struct VocabularyQuery {
+    #[serde(default)]
+    offset: u32,
+    #[serde(default = "default_vocabulary_limit")]
+    limit: u32,
+}
+
+fn default_vocabulary_limit() -> u32 {
+    1000
+}
+
+#[derive(Serialize)]
+struct VocabularyToken {
+    id: i32,
+    string: String,
+    is_special: bool,
+}
+
+#[derive(Serialize)]
+struct Vocabulary {
+    tokens: Vec<VocabularyToken>,
+}
+
+fn vocabulary_page(model: &LlamaModel, offset: u32, limit: u32) -> Result<Vocabulary> {
+    if limit > MAX_VOCABULARY_PAGE {
+        return Err(ApiError::BadRequest(format!("limit must not exceed {}", MAX_VOCABULARY_PAGE)).into());
+    }
+
+    let n_vocab = model.n_vocab() as u32;
+    let end = min(offset.saturating_add(limit), n_vocab);
+    let vocab = unsafe { llama_cpp_sys_2::llama_model_get_vocab(model.as_ptr()) };
+    let mut tokens = Vec::new();
+
+    for id in offset..end {
+        let token = LlamaToken(id as i32);
+        // tokenizer text, special tokens as written in the prompt
+        let bytes = model.token_to_bytes(token, Special::Tokenize)?;
+        let attr = unsafe { llama_cpp_sys_2::llama_token_get_attr(vocab, token.0) };
+
+        let is_special = token == model.token_bos() ||
+            token == model.token_eos() ||
+            attr & SPECIAL_TOKEN_ATTRS != 0;
+
+        tokens.push(VocabularyToken {
+            id: token.0,
+            string: String::from_utf8_lossy(&bytes).to_string(),
+            is_special,
+        });
+    }
+    Ok(Vocabulary { tokens })
+}
+
+async fn v1_model_vocabulary<Task>(
+    State(ctx): State<Arc<Context<Task>>>,
+    Query(query): Query<VocabularyQuery>,
+) -> Response {
+    let model = ctx.model.clone();
+    let res = tokio::task::spawn_blocking(move || vocabulary_page(&model, query.offset, query.limit)).await;
+
+    match res.map_err(anyhow::Error::from).and_then(|res| res) {
+        Ok(vocabulary) => Json(vocabulary).into_response(),
+        Err(e) => error_response(&e),
+    }
+}
+
+#[derive(Serialize)]
+struct SpecialToken {
+    id: i32,
+    string: String,
+    attribute_flags: u32,
+}
+
+#[derive(Serialize)]
+struct SpecialTokens {
+    bos_token_id: Option<i32>,
+    eos_token_id: Option<i32>,
+    unk_token_id: Option<i32>,
+    pad_token_id: Option<i32>,
+    add_bos_token: bool,
+    add_eos_token: bool,
+    additional_special_tokens: Vec<SpecialToken>,
+}
+
+fn special_tokens(model: &LlamaModel) -> Result<SpecialTokens> {
+    let vocab = unsafe { llama_cpp_sys_2::llama_model_get_vocab(model.as_ptr()) };
+    let token_id = |token: llama_cpp_sys_2::llama_token| Some(token).filter(|t| *t != llama_cpp_sys_2::LLAMA_TOKEN_NULL);
+
+    let (bos, eos, pad, add_bos_token, add_eos_token) = unsafe {
+        (
+            token_id(llama_cpp_sys_2::llama_vocab_bos(vocab)),
+            token_id(llama_cpp_sys_2::llama_vocab_eos(vocab)),
+            token_id(llama_cpp_sys_2::llama_vocab_pad(vocab)),
+            llama_cpp_sys_2::llama_vocab_get_add_bos(vocab),
+            llama_cpp_sys_2::llama_vocab_get_add_eos(vocab),
+        )
+    };
+
+    let mut unk = None;
+    let mut additional_special_tokens = Vec::new();
+
+    // llama.cpp has no getter for the unknown token, it is found by its attribute like the other special tokens
+    for id in 0..model.n_vocab() {
+        let attr = unsafe { llama_cpp_sys_2::llama_token_get_attr(vocab, id) };
+
+        if attr & SPECIAL_TOKEN_ATTRS == 0 {
+            continue;
+        }
+
+        if unk.is_none() && attr & llama_cpp_sys_2::LLAMA_TOKEN_ATTR_UNKNOWN != 0 {
+            unk = Some(id);
+            continue;
+        }
+
+        if [bos, eos, pad].contains(&Some(id)) {
+            continue;
+        }
+
+        let bytes = model.token_to_bytes(LlamaToken(id), Special::Tokenize)?;
+
+        additional_special_tokens.push(SpecialToken {
+            id,
+            string: String::from_utf8_lossy(&bytes).to_string(),
+            attribute_flags: attr as u32,
+        });
+    }
+
+    let tokens = SpecialTokens {
+        bos_token_id: bos,
+        eos_token_id: eos,
+        unk_token_id: unk,
+        pad_token_id: pad,
+        add_bos_token,
+        add_eos_token,
+        additional_special_tokens,
+    };
+    Ok(tokens)
+}
+
+async fn v1_model_special_tokens<Task>(State(ctx): State<Arc<Context<Task>>>) -> Response {
+    let model = ctx.model.clone();
+    let res = tokio::task::spawn_blocking(move || special_tokens(&model)).await;
+
+    match res.map_err(anyhow::Error::from).and_then(|res| res) {
+        Ok(tokens) => Json(tokens).into_response(),
+        Err(e) => error_response(&e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ParallelTasksQuery {
+    n: u32,
+}
+
+// lowering the limit lets in-flight tasks finish, new tasks only take slots below it
+async fn admin_set_parallel_tasks<Task>(
+    State(ctx): State<Arc<Context<Task>>>,
+    Query(query): Query<ParallelTasksQuery>,
+) -> Response {
+    if query.n == 0 || query.n > ctx.max_parallel_tasks {
+        let msg = format!(
+            "parallel tasks must be between 1 and {}, the kv cache is allocated for --parallel-tasks at startup",
+            ctx.max_parallel_tasks
+        );
+        return error_response(&ApiError::BadRequest(msg).into());
+    }
+
+    let old = ctx.parallel_tasks.swap(query.n, Ordering::Relaxed);
+    info!("parallel tasks changed from {} to {}", old, query.n);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn prometheus_metrics<Task>(State(ctx): State<Arc<Context<Task>>>) -> Response {
+    Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(ctx.metrics.render(
+            ctx.backend_bridge.len(),
+            ctx.backend_bridge.capacity().unwrap_or(0),
+            ctx.parallel_tasks.load(Ordering::Relaxed),
+        )))
+        .unwrap()
+}
+
+// runs through the inference channel before the listener opens, the output is discarded
+pub async fn warmup_completions(
+    model: Arc<LlamaModel>,
+    backend_bridge: flume::Sender<CompletionsTask>,
+    kv_cache_size_pre_task: u32,
+    prompt: String,
+    max_tokens: u32,
+) -> Result<()> {
+    let start = Instant::now();
+    let input_tokens = tokio::task::spawn_blocking(move || model.str_to_token(&prompt, AddBos::Always)).await??;
+    let prompt_tokens = input_tokens.len();
+    ensure!((prompt_tokens as u32) < kv_cache_size_pre_task, "warm-up prompt too large, prompt tokens len: {prompt_tokens}");
+
+    let (tx, rx) = flume::unbounded();
+
+    let task = CompletionsTask {
+        to_api: tx,
+        input_token_list: input_tokens,
+        sampler_params: SamplerParams::default(),
+        maximum_tokens: MaxTokens::Finite(max_tokens),
+        soft_prompt: None,
+        injections: None,
+        history_len: None,
+        request_id: String::from("warm-up"),
+        control: None,
+        prompt_logprobs: false,
+        disable_speculative: false,
+        enqueued_at: Instant::now(),
+        stop_token_ids: HashSet::new(),
+    };
+
+    backend_bridge.send_async(task).await.map_err(|_| anyhow!("backend channel disconnected"))?;
+
+    let mut completion_tokens = 0;
+
+    while let Ok(event) = rx.recv_async().await {
+        if let CompletionsEvent::Token(_) = event {
+            completion_tokens += 1;
+        }
+    }
+
+    info!(
+        "warm-up finished in {:?}, prompt tokens: {}, completion tokens: {}",
+        start.elapsed(),
+        prompt_tokens,
+        completion_tokens
+    );
+    Ok(())
+}
+
+const SELF_TEST_PROMPT: &str = "The quick brown fox jumps over the lazy dog. The quick brown fox";
+const SELF_TEST_SEED: i64 = 42;
+
+// greedy decoding of a fixed prompt through the inference loop, the sha-256 of the output text only changes
+// with the model, its loading or llama.cpp, e.g. a corrupted file or a wrong rope configuration
+pub async fn self_test_completions(
+    model: Arc<LlamaModel>,
+    backend_bridge: flume::Sender<CompletionsTask>,
+    max_tokens: u32,
+    expected_hash: Option<String>,
+) -> Result<()> {
+    let start = Instant::now();
+    let input_tokens = tokio::task::spawn_blocking({
+        let model = model.clone();
+        move || model.str_to_token(SELF_TEST_PROMPT, AddBos::Always)
+    }).await??;
+
+    let (tx, rx) = flume::unbounded();
+
+    let task = CompletionsTask {
+        to_api: tx,
+        input_token_list: input_tokens,
+        sampler_params: SamplerParams {
+            seed: Some(SELF_TEST_SEED),
+            temperature: Some(0.0),
+            top_k: Some(1),
+            ..SamplerParams::default()
+        },
+        maximum_tokens: MaxTokens::Finite(max_tokens),
+        soft_prompt: None,
+        injections: None,
+        history_len: None,
+        request_id: String::from("self-test"),
+        control: None,
+        prompt_logprobs: false,
+        // the draft tokens among the top candidates of the target would make the output depend on the draft model
+        disable_speculative: true,
+        enqueued_at: Instant::now(),
+        stop_token_ids: HashSet::new(),
+    };
+
+    backend_bridge.send_async(task).await.map_err(|_| anyhow!("backend channel disconnected"))?;
+
+    let mut tokens = Vec::new();
+
+    while let Ok(event) = rx.recv_async().await {
+        if let CompletionsEvent::Token(token) = event {
+            tokens.push(token);
+        }
+    }
+
+    ensure!(!tokens.is_empty(), "self-test generated no tokens");
+
+    let text = tokens_to_string(tokens, model).await?;
+    let hash = format!("{:x}", Sha256::digest(text.as_bytes()));
+    info!("self-test finished in {:?}, output: {:?}, sha-256: {}", start.elapsed(), text, hash);
+
+    match expected_hash {
+        Some(expected) if !hash.eq_ignore_ascii_case(expected.trim()) => {
+            Err(anyhow!("self-test output hash mismatch, expected: {}, actual: {}", expected.trim(), hash))
+        }
+        Some(_) => {
+            info!("self-test output hash verified");
+            Ok(())
+        }
+        None => {
+            info!("self-test has no --self-test-expected-hash, the output hash is not checked");
+            Ok(())
+        }
+    }
+}
+
+pub async fn warmup_embedding(
+    model: Arc<LlamaModel>,
+    backend_bridge: flume::Sender<EmbeddingTask>,
+    kv_cache_size_pre_task: u32,
+    prompt: String,
+) -> Result<()> {
+    let start = Instant::now();
+    let input_tokens = tokio::task::spawn_blocking(move || model.str_to_token(&prompt, AddBos::Never)).await??;
+    let input_len = input_tokens.len();
+    ensure!(input_len as u32 <= kv_cache_size_pre_task, "warm-up prompt too large, input tokens len: {input_len}");
+
+    let (tx, rx) = flume::unbounded();
+
+    let task = EmbeddingTask {
+        to_api: tx,
+        index: 0,
+        input_token_list: input_tokens,
+    };
+
+    backend_bridge.send_async(task).await.map_err(|_| anyhow!("backend channel disconnected"))?;
+    rx.recv_async().await?;
+
+    info!("warm-up finished in {:?}, input tokens: {}", start.elapsed(), input_len);
+    Ok(())
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum LoadingState {
+    LoadingTensors { progress: f32 },
+    LoadingDraftTensors { progress: f32 },
+    Ready,
+}
+
+async fn v1_model_loading_progress(
+    State(loading_state): State<watch::Receiver<LoadingState>>
+) -> Response {
+    let events = futures_util::stream::unfold((loading_state, true, false), |(mut loading_state, first, done)| async move {
+        if done {
+            return None;
+        }
+
+        // clients connected after loading still receive the ready event immediately
+        if !first {
+            loading_state.changed().await.ok()?;
+        }
+
+        let state = loading_state.borrow_and_update().clone();
+        let done = matches!(state, LoadingState::Ready);
+        let event = axum::response::sse::Event::default().json_data(&state);
+        Some((event, (loading_state, false, done)))
+    });
+
+    Sse::new(events).into_response()
+}
+
+// with an ipv4 address among them, ipv6 sockets only take ipv6 traffic, so [::] and 0.0.0.0 can share a port
+fn bind_all(bind_addrs: &[SocketAddr]) -> Result<Vec<tokio::net::TcpListener>> {
+    let v6_only = bind_addrs.iter().any(|addr| addr.is_ipv4());
+    let mut listeners = Vec::with_capacity(bind_addrs.len());
+
+    for &addr in bind_addrs {
+        let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
         }
+
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into()).map_err(|e| anyhow!("bind {} failed: {}", addr, e))?;
+        socket.listen(1024)?;
+        listeners.push(tokio::net::TcpListener::from_std(socket.into())?);
+    }
+    Ok(listeners)
+}
+
+// every listener serves the same router, the first one failing ends all of them
+async fn serve(
+    listeners: Vec<tokio::net::TcpListener>,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let (stop_tx, stop_rx) = watch::channel(());
+    let mut servers = tokio::task::JoinSet::new();
+
+    for listener in listeners {
+        let mut stop_rx = stop_rx.clone();
+
+        // the client address is logged by --log-requests
+        let server = axum::serve(listener, app.clone().into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                let _ = stop_rx.changed().await;
+            });
+
+        servers.spawn(async move { server.await });
+    }
+
+    tokio::spawn(async move {
+        shutdown.await;
+        drop(stop_tx);
+    });
+
+    while let Some(res) = servers.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+// operator endpoints have no authentication, they are only served on --admin-bind-addr and not at all without it
+async fn serve_admin(admin_bind_addr: Option<SocketAddr>, app: Router) -> Result<()> {
+    let addr = match admin_bind_addr {
+        Some(addr) => addr,
+        None => return std::future::pending().await,
+    };
+
+    let listeners = bind_all(&[addr])?;
+    info!("Admin api on http://{}", addr);
+    serve(listeners, app, std::future::pending()).await
+}
+
+fn loading_progress_router(loading_state: watch::Receiver<LoadingState>) -> Router {
+    Router::new()
+        .route("/v1/model/loading-progress", get(v1_model_loading_progress))
+        .with_state(loading_state)
+}
+
+// serves only the loading progress until the model is loaded
+pub async fn run_loading_progress(
+    bind_addrs: Vec<SocketAddr>,
+    loading_state: watch::Receiver<LoadingState>,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    let listeners = bind_all(&bind_addrs)?;
+
+    for addr in &bind_addrs {
+        info!("Loading progress on http://{}/v1/model/loading-progress", addr);
     }
+
+    let shutdown = async {
+        let _ = shutdown.await;
+    };
+    serve(listeners, loading_progress_router(loading_state), shutdown).await
 }
 
 pub async fn run_embedding(
-    bind_addr: SocketAddr,
+    bind_addrs: Vec<SocketAddr>,
     model: Arc<LlamaModel>,
     model_name: String,
     kv_cache_size_pre_task: u32,
+    context_limit: Option<ContextLimit>,
+    max_embedding_batch_size: usize,
     backend_bridge: flume::Sender<EmbeddingTask>,
+    loading_state: watch::Receiver<LoadingState>,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    request_log: Option<Arc<RequestLog>>,
+    log_requests: Option<LogRequests>,
+    max_concurrent_requests: Option<usize>,
+    admin_bind_addr: Option<SocketAddr>,
 ) -> Result<()> {
     let ctx = Context {
         model,
         model_name,
         backend_bridge,
         kv_cache_size_pre_task,
-        chat_template: None
+        chat_template: None,
+        sse_heartbeat: None,
+        non_streaming_timeout: None,
+        chunked_responses: false,
+        speculative: false,
+        response_cache: None,
+        max_tokens_per_second: None,
+        context_limit,
+        max_embedding_batch_size: Some(max_embedding_batch_size),
+        soft_prompts: HashMap::new(),
+        compaction: None,
+        fim_template: None,
+        system_prompt: None,
+        inflight_requests: DashMap::new(),
+        metrics,
+        max_parallel_tasks: parallel_tasks.load(Ordering::Relaxed),
+        started_at: Utc::now().timestamp(),
+        request_controls: DashMap::new(),
+        resumed: Arc::new(Notify::new()),
+        parallel_tasks,
+    };
+
+    let limit = ConcurrencyLimit {
+        metrics: ctx.metrics.clone(),
+        max_concurrent_requests,
+        parallel_tasks: ctx.parallel_tasks.clone(),
     };
 
     let ctx = Arc::new(ctx);
     let app = Router::new()
         .route("/v1/embeddings", post(v1_embedding))
+        .route("/v1/models", get(v1_models::<EmbeddingTask>))
+        .route("/v1/model/info", get(v1_model_info::<EmbeddingTask>))
+        .route("/v1/model/vocabulary", get(v1_model_vocabulary::<EmbeddingTask>))
+        .route("/v1/model/special-tokens", get(v1_model_special_tokens::<EmbeddingTask>))
+        .route("/metrics", get(prometheus_metrics::<EmbeddingTask>))
+        .with_state(ctx.clone())
+        .merge(loading_progress_router(loading_state));
+
+    let app = api_layers(app, request_log, log_requests, limit);
+
+    let admin = Router::new()
+        .route("/admin/set-parallel-tasks", post(admin_set_parallel_tasks::<EmbeddingTask>))
         .with_state(ctx);
 
-    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
-    info!("Listening on http://{}", bind_addr);
-    axum::serve(listener, app).await?;
+    let listeners = bind_all(&bind_addrs)?;
+
+    for addr in &bind_addrs {
+        info!("Listening on http://{}", addr);
+    }
+
+    tokio::try_join!(
+        serve(listeners, app, std::future::pending()),
+        serve_admin(admin_bind_addr, admin),
+    )?;
     Ok(())
 }
 
 pub async fn run_completions(
-    bind_addr: SocketAddr,
+    bind_addrs: Vec<SocketAddr>,
     model: Arc<LlamaModel>,
     model_name: String,
     kv_cache_size_pre_task: u32,
     backend_bridge: flume::Sender<CompletionsTask>,
     template: Option<String>,
+    sse_heartbeat: Duration,
+    non_streaming_timeout: Option<Duration>,
+    chunked_responses: bool,
+    speculative: bool,
+    response_cache_size: usize,
+    max_tokens_per_second: Option<f32>,
+    context_limit: Option<ContextLimit>,
+    soft_prompts: HashMap<String, Arc<SoftPrompt>>,
+    compaction: Option<Compaction>,
+    fim_template: String,
+    system_prompt: Option<SystemPrompt>,
+    loading_state: watch::Receiver<LoadingState>,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    request_log: Option<Arc<RequestLog>>,
+    log_requests: Option<LogRequests>,
+    max_concurrent_requests: Option<usize>,
+    admin_bind_addr: Option<SocketAddr>,
 ) -> Result<()> {
-    let template = ChatTemplates::from_model(&model, template.as_deref());
+    let gguf_template = metadata::get_metadata_str(&model, "tokenizer.chat_template");
+
+    let template = match (template, gguf_template) {
+        (Some(template), Some(_)) => {
+            debug!("--template takes precedence over tokenizer.chat_template of the model file");
+            Some(template)
+        }
+        (Some(template), None) => Some(template),
+        (None, Some(gguf_template)) => {
+            info!("using tokenizer.chat_template of the model file");
+            Some(gguf_template)
+        }
+        (None, None) => {
+            warn!("model file has no tokenizer.chat_template, fallback to the default template");
+            None
+        }
+    };
+
+    let template = ChatTemplates::from_model(&model, template.as_deref())?;
+    template.validate()?;
 
     let ctx = Context {
         model,
         model_name,
         backend_bridge,
         kv_cache_size_pre_task,
-        chat_template: Some(Arc::new(template))
+        chat_template: Some(Arc::new(template)),
+        sse_heartbeat: Some(sse_heartbeat),
+        non_streaming_timeout,
+        chunked_responses,
+        speculative,
+        response_cache: NonZeroUsize::new(response_cache_size).map(|size| Mutex::new(LruCache::new(size))),
+        max_tokens_per_second,
+        context_limit,
+        max_embedding_batch_size: None,
+        soft_prompts,
+        compaction,
+        fim_template: Some(fim_template),
+        system_prompt,
+        inflight_requests: DashMap::new(),
+        metrics,
+        max_parallel_tasks: parallel_tasks.load(Ordering::Relaxed),
+        started_at: Utc::now().timestamp(),
+        request_controls: DashMap::new(),
+        resumed: Arc::new(Notify::new()),
+        parallel_tasks,
+    };
+
+    let limit = ConcurrencyLimit {
+        metrics: ctx.metrics.clone(),
+        max_concurrent_requests,
+        parallel_tasks: ctx.parallel_tasks.clone(),
     };
 
     let ctx = Arc::new(ctx);
 
     let app = Router::new()
         .route("/v1/completions", post(v1_completions))
+        .route("/v1/completions/ws", get(v1_completions_ws))
+        .route("/v1/completions/{request_id}/pause", post(v1_completions_pause))
+        .route("/v1/completions/{request_id}/resume", post(v1_completions_resume))
         .route("/v1/chat/completions", post(v1_chat_completions))
+        .route("/v1/perplexity", post(v1_perplexity))
+        .route("/v1/models", get(v1_models::<CompletionsTask>))
+        .route("/v1/model/info", get(v1_model_info::<CompletionsTask>))
+        .route("/v1/model/vocabulary", get(v1_model_vocabulary::<CompletionsTask>))
+        .route("/v1/model/special-tokens", get(v1_model_special_tokens::<CompletionsTask>))
+        .route("/metrics", get(prometheus_metrics::<CompletionsTask>))
+        .with_state(ctx.clone())
+        .merge(loading_progress_router(loading_state));
+
+    let app = api_layers(app, request_log, log_requests, limit);
+
+    let admin = Router::new()
+        .route("/admin/set-parallel-tasks", post(admin_set_parallel_tasks::<CompletionsTask>))
         .with_state(ctx);
 
-    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
-    info!("Listening on http://{}", bind_addr);
-    axum::serve(listener, app).await?;
+    let listeners = bind_all(&bind_addrs)?;
+
+    for addr in &bind_addrs {
+        info!("Listening on http://{}", addr);
+    }
+
+    tokio::try_join!(
+        serve(listeners, app, std::future::pending()),
+        serve_admin(admin_bind_addr, admin),
+    )?;
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system_prompt() -> SystemPrompt {
+        SystemPrompt {
+            content: String::from("You are a test. "),
+            strict: false,
+        }
+    }
+
+    #[test]
+    fn system_prompt_prepended_to_string_prompts() {
+        let mut body = serde_json::json!({"prompt": "Hello"});
+        inject_system_prompt(&mut body, &system_prompt()).unwrap();
+        assert_eq!(body["prompt"], "You are a test. Hello");
+
+        let mut body = serde_json::json!({"prompt": ["Hello", "World"]});
+        inject_system_prompt(&mut body, &system_prompt()).unwrap();
+        assert_eq!(body["prompt"], serde_json::json!(["You are a test. Hello", "You are a test. World"]));
+    }
+
+    #[test]
+    fn system_prompt_rejects_token_id_prompts() {
+        for prompt in [serde_json::json!([1, 2, 3]), serde_json::json!([[1, 2], [3]])] {
+            let mut body = serde_json::json!({"prompt": prompt});
+            let e = inject_system_prompt(&mut body, &system_prompt()).unwrap_err();
+
+            match e.downcast_ref::<ApiError>() {
+                Some(ApiError::InvalidRequest { param, .. }) => assert_eq!(param.as_deref(), Some("prompt")),
+                _ => panic!("expected an invalid request, got {}", e),
+            }
+            assert_eq!(body["prompt"], prompt);
+        }
+    }
+}
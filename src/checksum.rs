@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::cmp::max;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let total_bytes = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    let mut buff = vec![0u8; 1024 * 1024];
+    let mut read_bytes: u64 = 0;
+    let mut last_report = Instant::now();
+
+    loop {
+        let n = file.read(&mut buff)?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buff[..n]);
+        read_bytes += n as u64;
+
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            info!(
+                "computing checksum of {}: {:.1}%",
+                path.display(),
+                read_bytes as f64 * 100.0 / max(total_bytes, 1) as f64
+            );
+            last_report = Instant::now();
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn verify(path: &Path, expected: &str) -> Result<()> {
+    let actual = sha256_file(path)?;
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(anyhow!(
+            "model checksum mismatch for {}, expected: {}, actual: {}",
+            path.display(),
+            expected.trim(),
+            actual
+        ));
+    }
+
+    info!("model checksum verified: {}", actual);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, ArgMatches, Command};
+use serde_json::{Map, Value};
+
+// clap's own arguments aren't part of the server configuration
+fn is_config_arg(id: &str) -> bool {
+    id != "help" && id != "version"
+}
+
+fn arg_name(arg: &clap::Arg) -> String {
+    arg.get_long()
+        .map(String::from)
+        .unwrap_or_else(|| arg.get_id().to_string())
+}
+
+// every argument with its resolved value, defaults included, keyed by the long flag name
+pub fn dump(command: &Command, matches: &ArgMatches) -> Value {
+    let mut config = Map::new();
+
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+
+        if !is_config_arg(id) {
+            continue;
+        }
+
+        let value = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => Value::Bool(matches.get_flag(id)),
+            ArgAction::Append => {
+                let values = matches.get_raw(id)
+                    .map(|values| values.map(|v| Value::from(v.to_string_lossy())).collect())
+                    .unwrap_or_default();
+
+                Value::Array(values)
+            }
+            _ => {
+                matches.get_raw(id)
+                    .and_then(|mut values| values.next())
+                    .map(|v| Value::from(v.to_string_lossy()))
+                    .unwrap_or(Value::Null)
+            }
+        };
+
+        config.insert(arg_name(arg), value);
+    }
+    Value::Object(config)
+}
+
+// the command line that reproduces a dumped configuration
+pub fn to_cli_args(config: &Value) -> Result<Vec<String>> {
+    let config = config.as_object().ok_or_else(|| anyhow!("config must be a JSON object"))?;
+    let mut args = Vec::new();
+
+    for (name, value) in config {
+        let flag = format!("--{}", name);
+
+        match value {
+            Value::Null | Value::Bool(false) => (),
+            Value::Bool(true) => args.push(flag),
+            Value::String(s) => {
+                args.push(flag);
+                args.push(s.clone());
+            }
+            Value::Number(n) => {
+                args.push(flag);
+                args.push(n.to_string());
+            }
+            Value::Array(values) => {
+                for v in values {
+                    let v = v.as_str().ok_or_else(|| anyhow!("{} must be an array of strings", name))?;
+                    args.push(flag.clone());
+                    args.push(v.to_string());
+                }
+            }
+            Value::Object(_) => return Err(anyhow!("{} must not be an object", name)),
+        }
+    }
+    Ok(args)
+}
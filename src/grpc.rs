@@ -0,0 +1,280 @@
+use crate::api::{self, ApiError};
+use crate::metrics::Metrics;
+use crate::sampler::SamplerParams;
+use crate::{CompletionsEvent, CompletionsTask, EmbeddingTask, MaxTokens};
+use anyhow::{anyhow, Result};
+use futures_util::{Stream, StreamExt};
+use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::watch;
+use tonic::server::NamedService;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+mod proto {
+    tonic::include_proto!("hibiki");
+}
+
+use proto::hibiki_server::{Hibiki, HibikiServer};
+use proto::{CompletionRequest, CompletionResponse, Embedding, EmbeddingRequest, TokenChunk};
+
+// the grpc server shares the task channel of the http api
+pub enum Backend {
+    Completions(flume::Sender<CompletionsTask>),
+    Embedding(flume::Sender<EmbeddingTask>),
+}
+
+struct HibikiService {
+    model: Arc<LlamaModel>,
+    kv_cache_size_pre_task: u32,
+    backend: Backend,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+// the grpc codes of the errors the http api answers with 400 and 503
+fn status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<ApiError>() {
+        Some(ApiError::BadRequest(_)) | Some(ApiError::InvalidRequest { .. }) => Status::invalid_argument(e.to_string()),
+        Some(ApiError::NotFound(_)) => Status::not_found(e.to_string()),
+        Some(ApiError::Conflict(_)) => Status::already_exists(e.to_string()),
+        Some(ApiError::ServiceUnavailable { .. }) => Status::unavailable(e.to_string()),
+        None => internal(e),
+    }
+}
+
+impl HibikiService {
+    async fn send_completions(&self, req: CompletionRequest) -> Result<(flume::Receiver<CompletionsEvent>, u32), Status> {
+        let backend_bridge = match &self.backend {
+            Backend::Completions(tx) => tx,
+            Backend::Embedding(_) => return Err(Status::unimplemented("server is running in embedding mode")),
+        };
+
+        // the same checks as the fields of a /v1/completions body
+        let body = serde_json::json!({
+            "max_tokens": req.max_tokens,
+            "temperature": req.temperature,
+            "top_p": req.top_p,
+            "frequency_penalty": req.frequency_penalty,
+            "presence_penalty": req.presence_penalty,
+            "seed": req.seed,
+        });
+        api::validate_request(&body, "", self.kv_cache_size_pre_task).map_err(status)?;
+
+        let model = self.model.clone();
+        let prompt = req.prompt;
+
+        let input_tokens = tokio::task::spawn_blocking(move || model.str_to_token(&prompt, AddBos::Always))
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+
+        let prompt_tokens = input_tokens.len() as u32;
+
+        if prompt_tokens >= self.kv_cache_size_pre_task {
+            return Err(Status::invalid_argument(format!("Prompt too large, prompt tokens len: {prompt_tokens}")));
+        }
+
+        let (tx, rx) = flume::unbounded();
+
+        let task = CompletionsTask {
+            to_api: tx,
+            input_token_list: input_tokens,
+            sampler_params: SamplerParams {
+                frequency_penalty: req.frequency_penalty,
+                presence_penalty: req.presence_penalty,
+                seed: req.seed,
+                temperature: req.temperature,
+                top_p: req.top_p,
+                ..SamplerParams::default()
+            },
+            maximum_tokens: MaxTokens::from(req.max_tokens),
+            soft_prompt: None,
+            injections: None,
+            history_len: None,
+            request_id: uuid::Uuid::new_v4().to_string(),
+            control: None,
+            prompt_logprobs: false,
+            disable_speculative: false,
+            enqueued_at: Instant::now(),
+            stop_token_ids: HashSet::new(),
+        };
+
+        api::try_enqueue(backend_bridge, task, &self.metrics, &self.parallel_tasks).map_err(status)?;
+        Ok((rx, prompt_tokens))
+    }
+}
+
+#[tonic::async_trait]
+impl Hibiki for HibikiService {
+    async fn generate(&self, request: Request<CompletionRequest>) -> Result<Response<CompletionResponse>, Status> {
+        let (rx, prompt_tokens) = self.send_completions(request.into_inner()).await?;
+
+        let mut out_tokens = Vec::new();
+
+        while let Ok(event) = rx.recv_async().await {
+            match event {
+                CompletionsEvent::Token(token) => out_tokens.push(token),
+                CompletionsEvent::Failed => return Err(Status::internal("inference of the request failed")),
+                _ => (),
+            }
+        }
+
+        let completion_tokens = out_tokens.len() as u32;
+        let model = self.model.clone();
+
+        let text = tokio::task::spawn_blocking(move || model.tokens_to_str(&out_tokens, Special::Plaintext))
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+
+        let resp = CompletionResponse {
+            text,
+            prompt_tokens,
+            completion_tokens,
+        };
+        Ok(Response::new(resp))
+    }
+
+    type GenerateStreamStream = Pin<Box<dyn Stream<Item = Result<TokenChunk, Status>> + Send + 'static>>;
+
+    async fn generate_stream(&self, request: Request<CompletionRequest>) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let (rx, _) = self.send_completions(request.into_inner()).await?;
+        let model = self.model.clone();
+        let mut single_token_bytes = Vec::new();
+
+        let chunks = rx.into_stream()
+            .filter_map(move |event| {
+                let token = match event {
+                    CompletionsEvent::Token(token) => token,
+                    CompletionsEvent::Failed => return futures_util::future::ready(Some(Err(Status::internal("inference of the request failed")))),
+                    _ => return futures_util::future::ready(None),
+                };
+
+                let res = match model.token_to_bytes(token, Special::Plaintext) {
+                    Ok(token_bytes) => {
+                        single_token_bytes.extend_from_slice(&token_bytes);
+
+                        match String::from_utf8(single_token_bytes.clone()) {
+                            Ok(text) => {
+                                single_token_bytes.clear();
+                                Some(Ok(TokenChunk { text, token_id: token.0 }))
+                            }
+                            Err(_) => None
+                        }
+                    }
+                    Err(e) => Some(Err(internal(e)))
+                };
+
+                futures_util::future::ready(res)
+            });
+
+        Ok(Response::new(Box::pin(chunks)))
+    }
+
+    async fn embed(&self, request: Request<EmbeddingRequest>) -> Result<Response<Embedding>, Status> {
+        let backend_bridge = match &self.backend {
+            Backend::Embedding(tx) => tx,
+            Backend::Completions(_) => return Err(Status::unimplemented("server is running in completions mode")),
+        };
+
+        let model = self.model.clone();
+        let input = request.into_inner().input;
+        let kv_cache_size_pre_task = self.kv_cache_size_pre_task;
+
+        let (input_tokens, truncated) = tokio::task::spawn_blocking(move || api::embedding_input_tokens(&model, &input, kv_cache_size_pre_task))
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+
+        let (tx, rx) = flume::unbounded();
+
+        let task = EmbeddingTask {
+            to_api: tx,
+            index: 0,
+            input_token_list: input_tokens,
+        };
+
+        api::try_enqueue(backend_bridge, task, &self.metrics, &self.parallel_tasks).map_err(status)?;
+        let (_, values) = rx.recv_async().await.map_err(internal)?;
+
+        let mut resp = Response::new(Embedding { values });
+
+        // the X-Truncated-Inputs header of /v1/embeddings
+        if truncated {
+            resp.metadata_mut().insert("x-truncated-inputs", tonic::metadata::MetadataValue::from_static("[0]"));
+        }
+        Ok(resp)
+    }
+}
+
+const SERVICE_NAME: &str = <HibikiServer<HibikiService> as NamedService>::NAME;
+
+// grpc.health.v1.Health follows the readiness of the api, NOT_SERVING during warm-up and after the api stopped
+async fn report_health(mut reporter: HealthReporter, mut serving: watch::Receiver<bool>) {
+    loop {
+        let status = if *serving.borrow_and_update() {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+
+        // a kubernetes grpc probe asks for the overall status, the empty service name
+        reporter.set_service_status("", status).await;
+        reporter.set_service_status(SERVICE_NAME, status).await;
+
+        if serving.changed().await.is_err() {
+            break;
+        }
+    }
+
+    reporter.set_service_status("", ServingStatus::NotServing).await;
+    reporter.set_service_status(SERVICE_NAME, ServingStatus::NotServing).await;
+}
+
+// binds before returning so a taken address fails the startup, the returned future serves the bound listener
+pub fn bind(
+    bind_addr: SocketAddr,
+    model: Arc<LlamaModel>,
+    kv_cache_size_pre_task: u32,
+    backend: Backend,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    serving: watch::Receiver<bool>,
+) -> Result<impl Future<Output = Result<()>>> {
+    let service = HibikiService {
+        model,
+        kv_cache_size_pre_task,
+        backend,
+        metrics,
+        parallel_tasks,
+    };
+
+    let incoming = TcpIncoming::new(bind_addr, true, None).map_err(|e| anyhow!("bind gRPC address {} failed: {}", bind_addr, e))?;
+    info!("gRPC listening on {}", bind_addr);
+
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(reporter, serving));
+
+    let serve = async move {
+        tonic::transport::Server::builder()
+            .add_service(health_service)
+            .add_service(HibikiServer::new(service))
+            .serve_with_incoming(incoming)
+            .await?;
+        Ok(())
+    };
+    Ok(serve)
+}
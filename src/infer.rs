@@ -1,5 +1,5 @@
-use crate::sampler::Sampler;
-use crate::{CompletionsTask, EmbeddingTask, KVCacheTypes};
+use crate::sampler::{self, Sampler, SamplerParams};
+use crate::{CompletionsEvent, CompletionsTask, EmbeddingTask, KVCacheTypes};
 use anyhow::{anyhow, ensure, Result};
 use flume::{RecvTimeoutError, TryRecvError};
 use llama_cpp_2::context::params::LlamaContextParams;
@@ -8,21 +8,149 @@ use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::{LlamaModel};
 use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::DecodeError;
 use llama_cpp_sys_2::{ggml_type, hibiki_common_speculative_are_compatible, LLAMA_POOLING_TYPE_NONE};
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::collections::{BTreeMap};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::num::NonZeroU32;
 use std::ptr::slice_from_raw_parts;
 use std::rc::Rc;
 use std::slice;
-use std::sync::atomic::AtomicBool;
+use std::backtrace::Backtrace;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use crate::affinity;
 use crate::metadata::ModelMetadata;
+use crate::metrics::Metrics;
+use crate::soft_prompt::SoftPrompt;
 use crate::radixtrie_kv_cache::RadixTrieKVCache;
 
+const RETRY_BASE_BACKOFF: Duration = Duration::from_millis(50);
+// the inference thread stalls every sequence while it waits, so the backoff stays short
+const RETRY_MAX_BACKOFF: Duration = Duration::from_millis(400);
+// total wait of a single decode, later retries are given up whatever --max-retries is
+const RETRY_BACKOFF_BUDGET: Duration = Duration::from_secs(1);
+// llama_decode return value of a graph compute stopped by the abort callback
+const DECODE_ABORTED: i32 = 2;
+
+// a transient decode error that kept failing after all retries
+#[derive(Debug)]
+pub struct RetryableError {
+    source: DecodeError,
+    retries: u32,
+}
+
+impl std::fmt::Display for RetryableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (after {} retries)", self.source, self.retries)
+    }
+}
+
+impl std::error::Error for RetryableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+fn is_transient(e: &DecodeError) -> bool {
+    match e {
+        // the kv cache is restored before returning, the same batch can run again.
+        // allocation and compute failures (< -1) come back on every attempt
+        DecodeError::Unknown(code) => *code == DECODE_ABORTED,
+        // the same batch can't fit again until sequences free their cells
+        DecodeError::NoKvCacheSlot => false,
+        DecodeError::NTokensZero => false,
+    }
+}
+
+#[derive(Clone)]
+pub struct DecodeRetry {
+    max_retries: u32,
+    metrics: Arc<Metrics>,
+}
+
+impl DecodeRetry {
+    fn decode(&self, ctx: &mut LlamaContext, batch: &mut LlamaBatch) -> Result<()> {
+        let mut retries = 0;
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let e = match ctx.decode(batch) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            if !is_transient(&e) {
+                return Err(e.into());
+            }
+
+            let backoff = RETRY_BASE_BACKOFF.saturating_mul(2u32.saturating_pow(retries)).min(RETRY_MAX_BACKOFF);
+
+            if retries >= self.max_retries || waited + backoff > RETRY_BACKOFF_BUDGET {
+                let e = RetryableError { source: e, retries };
+                error!("decode failed: {}\n{}", e, Backtrace::force_capture());
+                return Err(e.into());
+            }
+
+            retries += 1;
+            waited += backoff;
+            self.metrics.decode_retries.fetch_add(1, Ordering::Relaxed);
+
+            warn!("transient decode error: {}, retry {}/{} after {:?}", e, retries, self.max_retries, backoff);
+            std::thread::sleep(backoff);
+        }
+    }
+}
+
+// pause state of a streaming completion, shared by the api and the inference loop
+pub struct RequestControl {
+    paused: AtomicBool,
+    // counts the pauses, so the timeout of an earlier pause leaves a later one alone
+    pauses: AtomicU64,
+    // shared by all requests of a handler, so a loop with every sequence paused has a single signal to wait on
+    resumed: Arc<Notify>,
+}
+
+impl RequestControl {
+    pub fn new(resumed: Arc<Notify>) -> Self {
+        RequestControl {
+            paused: AtomicBool::new(false),
+            pauses: AtomicU64::new(0),
+            resumed,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn pause(&self) -> u64 {
+        self.paused.store(true, Ordering::Relaxed);
+        self.pauses.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // resumes only if the given pause is still the current one
+    pub fn resume_pause(&self, pause: u64) -> bool {
+        if self.pauses.load(Ordering::Relaxed) != pause || !self.paused.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        self.resume();
+        true
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        // notify_one keeps a permit if the loop isn't waiting yet
+        self.resumed.notify_one();
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 enum SeqState {
     Prefill,
@@ -32,11 +160,115 @@ enum SeqState {
 struct Sequence {
     input_tokens: Vec<LlamaToken>,
     sampler: Sampler,
-    callback: flume::Sender<LlamaToken>,
+    callback: flume::Sender<CompletionsEvent>,
     token_pos: u32,
     maximum_tokens: u32,
     logits_pos: Option<i32>,
-    state: SeqState
+    state: SeqState,
+    soft_prompt: Option<Arc<SoftPrompt>>,
+    injections: Option<flume::Receiver<Vec<LlamaToken>>>,
+    injected_tokens: u32,
+    confidence_threshold: Option<f32>,
+    history_len: Option<usize>,
+    request_id: String,
+    control: Option<Arc<RequestControl>>,
+    // sampled while paused, fed back into the batch on resume
+    held_token: Option<LlamaToken>,
+    prompt_logprobs: bool,
+    stop_token_ids: HashSet<u32>,
+    // per sequence, the perf counters of llama.cpp add up every sequence of the context
+    started_at: Instant,
+    first_token_at: Option<Instant>,
+}
+
+impl Sequence {
+    fn new(model: &LlamaModel, task: CompletionsTask, kv_cache_size_pre_task: u32, metrics: &Metrics) -> Self {
+        let prompt_len = task.input_token_list.len() as u32 + task.soft_prompt.as_ref().map(|p| p.n_tokens as u32).unwrap_or(0);
+        let sampler = Sampler::new(model, &task.sampler_params);
+        debug!("[{}] seed: {:?}, effective seed: {}", task.request_id, task.sampler_params.seed, sampler.seed);
+        metrics.record_queue_wait(task.enqueued_at.elapsed());
+        let _ = task.to_api.send(CompletionsEvent::Started(Instant::now()));
+
+        Sequence {
+            sampler,
+            callback: task.to_api,
+            token_pos: prompt_len,
+            maximum_tokens: task.maximum_tokens.end_pos(prompt_len, kv_cache_size_pre_task),
+            input_tokens: task.input_token_list,
+            logits_pos: None,
+            state: SeqState::Prefill,
+            soft_prompt: task.soft_prompt,
+            injections: task.injections,
+            injected_tokens: 0,
+            confidence_threshold: task.sampler_params.confidence_threshold,
+            history_len: task.history_len,
+            request_id: task.request_id,
+            control: task.control,
+            held_token: None,
+            prompt_logprobs: task.prompt_logprobs,
+            stop_token_ids: task.stop_token_ids,
+            started_at: Instant::now(),
+            first_token_at: None,
+        }
+    }
+
+    fn log_timing(&self) {
+        if log::max_level() < log::Level::Debug {
+            return;
+        }
+
+        let now = Instant::now();
+        let prefill = self.first_token_at.unwrap_or(now) - self.started_at;
+        let decode = self.first_token_at.map(|t| now - t).unwrap_or_default();
+        let prompt_tokens = self.prompt_len();
+
+        debug!(
+            "[{}] timing: prefill {:.2} ms / {} tokens, decode {:.2} ms / {} tokens",
+            self.request_id,
+            prefill.as_secs_f64() * 1000.0,
+            prompt_tokens,
+            decode.as_secs_f64() * 1000.0,
+            self.token_pos - prompt_tokens - self.injected_tokens
+        );
+    }
+
+    fn is_paused(&self) -> bool {
+        self.control.as_ref().is_some_and(|c| c.is_paused())
+    }
+
+    // virtual tokens of the soft prompt come first
+    fn prompt_len(&self) -> u32 {
+        self.input_tokens.len() as u32 + self.soft_prompt.as_ref().map(|p| p.n_tokens as u32).unwrap_or(0)
+    }
+}
+
+// one line per verification step, the probabilities are the target's for the verified draft tokens
+fn log_speculative_step(
+    request_id: &str,
+    draft_tokens: &[LlamaToken],
+    accepted_tokens: &[LlamaToken],
+    next_token: Option<LlamaToken>,
+    probabilities: &[f32],
+) {
+    let step = serde_json::json!({
+        "proposed": draft_tokens.len(),
+        "accepted": accepted_tokens.len(),
+        "draft_tokens": draft_tokens.iter().map(|t| t.0).collect::<Vec<_>>(),
+        "accepted_tokens": accepted_tokens.iter().map(|t| t.0).collect::<Vec<_>>(),
+        "next_token": next_token.map(|t| t.0),
+        "probabilities": probabilities,
+    });
+
+    debug!("[{}] speculative step: {}", request_id, step);
+}
+
+// log softmax of the logits at the batch position, for a single token
+fn token_logprob(ctx: &LlamaContext, logits_pos: i32, token: LlamaToken) -> f32 {
+    let logits = ctx.get_logits_ith(logits_pos);
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+
+    logits[token.0 as usize] - max - sum.ln()
 }
 
 struct SequenceSlots<'a> {
@@ -66,22 +298,61 @@ impl <'a> SequenceSlots<'a> {
             .count()
     }
 
-    fn put(&mut self, mut seq: Sequence, ctx: &mut LlamaContext, cache: &mut RadixTrieKVCache) -> Result<()> {
+    fn put(&mut self, mut seq: Sequence, ctx: &mut LlamaContext, mut cache: Option<&mut RadixTrieKVCache>, metrics: &Metrics) -> Result<()> {
         for (i, slot) in self.sequence_list.iter_mut().enumerate() {
             if slot.is_some() {
                 continue;
             }
 
+            // the virtual tokens of the soft prompt take the positions before the prompt
+            let offset = match &seq.soft_prompt {
+                None => 0,
+                Some(soft_prompt) => {
+                    if let Err(e) = soft_prompt.decode(ctx, i as i32) {
+                        error!("[{}] {}", seq.request_id, e);
+                        let _ = seq.callback.send(CompletionsEvent::Failed);
+                        ctx.clear_kv_cache_seq(Some(i as u32), None, None)?;
+                        return Ok(());
+                    }
+                    soft_prompt.n_tokens
+                }
+            };
+
+            // every position needs its logits, a cached prefix would skip them
+            if seq.prompt_logprobs {
+                for (pos, token) in seq.input_tokens.iter().enumerate() {
+                    self.batch.add(*token, (offset + pos) as i32, &[i as i32], true)?;
+                }
+
+                seq.logits_pos = Some(self.batch.n_tokens() - 1);
+                *slot = Some(seq);
+                return Ok(());
+            }
+
+            // the prefix cache is keyed by token ids only, sequences with a soft prompt bypass it
+            if seq.soft_prompt.is_some() {
+                for (pos, token) in seq.input_tokens.iter().enumerate() {
+                    self.batch.add(*token, (offset + pos) as i32, &[i as i32], pos == seq.input_tokens.len() - 1)?;
+                }
+
+                seq.logits_pos = Some(self.batch.n_tokens() - 1);
+                *slot = Some(seq);
+                return Ok(());
+            }
+
             let raw_tokens = seq.input_tokens.iter().map(|t| t.0).collect::<Vec<_>>();
+            metrics.kv_cache_prefill_tokens.fetch_add(seq.input_tokens.len() as u64, Ordering::Relaxed);
 
-            match cache.get(&raw_tokens) {
+            match cache.as_deref_mut().and_then(|cache| cache.get(&raw_tokens, seq.history_len)) {
                 None => {
                     self.batch.add_sequence(&seq.input_tokens, i as i32, false)?;
                 }
                 Some((sub_seq_data, sub_seq_tokens_len)) => {
-                    debug!("cache hit");
+                    debug!("[{}] cache hit", seq.request_id);
 
                     let sub_seq_len = min(seq.input_tokens.len() - 1, sub_seq_tokens_len);
+                    metrics.kv_cache_hit_tokens.fetch_add(sub_seq_len as u64, Ordering::Relaxed);
+                    let _ = seq.callback.send(CompletionsEvent::PromptCached(sub_seq_len as u32));
 
                     unsafe {
                         let res = llama_cpp_sys_2::llama_state_seq_set_data(ctx.context.as_ptr(), sub_seq_data.as_ptr(), sub_seq_data.len(), i as i32);
@@ -102,14 +373,18 @@ impl <'a> SequenceSlots<'a> {
         Err(anyhow!("No available slot"))
     }
 
-    fn batch_decode(&mut self, ctx: &mut LlamaContext, cache: &mut RadixTrieKVCache) -> Result<usize> {
+    fn batch_decode(&mut self, ctx: &mut LlamaContext, mut cache: Option<&mut RadixTrieKVCache>, decode_retry: &DecodeRetry) -> Result<usize> {
         let slot_size = self.len();
 
         if slot_size == 0 {
             return Err(anyhow!("No sequence to decode"));
         }
 
-        ctx.decode(self.batch)?;
+        if let Err(e) = decode_retry.decode(ctx, self.batch) {
+            error!("batch decode failed: {:?}", e);
+            self.fail_batch(ctx)?;
+            return Ok(0);
+        }
         self.batch.clear();
 
         for (i, x) in &mut self.sequence_list.iter_mut().enumerate() {
@@ -117,6 +392,12 @@ impl <'a> SequenceSlots<'a> {
                 if seq.state == SeqState::Prefill {
                     seq.state = SeqState::Decode;
 
+                    // a perplexity window ends with its prefill, caching it would only evict reusable prefixes
+                    let cache = match &mut cache {
+                        Some(cache) if seq.soft_prompt.is_none() && !seq.prompt_logprobs => cache,
+                        _ => continue
+                    };
+
                     unsafe {
                         let data_size = llama_cpp_sys_2::llama_state_seq_get_size(ctx.context.as_ptr(), i as i32);
                         let mut data = vec![0u8; data_size];
@@ -131,7 +412,21 @@ impl <'a> SequenceSlots<'a> {
         Ok(slot_size)
     }
 
-    fn batch_sample(&mut self, ctx: &mut LlamaContext) -> Result<usize> {
+    // ends every sequence with tokens in the failed batch, the other sequences keep running
+    fn fail_batch(&mut self, ctx: &mut LlamaContext) -> Result<()> {
+        self.batch.clear();
+
+        for (i, slot) in self.sequence_list.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|seq| seq.logits_pos.is_some()) {
+                let seq = slot.take().unwrap();
+                let _ = seq.callback.send(CompletionsEvent::Failed);
+                ctx.clear_kv_cache_seq(Some(i as u32), None, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn batch_sample(&mut self, ctx: &mut LlamaContext, metrics: &Metrics) -> Result<usize> {
         let slot_size = self.len();
 
         if slot_size == 0 {
@@ -142,58 +437,157 @@ impl <'a> SequenceSlots<'a> {
 
         for (i, slot) in sequence_list.iter_mut().enumerate() {
             if let Some(seq) = slot {
-                let out_token = seq.sampler.sample(ctx, seq.logits_pos.take().unwrap());
-
                 macro_rules! remove_slot {
                     () => {
+                        seq.log_timing();
                         *slot = None;
                         ctx.clear_kv_cache_seq(Some(i as u32), None, None)?;
                     };
                 }
 
-                if self.model.is_eog_token(out_token) {
+                // injected tokens are neither prompt nor generated tokens
+                let prompt_tokens = seq.prompt_len();
+                let generated_tokens = seq.token_pos - prompt_tokens - seq.injected_tokens;
+
+                let logits_pos = match seq.logits_pos.take() {
+                    Some(pos) => pos,
+                    None => {
+                        if seq.is_paused() {
+                            continue;
+                        }
+
+                        if seq.callback.is_disconnected() {
+                            remove_slot!();
+                            continue;
+                        }
+
+                        let held_token = seq.held_token.take().unwrap();
+                        self.batch.add(held_token, seq.token_pos as i32, &[i as i32], true)?;
+                        seq.sampler.accept(held_token);
+                        seq.token_pos += 1;
+                        seq.logits_pos = Some(self.batch.n_tokens() - 1);
+                        continue;
+                    }
+                };
+
+                if seq.prompt_logprobs {
+                    let first_pos = logits_pos + 1 - seq.input_tokens.len() as i32;
+
+                    for (pos, token) in seq.input_tokens.iter().enumerate().skip(1) {
+                        let logprob = token_logprob(ctx, first_pos + pos as i32 - 1, *token);
+                        let _ = seq.callback.send(CompletionsEvent::PromptLogprob(logprob));
+                    }
+
+                    metrics.record_completion(prompt_tokens as u64, 0);
+                    remove_slot!();
+                    continue;
+                }
+
+                let out_token = seq.sampler.sample(ctx, logits_pos);
+                seq.first_token_at.get_or_insert_with(Instant::now);
+                metrics.record_sample(&seq.request_id, &seq.sampler, out_token);
+
+                if self.model.is_eog_token(out_token) || seq.stop_token_ids.contains(&(out_token.0 as u32)) {
+                    metrics.record_completion(prompt_tokens as u64, generated_tokens as u64);
                     remove_slot!();
                     continue;
                 }
 
-                if seq.callback.send(out_token).is_err() {
+                if seq.callback.send(CompletionsEvent::Token(out_token)).is_err() {
+                    remove_slot!();
+                    continue;
+                }
+
+                let confident = seq.confidence_threshold
+                    .zip(seq.sampler.selected_probability())
+                    .is_some_and(|(threshold, p)| p > threshold);
+
+                if confident {
+                    let _ = seq.callback.send(CompletionsEvent::Confident(out_token));
+                    metrics.record_completion(prompt_tokens as u64, generated_tokens as u64 + 1);
                     remove_slot!();
                     continue;
                 }
 
                 if seq.token_pos + 1 >= seq.maximum_tokens {
+                    let _ = seq.callback.send(CompletionsEvent::Length);
+                    metrics.record_completion(prompt_tokens as u64, generated_tokens as u64 + 1);
                     remove_slot!();
                     continue;
                 }
 
-                self.batch.add(out_token, seq.token_pos as i32, &[i as i32], true)?;
-                seq.logits_pos = Some(self.batch.n_tokens() - 1);
-                seq.sampler.accept(out_token);
+                // stop decoding the sequence, its kv cache stays in place
+                if seq.is_paused() {
+                    seq.held_token = Some(out_token);
+                    continue;
+                }
+
+                let mut injected = seq.injections.as_ref()
+                    .and_then(|rx| rx.try_recv().ok())
+                    .unwrap_or_default();
+
+                // keep room for at least one more generated token
+                if !injected.is_empty() && seq.token_pos + 1 + injected.len() as u32 >= seq.maximum_tokens {
+                    warn!("[{}] injected tokens exceed the maximum tokens of the sequence, ignored", seq.request_id);
+                    injected.clear();
+                }
 
+                self.batch.add(out_token, seq.token_pos as i32, &[i as i32], injected.is_empty())?;
+                seq.sampler.accept(out_token);
                 seq.token_pos += 1;
+
+                for (n, token) in injected.iter().enumerate() {
+                    self.batch.add(*token, seq.token_pos as i32, &[i as i32], n == injected.len() - 1)?;
+                    seq.sampler.accept(*token);
+                    seq.token_pos += 1;
+                }
+
+                if !injected.is_empty() {
+                    seq.injected_tokens += injected.len() as u32;
+                    let _ = seq.callback.send(CompletionsEvent::Injected(injected.len() as u32));
+                }
+
+                seq.logits_pos = Some(self.batch.n_tokens() - 1);
             }
         }
 
         Ok(slot_size)
     }
-}
 
-const RAIDX_TRIE_KV_CACHE_MAX_SEQ: usize = 64;
+    // the signal to wait on when every sequence is paused and nothing is left to decode
+    fn all_paused(&self) -> Option<Arc<Notify>> {
+        if self.batch.n_tokens() > 0 {
+            return None;
+        }
+
+        self.sequence_list.iter()
+            .flatten()
+            .find_map(|seq| seq.control.as_ref())
+            .map(|c| c.resumed.clone())
+    }
+}
 
 fn completions_handler(
     model: &LlamaModel,
     backend: &LlamaBackend,
+    ctx_params: LlamaContextParams,
     task_rx: &flume::Receiver<CompletionsTask>,
     n_tasks: u32,
     kv_cache_size_pre_task: u32,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
+    has_kv_cache: bool,
+    prefix_cache_slots: usize,
+    cache_version: u64,
+    decode_retry: &DecodeRetry,
+    metrics: &Metrics,
+    active_tasks: &AtomicU32,
     is_cancel: &AtomicBool,
 ) -> Result<()> {
     let model_metadata = ModelMetadata::from(model);
 
-    let mut ctx_params = LlamaContextParams::default()
+    let mut ctx_params = ctx_params
         // .with_flash_attention(true)
         .with_offload_kqv(offload_kqv)
         .with_n_ctx(NonZeroU32::new(n_tasks * kv_cache_size_pre_task))
@@ -215,7 +609,12 @@ fn completions_handler(
     let mut batch = LlamaBatch::new(kv_cache_size_pre_task as usize * n_tasks as usize, 1);
 
     let mut sequence_slots = SequenceSlots::new(n_tasks, &mut batch, model);
-    let mut trie_cache = RadixTrieKVCache::new(RAIDX_TRIE_KV_CACHE_MAX_SEQ);
+    // recurrent models can't restore a partial sequence state, so prefix caching is skipped
+    let mut trie_cache = if has_kv_cache && prefix_cache_slots > 0 {
+        Some(RadixTrieKVCache::new(prefix_cache_slots, cache_version))
+    } else {
+        None
+    };
 
     loop {
         if sequence_slots.len() == 0 {
@@ -232,53 +631,16 @@ fn completions_handler(
                 }
             };
 
-            let sequence = Sequence {
-                sampler: Sampler::new(
-                    model,
-                    task.frequency_penalty,
-                    task.presence_penalty,
-                    task.seed,
-                    task.temperature,
-                    task.top_p
-                ),
-                callback: task.to_api,
-                token_pos: task.input_token_list.len() as u32,
-                maximum_tokens: min(
-                    task.maximum_tokens.map(|n_tokens| n_tokens + task.input_token_list.len() as u32).unwrap_or(kv_cache_size_pre_task),
-                    kv_cache_size_pre_task
-                ),
-                input_tokens: task.input_token_list,
-                logits_pos: None,
-                state: SeqState::Prefill
-            };
-
-            sequence_slots.put(sequence, &mut ctx, &mut trie_cache)?;
+            let sequence = Sequence::new(model, task, kv_cache_size_pre_task, metrics);
+            sequence_slots.put(sequence, &mut ctx, trie_cache.as_mut(), metrics)?;
         }
 
-        while sequence_slots.len() < n_tasks as usize {
+        // the context is sized for n_tasks, the active limit can only lower it
+        while sequence_slots.len() < min(n_tasks, active_tasks.load(Ordering::Relaxed)) as usize {
             match task_rx.try_recv() {
                 Ok(task) => {
-                    let sequence = Sequence {
-                        sampler: Sampler::new(
-                            model,
-                            task.frequency_penalty,
-                            task.presence_penalty,
-                            task.seed,
-                            task.temperature,
-                            task.top_p
-                        ),
-                        callback: task.to_api,
-                        token_pos: task.input_token_list.len() as u32,
-                        maximum_tokens: min(
-                            task.maximum_tokens.map(|n_tokens| n_tokens + task.input_token_list.len() as u32).unwrap_or(kv_cache_size_pre_task),
-                            kv_cache_size_pre_task
-                        ),
-                        input_tokens: task.input_token_list,
-                        logits_pos: None,
-                        state: SeqState::Prefill
-                    };
-
-                    sequence_slots.put(sequence, &mut ctx, &mut trie_cache)?;
+                    let sequence = Sequence::new(model, task, kv_cache_size_pre_task, metrics);
+                    sequence_slots.put(sequence, &mut ctx, trie_cache.as_mut(), metrics)?;
                 }
                 Err(flume::TryRecvError::Empty) => break,
                 Err(flume::TryRecvError::Disconnected) => {
@@ -287,39 +649,163 @@ fn completions_handler(
             }
         }
 
-        sequence_slots.batch_decode(&mut ctx, &mut trie_cache)?;
-        sequence_slots.batch_sample(&mut ctx)?;
+        if let Some(resumed) = sequence_slots.all_paused() {
+            let accept_task = sequence_slots.len() < min(n_tasks, active_tasks.load(Ordering::Relaxed)) as usize;
+
+            let task = Handle::current().block_on(async {
+                tokio::select! {
+                    _ = resumed.notified() => None,
+                    res = task_rx.recv_async(), if accept_task => Some(res),
+                }
+            });
+
+            match task {
+                // the prompt of the new sequence is decoded before anything is sampled
+                Some(task) => {
+                    let task = task.map_err(|_| anyhow!("Task channel disconnected"))?;
+                    let sequence = Sequence::new(model, task, kv_cache_size_pre_task, metrics);
+                    sequence_slots.put(sequence, &mut ctx, trie_cache.as_mut(), metrics)?;
+                }
+                // feeds the held tokens of resumed sequences back into the batch
+                None => {
+                    sequence_slots.batch_sample(&mut ctx, metrics)?;
+                }
+            }
+            continue;
+        }
+
+        // every new sequence failed in put
+        if sequence_slots.len() == 0 {
+            continue;
+        }
+
+        sequence_slots.batch_decode(&mut ctx, trie_cache.as_mut(), decode_retry)?;
+
+        // every sequence of the batch failed to decode
+        if sequence_slots.len() == 0 {
+            continue;
+        }
+        sequence_slots.batch_sample(&mut ctx, metrics)?;
     }
 }
 
+// the draft proposes `width` alternatives for the next token, each extended to a branch of `depth` tokens
+#[derive(Clone, Copy)]
+pub struct DraftTree {
+    pub width: usize,
+    pub depth: usize,
+}
+
+impl DraftTree {
+    // branch sequences are allocated after the n_tasks sequence slots
+    fn branch_seq_id(&self, n_tasks: u32, seq_id: usize, branch: usize) -> i32 {
+        (n_tasks as usize + seq_id * self.width + branch) as i32
+    }
+}
+
+// LLAMA_MAX_SEQ of llama-cparams.h, llama.h doesn't export it and context creation fails above it
+pub const LLAMA_MAX_SEQ: u32 = 64;
+
+pub fn n_seq_max(n_tasks: u32, draft_tree: Option<DraftTree>) -> u32 {
+    match draft_tree {
+        None => n_tasks,
+        Some(tree) => n_tasks * (1 + tree.width as u32),
+    }
+}
+
+// the sampled token, or the first draft token that is among the top n_candidates, with its index in drafts
+fn verify_draft_tokens(
+    sampler: &mut Sampler,
+    ctx: &mut LlamaContext,
+    logits_pos: i32,
+    drafts: &[LlamaToken],
+    n_candidates: usize,
+) -> Result<(LlamaToken, Option<usize>)> {
+    debug!("target sample");
+    let token = sampler.sample(ctx, logits_pos);
+
+    if let Some(idx) = drafts.iter().position(|draft| *draft == token) {
+        return Ok((token, Some(idx)));
+    }
+
+    let candidates = sampler.get_candidates();
+    ensure!(candidates.sorted);
+
+    let token_data_list = unsafe { &*slice_from_raw_parts(candidates.data, min(n_candidates, candidates.size)) };
+
+    for (idx, draft) in drafts.iter().enumerate() {
+        if token_data_list.iter().any(|td| td.id == draft.0) {
+            info!("candidate found");
+            return Ok((*draft, Some(idx)));
+        }
+    }
+    Ok((token, None))
+}
+
+// the sampled token and the next most likely candidates start the branches of the draft tree
+fn draft_tree_heads(sampler: &mut Sampler, ctx: &mut LlamaContext, logits_pos: i32, width: usize) -> Result<Vec<LlamaToken>> {
+    debug!("draft sample");
+    let token = sampler.sample(ctx, logits_pos);
+
+    let candidates = sampler.get_candidates();
+    ensure!(candidates.sorted);
+
+    let token_data_list = unsafe { &*slice_from_raw_parts(candidates.data, candidates.size) };
+
+    let mut heads = vec![token];
+    heads.extend(
+        token_data_list.iter()
+            .map(|td| LlamaToken(td.id))
+            .filter(|t| *t != token)
+            .take(width - 1)
+    );
+    Ok(heads)
+}
+
 enum SpeculativeCompletionsTargetInput {
     PromptInput {
-        token_list: Vec<LlamaToken>
+        token_list: Vec<LlamaToken>,
+        history_len: Option<usize>,
     },
     DraftInput {
         draft_token_list: Vec<LlamaToken>
     },
+    // branches share the last accepted token as their root
+    DraftTreeInput {
+        branches: Vec<Vec<LlamaToken>>
+    },
 }
 
 struct SpeculativeCompletionsTargetOutput {
     accept_token_n: u32,
-    next_token: Option<LlamaToken>
+    next_token: Option<LlamaToken>,
+    // index of the accepted branch of a draft tree, 0 for a flat draft
+    branch: usize,
+}
+
+struct DraftTreeVerify {
+    root_logits: i32,
+    // logits of every branch token but the last
+    branch_logits: Vec<Vec<i32>>,
+    branches: Vec<Vec<LlamaToken>>,
 }
 
 struct SpeculativeCompletionsTargetTask {
-    frequency_penalty: Option<f32>,
-    presence_penalty: Option<f32>,
-    seed: i64,
-    temperature: Option<f32>,
-    top_p: Option<f32>,
+    request_id: String,
+    sampler_params: SamplerParams,
+    api_channel: flume::Sender<CompletionsEvent>,
     input_channel: flume::Receiver<SpeculativeCompletionsTargetInput>,
     output_channel: flume::Sender<SpeculativeCompletionsTargetOutput>
 }
 
 struct SpeculativeCompletionsTargetSequence {
+    request_id: String,
     prompt_token_list: Vec<LlamaToken>,
+    history_len: Option<usize>,
     accepted_token_list: Vec<LlamaToken>,
     sampler: Sampler,
+    // only used to report the prompt cache hit, dropped after the prompt so it doesn't keep the api stream open
+    api_channel: Option<flume::Sender<CompletionsEvent>>,
     input_channel: flume::Receiver<SpeculativeCompletionsTargetInput>,
     output_channel: flume::Sender<SpeculativeCompletionsTargetOutput>,
 }
@@ -328,11 +814,13 @@ struct SpeculativeCompletionsTargetSequenceSlots<'a> {
     sequence_list: Vec<Option<SpeculativeCompletionsTargetSequence>>,
     batch: &'a mut LlamaBatch,
     model: &'a LlamaModel,
-    n_candidates: usize
+    n_candidates: usize,
+    n_tasks: u32,
+    draft_tree: Option<DraftTree>,
 }
 
 impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
-    fn new(n_task: u32, batch: &'a mut LlamaBatch, model: &'a LlamaModel, n_candidates: usize) -> Self {
+    fn new(n_task: u32, batch: &'a mut LlamaBatch, model: &'a LlamaModel, n_candidates: usize, draft_tree: Option<DraftTree>) -> Self {
         let mut sequence_list = Vec::with_capacity(n_task as usize);
 
         for _ in 0..n_task {
@@ -343,7 +831,9 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
             sequence_list,
             batch,
             model,
-            n_candidates
+            n_candidates,
+            n_tasks: n_task,
+            draft_tree,
         }
     }
 
@@ -386,11 +876,20 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
     }
 
     // (seq_id, task_input)
-    fn poll(&mut self, ctx: &mut LlamaContext, mut select_task: Option<(u32, SpeculativeCompletionsTargetInput)>, cache: &mut RadixTrieKVCache) -> Result<u32> {
+    fn poll(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut select_task: Option<(u32, SpeculativeCompletionsTargetInput)>,
+        mut cache: Option<&mut RadixTrieKVCache>,
+        decode_retry: &DecodeRetry,
+        metrics: &Metrics,
+    ) -> Result<u32> {
         // (logits_idx, pos, seq_id)
         let mut sample_list: Vec<(i32, u32, u32)> = Vec::new();
         // seq_id -> draft_token_list
         let mut draft_mapping: BTreeMap<u32, Vec<LlamaToken>> = BTreeMap::new();
+        // seq_id -> draft tree
+        let mut tree_mapping: BTreeMap<u32, DraftTreeVerify> = BTreeMap::new();
         let mut prefill_seq_ids = Vec::new();
         let mut decode_n: u32 = 0;
 
@@ -407,21 +906,29 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
                 match input_task {
                     Some(task) => {
                         match task {
-                            SpeculativeCompletionsTargetInput::PromptInput { token_list } => {
+                            SpeculativeCompletionsTargetInput::PromptInput { token_list, history_len } => {
                                 seq.prompt_token_list = token_list;
+                                seq.history_len = history_len;
                                 let token_list = &seq.prompt_token_list;
+                                let api_channel = seq.api_channel.take();
                                 prefill_seq_ids.push(id);
                                 let raw_tokens = token_list.iter().map(|t| t.0).collect::<Vec<_>>();
+                                metrics.kv_cache_prefill_tokens.fetch_add(token_list.len() as u64, Ordering::Relaxed);
 
-                                match cache.get(&raw_tokens) {
+                                match cache.as_deref_mut().and_then(|cache| cache.get(&raw_tokens, seq.history_len)) {
                                     None => {
                                         for i in 0..token_list.len() - 1 {
                                             self.batch.add(token_list[i], i as i32, &[id as i32], false)?
                                         }
                                     }
                                     Some((sub_seq_data, sub_seq_tokens_len)) => {
-                                        debug!("cache hit");
+                                        debug!("[{}] cache hit", seq.request_id);
                                         let sub_seq_len = min(token_list.len() - 1, sub_seq_tokens_len);
+                                        metrics.kv_cache_hit_tokens.fetch_add(sub_seq_len as u64, Ordering::Relaxed);
+
+                                        if let Some(api_channel) = api_channel {
+                                            let _ = api_channel.send(CompletionsEvent::PromptCached(sub_seq_len as u32));
+                                        }
 
                                         unsafe {
                                             let res = llama_cpp_sys_2::llama_state_seq_set_data(ctx.context.as_ptr(), sub_seq_data.as_ptr(), sub_seq_data.len(), id as i32);
@@ -468,6 +975,47 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
 
                                 draft_mapping.insert(id as u32, draft_token_list);
                             }
+                            SpeculativeCompletionsTargetInput::DraftTreeInput { branches } => {
+                                let tree = self.draft_tree.ok_or_else(|| anyhow!("draft tree is not enabled"))?;
+
+                                let root_pos = if seq.accepted_token_list.is_empty() {
+                                    seq.accepted_token_list.extend_from_slice(&seq.prompt_token_list);
+                                    seq.prompt_token_list.len() - 1
+                                } else {
+                                    let root_pos = seq.accepted_token_list.len() - 1;
+                                    ctx.clear_kv_cache_seq(Some(id as u32), Some(root_pos as u32), None)?;
+                                    root_pos
+                                };
+                                let root = seq.accepted_token_list[root_pos];
+
+                                let branch_seq_ids = (0..branches.len())
+                                    .map(|branch| tree.branch_seq_id(self.n_tasks, id, branch))
+                                    .collect::<Vec<_>>();
+
+                                // the branches see the accepted tokens, the root is added to all of them at once
+                                let mut root_seq_ids = vec![id as i32];
+
+                                for branch_seq_id in &branch_seq_ids {
+                                    ctx.copy_kv_cache_seq(id as i32, *branch_seq_id, None, None)?;
+                                    root_seq_ids.push(*branch_seq_id);
+                                }
+
+                                self.batch.add(root, root_pos as i32, &root_seq_ids, true)?;
+                                let root_logits = self.batch.n_tokens() - 1;
+                                let mut branch_logits = Vec::with_capacity(branches.len());
+
+                                for (branch, branch_seq_id) in branches.iter().zip(&branch_seq_ids) {
+                                    let mut logits = Vec::with_capacity(branch.len());
+
+                                    for (i, token) in branch[..branch.len() - 1].iter().enumerate() {
+                                        self.batch.add(*token, (root_pos + 1 + i) as i32, &[*branch_seq_id], true)?;
+                                        logits.push(self.batch.n_tokens() - 1);
+                                    }
+                                    branch_logits.push(logits);
+                                }
+
+                                tree_mapping.insert(id as u32, DraftTreeVerify { root_logits, branch_logits, branches });
+                            }
                         }
                         decode_n += 1;
                     }
@@ -480,18 +1028,42 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
             return Ok(0);
         }
 
-        ctx.decode(self.batch)?;
+        if let Err(e) = decode_retry.decode(ctx, self.batch) {
+            error!("target batch decode failed: {:?}", e);
+            self.batch.clear();
+
+            // dropping the sequence closes its output channel, the draft side reports the failure to the api
+            let failed = prefill_seq_ids.into_iter()
+                .chain(draft_mapping.keys().map(|id| *id as usize))
+                .chain(tree_mapping.keys().map(|id| *id as usize))
+                .collect::<BTreeSet<_>>();
+
+            for seq_id in failed {
+                self.sequence_list[seq_id] = None;
+                ctx.clear_kv_cache_seq(Some(seq_id as u32), None, None)?;
+
+                if let Some(tree) = tree_mapping.get(&(seq_id as u32)) {
+                    for branch in 0..tree.branches.len() {
+                        let branch_seq_id = self.draft_tree.unwrap().branch_seq_id(self.n_tasks, seq_id, branch);
+                        ctx.clear_kv_cache_seq(Some(branch_seq_id as u32), None, None)?;
+                    }
+                }
+            }
+            return Ok(0);
+        }
         self.batch.clear();
 
-        for seq_id in prefill_seq_ids {
-            unsafe {
-                let seq = self.sequence_list[seq_id].as_ref().unwrap();
-                let data_size = llama_cpp_sys_2::llama_state_seq_get_size(ctx.context.as_ptr(), seq_id as i32);
-                let mut data = vec![0u8; data_size];
-                llama_cpp_sys_2::llama_state_seq_get_data(ctx.context.as_ptr(), data.as_mut_ptr(), data_size, seq_id as i32);
+        if let Some(cache) = cache.as_deref_mut() {
+            for seq_id in prefill_seq_ids {
+                unsafe {
+                    let seq = self.sequence_list[seq_id].as_ref().unwrap();
+                    let data_size = llama_cpp_sys_2::llama_state_seq_get_size(ctx.context.as_ptr(), seq_id as i32);
+                    let mut data = vec![0u8; data_size];
+                    llama_cpp_sys_2::llama_state_seq_get_data(ctx.context.as_ptr(), data.as_mut_ptr(), data_size, seq_id as i32);
 
-                let raw_input_tokens = seq.prompt_token_list[0..seq.prompt_token_list.len() - 1].iter().map(|t| t.0).collect::<Vec<_>>();
-                cache.insert(raw_input_tokens, data)?;
+                    let raw_input_tokens = seq.prompt_token_list[0..seq.prompt_token_list.len() - 1].iter().map(|t| t.0).collect::<Vec<_>>();
+                    cache.insert(raw_input_tokens, data)?;
+                }
             }
         }
 
@@ -499,6 +1071,10 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
         let mut out_mapping: BTreeMap<u32, Vec<LlamaToken>> = BTreeMap::new();
         // seq_id -> next_token
         let mut next_mapping: BTreeMap<u32, LlamaToken> = BTreeMap::new();
+        // seq_id -> target probability of each verified draft token, only collected for the debug log
+        let mut prob_mapping: BTreeMap<u32, Vec<f32>> = BTreeMap::new();
+        let log_step = log::max_level() >= log::Level::Debug;
+
         for (i, pos, seq_id) in sample_list {
             if out_mapping.get(&seq_id).is_none() {
                 out_mapping.insert(seq_id, Vec::new());
@@ -510,32 +1086,19 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
             }
 
             let seq = self.sequence_list[seq_id as usize].as_mut().unwrap();
-            debug!("target sample");
-            let mut token = seq.sampler.sample(ctx, i);
 
             let draft_tokens = draft_mapping.get(&seq_id).unwrap();
             let draft_idx = pos as usize + 1 - seq.accepted_token_list.len();
 
-            let mut set_next = true;
-
-            if token != draft_tokens[draft_idx] {
-                let candidates = seq.sampler.get_candidates();
-                ensure!(candidates.sorted);
-
-                let token_data_list = unsafe { &*slice_from_raw_parts(candidates.data, min(self.n_candidates, candidates.size)) };
-
-                for td in token_data_list {
-                    if td.id == draft_tokens[draft_idx].0 {
-                        info!("candidate found");
-                        token = draft_tokens[draft_idx];
-                        set_next = false;
-                        break;
-                    }
-                }
-            } else {
-                set_next = false;
+            if log_step {
+                let p = token_logprob(ctx, i, draft_tokens[draft_idx]).exp();
+                prob_mapping.entry(seq_id).or_default().push(p);
             }
 
+            let (token, matched) = verify_draft_tokens(&mut seq.sampler, ctx, i, &draft_tokens[draft_idx..draft_idx + 1], self.n_candidates)?;
+            metrics.record_sample(&seq.request_id, &seq.sampler, token);
+            let set_next = matched.is_none();
+
             let is_eog_token = self.model.is_eog_token(token);
             if !is_eog_token {
                 seq.sampler.accept(token);
@@ -553,40 +1116,146 @@ impl <'a> SpeculativeCompletionsTargetSequenceSlots<'a> {
             seq.accepted_token_list.extend_from_slice(&out_tokens);
             let next = next_mapping.get(&seq_id).cloned();
 
+            if log_step {
+                let probabilities = prob_mapping.remove(&seq_id).unwrap_or_default();
+                log_speculative_step(&seq.request_id, &draft_mapping[&seq_id], &out_tokens, next, &probabilities);
+            }
+
             if let Some(next) = next {
                 seq.accepted_token_list.push(next);
             }
 
             let out = SpeculativeCompletionsTargetOutput {
                 accept_token_n: out_tokens.len() as u32,
-                next_token: next
+                next_token: next,
+                branch: 0,
             };
 
             let _ = seq.output_channel.send(out);
         }
+
+        for (seq_id, tree) in tree_mapping {
+            self.verify_tree(ctx, seq_id as usize, tree, metrics)?;
+        }
         Ok(decode_n)
     }
+
+    // follows the branch whose head the target accepts, as far as the target agrees with it
+    fn verify_tree(&mut self, ctx: &mut LlamaContext, seq_id: usize, tree: DraftTreeVerify, metrics: &Metrics) -> Result<()> {
+        let draft_tree = self.draft_tree.unwrap();
+        let model = self.model;
+        let seq = self.sequence_list[seq_id].as_mut().unwrap();
+
+        let accept = |seq: &mut SpeculativeCompletionsTargetSequence, token: LlamaToken| {
+            if !model.is_eog_token(token) {
+                seq.sampler.accept(token);
+            }
+        };
+
+        let log_step = log::max_level() >= log::Level::Debug;
+        let mut probabilities = Vec::new();
+
+        let heads = tree.branches.iter().map(|branch| branch[0]).collect::<Vec<_>>();
+        let (token, matched) = verify_draft_tokens(&mut seq.sampler, ctx, tree.root_logits, &heads, self.n_candidates)?;
+        metrics.record_sample(&seq.request_id, &seq.sampler, token);
+        accept(seq, token);
+
+        if log_step {
+            let head = heads[matched.unwrap_or(0)];
+            probabilities.push(token_logprob(ctx, tree.root_logits, head).exp());
+        }
+
+        let mut out_tokens = Vec::new();
+        let mut next = None;
+
+        let branch = match matched {
+            None => {
+                next = Some(token);
+                0
+            }
+            Some(branch) => {
+                out_tokens.push(token);
+                let draft_tokens = &tree.branches[branch];
+
+                for i in 1..draft_tokens.len() {
+                    let logits_pos = tree.branch_logits[branch][i - 1];
+
+                    if log_step {
+                        probabilities.push(token_logprob(ctx, logits_pos, draft_tokens[i]).exp());
+                    }
+
+                    let (token, matched) = verify_draft_tokens(&mut seq.sampler, ctx, logits_pos, &draft_tokens[i..i + 1], self.n_candidates)?;
+                    metrics.record_sample(&seq.request_id, &seq.sampler, token);
+                    accept(seq, token);
+
+                    if matched.is_none() {
+                        next = Some(token);
+                        break;
+                    }
+                    out_tokens.push(token);
+                }
+                branch
+            }
+        };
+
+        // the accepted branch cells become part of the sequence, the rest of the tree is dropped
+        let root_pos = seq.accepted_token_list.len() - 1;
+
+        if !out_tokens.is_empty() {
+            let branch_seq_id = draft_tree.branch_seq_id(self.n_tasks, seq_id, branch);
+            ctx.copy_kv_cache_seq(branch_seq_id, seq_id as i32, Some(root_pos as u32 + 1), None)?;
+        }
+
+        for i in 0..tree.branches.len() {
+            ctx.clear_kv_cache_seq(Some(draft_tree.branch_seq_id(self.n_tasks, seq_id, i) as u32), None, None)?;
+        }
+
+        seq.accepted_token_list.extend_from_slice(&out_tokens);
+
+        if log_step {
+            log_speculative_step(&seq.request_id, &tree.branches[branch], &out_tokens, next, &probabilities);
+        }
+
+        if let Some(next) = next {
+            seq.accepted_token_list.push(next);
+        }
+
+        let out = SpeculativeCompletionsTargetOutput {
+            accept_token_n: out_tokens.len() as u32,
+            next_token: next,
+            branch,
+        };
+
+        let _ = seq.output_channel.send(out);
+        Ok(())
+    }
 }
 
 fn speculative_completions_target_handler(
     model: &LlamaModel,
     backend: &LlamaBackend,
+    ctx_params: LlamaContextParams,
     task_rx: &flume::Receiver<SpeculativeCompletionsTargetTask>,
     n_tasks: u32,
     kv_cache_size_pre_task: u32,
     n_candidates: usize,
+    draft_tree: Option<DraftTree>,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
+    prefix_cache_slots: usize,
+    cache_version: u64,
+    decode_retry: &DecodeRetry,
+    metrics: &Metrics,
     _is_cancel: &AtomicBool
 ) -> Result<()> {
-    let mut ctx_params = LlamaContextParams::default()
+    let mut ctx_params = ctx_params
         .with_flash_attention(true)
         .with_offload_kqv(offload_kqv)
         .with_n_ctx(NonZeroU32::new(n_tasks * kv_cache_size_pre_task))
         .with_n_batch(n_tasks * kv_cache_size_pre_task);
 
-    ctx_params.context_params.n_seq_max = n_tasks;
+    ctx_params.context_params.n_seq_max = n_seq_max(n_tasks, draft_tree);
 
     if let Some(type_k) = type_k {
         ctx_params.context_params.type_k = type_k as ggml_type;
@@ -597,10 +1266,16 @@ fn speculative_completions_target_handler(
     };
 
     let mut ctx = model.new_context(backend, ctx_params)?;
-    let mut batch = LlamaBatch::new(kv_cache_size_pre_task as usize * n_tasks as usize, 1);
-
-    let mut slots = SpeculativeCompletionsTargetSequenceSlots::new(n_tasks, &mut batch, model, n_candidates);
-    let mut trie_cache = RadixTrieKVCache::new(RAIDX_TRIE_KV_CACHE_MAX_SEQ);
+    // the root of a draft tree belongs to the sequence and all of its branches
+    let seq_ids_per_token = 1 + draft_tree.map(|tree| tree.width).unwrap_or(0);
+    let mut batch = LlamaBatch::new(kv_cache_size_pre_task as usize * n_tasks as usize, seq_ids_per_token as i32);
+
+    let mut slots = SpeculativeCompletionsTargetSequenceSlots::new(n_tasks, &mut batch, model, n_candidates, draft_tree);
+    let mut trie_cache = if prefix_cache_slots > 0 {
+        Some(RadixTrieKVCache::new(prefix_cache_slots, cache_version))
+    } else {
+        None
+    };
 
     let select_tmp = RefCell::new(None);
     loop {
@@ -613,18 +1288,14 @@ fn speculative_completions_target_handler(
                     let output_channel = task.output_channel;
 
                     let sequence = SpeculativeCompletionsTargetSequence {
-                        sampler: Sampler::new(
-                            model,
-                            task.frequency_penalty,
-                            task.presence_penalty,
-                            Some(task.seed),
-                            task.temperature,
-                            task.top_p
-                        ),
+                        sampler: Sampler::new(model, &task.sampler_params),
+                        api_channel: Some(task.api_channel),
                         input_channel,
                         output_channel,
                         prompt_token_list: Vec::new(),
+                        history_len: None,
                         accepted_token_list: Vec::new(),
+                        request_id: task.request_id,
                     };
 
                     slots.put(sequence)?;
@@ -636,7 +1307,7 @@ fn speculative_completions_target_handler(
             }
         }
 
-        slots.poll(&mut ctx, select_tmp.take(), &mut trie_cache)?;
+        slots.poll(&mut ctx, select_tmp.take(), trie_cache.as_mut(), decode_retry, metrics)?;
 
         let mut selector = flume::Selector::new();
 
@@ -667,18 +1338,14 @@ fn speculative_completions_target_handler(
             let output_channel = task.output_channel;
 
             let sequence = SpeculativeCompletionsTargetSequence {
-                sampler: Sampler::new(
-                    model,
-                    task.frequency_penalty,
-                    task.presence_penalty,
-                    Some(task.seed),
-                    task.temperature,
-                    task.top_p
-                ),
+                sampler: Sampler::new(model, &task.sampler_params),
+                api_channel: Some(task.api_channel),
                 input_channel,
                 output_channel,
                 prompt_token_list: Vec::new(),
+                history_len: None,
                 accepted_token_list: Vec::new(),
+                request_id: task.request_id,
             };
 
             slots.put(sequence)?;
@@ -693,18 +1360,26 @@ enum DraftSequenceState {
 }
 
 struct SpeculativeCompletionsDraftSequence {
+    request_id: String,
     state: DraftSequenceState,
     prompt_tokens: Vec<LlamaToken>,
+    history_len: Option<usize>,
     confirmed_tokens: Vec<LlamaToken>,
     unconfirmed_tokens: Vec<LlamaToken>,
     sampler: Sampler,
-    api_channel: flume::Sender<LlamaToken>,
+    api_channel: flume::Sender<CompletionsEvent>,
     to_target_channel: flume::Sender<SpeculativeCompletionsTargetInput>,
     from_target_channel: flume::Receiver<SpeculativeCompletionsTargetOutput>,
     maximum_tokens: u32,
     max_unconfirmed_tokens: usize,
     total_draft_tokens: u32,
     total_accept_tokens: u32,
+    // set after a draft decode failure or for a task with speculative decoding disabled,
+    // the target decodes the rest of the sequence on its own
+    fallback: bool,
+    // branches sent to the target, one of them becomes the unconfirmed tokens once the target answers
+    tree_branches: Option<Vec<Vec<LlamaToken>>>,
+    stop_token_ids: HashSet<u32>,
 }
 
 impl SpeculativeCompletionsDraftSequence {
@@ -714,27 +1389,36 @@ impl SpeculativeCompletionsDraftSequence {
         send_to_target: flume::Sender<SpeculativeCompletionsTargetInput>,
         from_target:  flume::Receiver<SpeculativeCompletionsTargetOutput>,
         max_unconfirmed_tokens: usize,
+        kv_cache_size_pre_task: u32,
+        metrics: &Metrics,
     ) -> Self {
+        let draft_params = SamplerParams {
+            seed: sampler::draft_seed(task.sampler_params.seed),
+            ..task.sampler_params.clone()
+        };
+        let sampler = Sampler::new(model, &draft_params);
+        debug!("[{}] seed: {:?}, draft effective seed: {}", task.request_id, task.sampler_params.seed, sampler.seed);
+        metrics.record_queue_wait(task.enqueued_at.elapsed());
+        let _ = task.to_api.send(CompletionsEvent::Started(Instant::now()));
+
         let sequence = SpeculativeCompletionsDraftSequence {
+            request_id: task.request_id,
             state: DraftSequenceState::Decode,
             prompt_tokens: task.input_token_list,
+            history_len: task.history_len,
             confirmed_tokens: Vec::new(),
             unconfirmed_tokens: Vec::new(),
-            sampler: Sampler::new(
-                model,
-                task.frequency_penalty,
-                task.presence_penalty,
-                task.seed,
-                task.temperature,
-                task.top_p
-            ),
+            sampler,
             api_channel: task.to_api,
             to_target_channel: send_to_target,
             from_target_channel: from_target,
-            maximum_tokens: task.maximum_tokens.unwrap(),
+            maximum_tokens: task.maximum_tokens.end_pos(task.input_token_list.len() as u32, kv_cache_size_pre_task),
             max_unconfirmed_tokens,
             total_draft_tokens: 0,
-            total_accept_tokens: 0
+            total_accept_tokens: 0,
+            fallback: task.disable_speculative,
+            tree_branches: None,
+            stop_token_ids: task.stop_token_ids,
         };
         sequence
     }
@@ -744,6 +1428,8 @@ struct SpeculativeCompletionsDraftSequenceSlots<'a> {
     sequence_list: Vec<Option<SpeculativeCompletionsDraftSequence>>,
     batch: &'a mut LlamaBatch,
     model: &'a LlamaModel,
+    n_tasks: u32,
+    draft_tree: Option<DraftTree>,
 }
 
 impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
@@ -751,6 +1437,7 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
         n_task: u32,
         batch: &'a mut LlamaBatch,
         model: &'a LlamaModel,
+        draft_tree: Option<DraftTree>,
     ) -> Self {
         let mut sequence_list = Vec::with_capacity(n_task as usize);
 
@@ -762,6 +1449,8 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
             sequence_list,
             batch,
             model,
+            n_tasks: n_task,
+            draft_tree,
         }
     }
 
@@ -771,6 +1460,103 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
             .count()
     }
 
+    fn fallback(&mut self, ctx: &mut LlamaContext, seq_id: usize, metrics: &Metrics) {
+        let seq = self.sequence_list[seq_id].as_mut().unwrap();
+        seq.fallback = true;
+        seq.unconfirmed_tokens.clear();
+        metrics.speculative_fallback.fetch_add(1, Ordering::Relaxed);
+        let _ = ctx.clear_kv_cache_seq(Some(seq_id as u32), None, None);
+
+        if let Some(tree) = self.draft_tree {
+            for branch in 0..tree.width {
+                let _ = ctx.clear_kv_cache_seq(Some(tree.branch_seq_id(self.n_tasks, seq_id, branch) as u32), None, None);
+            }
+        }
+    }
+
+    // extends every branch head by one token per level, all trees of the poll share each level's decode
+    fn grow_draft_trees(
+        &mut self,
+        ctx: &mut LlamaContext,
+        heads: Vec<(usize, Vec<LlamaToken>)>,
+        decode_retry: &DecodeRetry,
+        metrics: &Metrics,
+    ) -> Result<()> {
+        let draft_tree = self.draft_tree.unwrap();
+        // (seq_id, depth, branches)
+        let mut trees = Vec::with_capacity(heads.len());
+
+        for (seq_id, heads) in heads {
+            let seq = self.sequence_list[seq_id].as_ref().unwrap();
+            // the root is the last confirmed token, the branches can't run past the maximum tokens
+            let depth = min(draft_tree.depth, seq.maximum_tokens as usize - seq.confirmed_tokens.len());
+
+            if depth > 1 {
+                for branch in 0..heads.len() {
+                    ctx.copy_kv_cache_seq(seq_id as i32, draft_tree.branch_seq_id(self.n_tasks, seq_id, branch), None, None)?;
+                }
+            }
+
+            let branches = heads.into_iter().map(|head| vec![head]).collect::<Vec<_>>();
+            trees.push((seq_id, depth, branches));
+        }
+
+        let max_depth = trees.iter().map(|(_, depth, _)| *depth).max().unwrap_or(1);
+
+        for level in 1..max_depth {
+            // (tree_idx, branch, logits_pos)
+            let mut sample_list = Vec::new();
+
+            for (tree_idx, (seq_id, depth, branches)) in trees.iter().enumerate() {
+                if level >= *depth {
+                    continue;
+                }
+
+                let root_pos = self.sequence_list[*seq_id].as_ref().unwrap().confirmed_tokens.len() - 1;
+
+                for (branch, tokens) in branches.iter().enumerate() {
+                    let branch_seq_id = draft_tree.branch_seq_id(self.n_tasks, *seq_id, branch);
+                    self.batch.add(*tokens.last().unwrap(), (root_pos + level) as i32, &[branch_seq_id], true)?;
+                    sample_list.push((tree_idx, branch, self.batch.n_tokens() - 1));
+                }
+            }
+
+            if let Err(e) = decode_retry.decode(ctx, self.batch) {
+                warn!("draft tree decode failed, falling back to non-speculative decoding: {:?}", e);
+                self.batch.clear();
+
+                for (seq_id, _, _) in trees {
+                    self.fallback(ctx, seq_id, metrics);
+                }
+                return Ok(());
+            }
+            self.batch.clear();
+
+            for (tree_idx, branch, logits_pos) in sample_list {
+                let (seq_id, _, branches) = &mut trees[tree_idx];
+                let seq = self.sequence_list[*seq_id].as_mut().unwrap();
+
+                // the branches are alternatives, the sampler only accepts the tokens the target confirms
+                debug!("draft sample");
+                let token = seq.sampler.sample(ctx, logits_pos);
+                branches[branch].push(token);
+            }
+        }
+
+        for (seq_id, _, branches) in trees {
+            let seq = self.sequence_list[seq_id].as_mut().unwrap();
+            seq.state = DraftSequenceState::WaitConfirm;
+
+            let target_input = SpeculativeCompletionsTargetInput::DraftTreeInput {
+                branches: branches.clone()
+            };
+            // the target drops a sequence whose batch failed to decode, the wait for its output ends it
+            let _ = seq.to_target_channel.send(target_input);
+            seq.tree_branches = Some(branches);
+        }
+        Ok(())
+    }
+
     fn put(&mut self, seq: SpeculativeCompletionsDraftSequence) -> Result<()> {
         for slot in self.sequence_list.iter_mut() {
             if slot.is_some() {
@@ -784,7 +1570,14 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
     }
 
     // (seq_id, task_input)
-    fn poll(&mut self, ctx: &mut LlamaContext, mut select_task: Option<(u32, SpeculativeCompletionsTargetOutput)>, cache: &mut RadixTrieKVCache) -> Result<Poll<()>> {
+    fn poll(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut select_task: Option<(u32, SpeculativeCompletionsTargetOutput)>,
+        mut cache: Option<&mut RadixTrieKVCache>,
+        decode_retry: &DecodeRetry,
+        metrics: &Metrics,
+    ) -> Result<Poll<()>> {
         // seq_id -> logits_pos
         let mut decode_seq_list = BTreeMap::new();
         let mut need_loop = true;
@@ -799,24 +1592,48 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                                 continue;
                             }
 
+                            if seq.fallback {
+                                if seq.confirmed_tokens.is_empty() {
+                                    // speculative decoding is disabled for the task, the draft never sees the prompt
+                                    seq.confirmed_tokens.extend_from_slice(&seq.prompt_tokens);
+
+                                    let input = SpeculativeCompletionsTargetInput::PromptInput {
+                                        token_list: seq.prompt_tokens.clone(),
+                                        history_len: seq.history_len,
+                                    };
+                                    let _ = seq.to_target_channel.send(input);
+                                }
+
+                                // a placeholder draft, the target either replaces it with its own sample or accepts it as a candidate
+                                seq.unconfirmed_tokens.push(*seq.confirmed_tokens.last().unwrap());
+                                seq.state = DraftSequenceState::WaitConfirm;
+
+                                let target_input = SpeculativeCompletionsTargetInput::DraftInput {
+                                    draft_token_list: seq.unconfirmed_tokens.clone()
+                                };
+                                let _ = seq.to_target_channel.send(target_input);
+                                continue;
+                            }
+
                             if seq.unconfirmed_tokens.is_empty() && seq.confirmed_tokens.is_empty() {
                                 seq.confirmed_tokens.extend_from_slice(&seq.prompt_tokens);
 
                                 let input = SpeculativeCompletionsTargetInput::PromptInput {
-                                    token_list: seq.prompt_tokens.clone()
+                                    token_list: seq.prompt_tokens.clone(),
+                                    history_len: seq.history_len,
                                 };
 
-                                seq.to_target_channel.send(input)?;
+                                let _ = seq.to_target_channel.send(input);
 
                                 let raw_tokens = seq.confirmed_tokens.iter().map(|t| t.0).collect::<Vec<_>>();
-                                match cache.get(&raw_tokens) {
+                                match cache.as_deref_mut().and_then(|cache| cache.get(&raw_tokens, seq.history_len)) {
                                     None => {
                                         for i in 0..seq.confirmed_tokens.len() - 1 {
                                             self.batch.add(seq.confirmed_tokens[i], i as i32, &[seq_id as i32], false)?
                                         }
                                     }
                                     Some((sub_seq_data, sub_seq_tokens_len)) => {
-                                        debug!("cache hit");
+                                        debug!("[{}] cache hit", seq.request_id);
                                         let sub_seq_len = min(seq.confirmed_tokens.len() - 1, sub_seq_tokens_len);
 
                                         unsafe {
@@ -838,7 +1655,7 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                                 let target_input = SpeculativeCompletionsTargetInput::DraftInput {
                                     draft_token_list: seq.unconfirmed_tokens.clone()
                                 };
-                                seq.to_target_channel.send(target_input)?;
+                                let _ = seq.to_target_channel.send(target_input);
                                 continue;
                             }
 
@@ -860,12 +1677,25 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                             } else {
                                 let out = match seq.from_target_channel.try_recv() {
                                     Ok(out) => out,
-                                    Err(_) => continue,
+                                    Err(TryRecvError::Empty) => continue,
+                                    Err(TryRecvError::Disconnected) => {
+                                        error!("[{}] the target dropped the sequence", seq.request_id);
+                                        let _ = seq.api_channel.send(CompletionsEvent::Failed);
+                                        *seq_op = None;
+                                        ctx.clear_kv_cache_seq(Some(seq_id as u32), None, None)?;
+                                        continue;
+                                    }
                                 };
                                 out
                             };
 
-                            info!("accept_token_n: {}", out.accept_token_n);
+                            info!("[{}] accept_token_n: {}", seq.request_id, out.accept_token_n);
+                            let tree_branches = seq.tree_branches.take();
+
+                            if let Some(branches) = &tree_branches {
+                                seq.unconfirmed_tokens = branches[out.branch].clone();
+                            }
+
                             seq.total_draft_tokens += seq.unconfirmed_tokens.len() as u32;
                             seq.total_accept_tokens += out.accept_token_n;
 
@@ -886,7 +1716,25 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                                 seq.max_unconfirmed_tokens = max(2, seq.max_unconfirmed_tokens - 2);
                             }
 
-                            if out.accept_token_n as usize != seq.unconfirmed_tokens.len() {
+                            if let Some(branches) = &tree_branches {
+                                let draft_tree = self.draft_tree.unwrap();
+
+                                // the accepted branch cells become part of the sequence, the rest of the tree is dropped
+                                if out.accept_token_n > 0 && branches[out.branch].len() > 1 {
+                                    let branch_seq_id = draft_tree.branch_seq_id(self.n_tasks, seq_id, out.branch);
+                                    ctx.copy_kv_cache_seq(branch_seq_id, seq_id as i32, Some(old_pos as u32 + 1), None)?;
+                                }
+
+                                for branch in 0..branches.len() {
+                                    ctx.clear_kv_cache_seq(Some(draft_tree.branch_seq_id(self.n_tasks, seq_id, branch) as u32), None, None)?;
+                                }
+
+                                for token in &seq.confirmed_tokens[old_pos + 1..] {
+                                    seq.sampler.accept(*token);
+                                }
+
+                                ctx.clear_kv_cache_seq(Some(seq_id as u32), Some(seq.confirmed_tokens.len() as u32 - 1), None)?;
+                            } else if out.accept_token_n as usize != seq.unconfirmed_tokens.len() && !seq.fallback {
                                 seq.sampler.reset();
                                 for token in seq.confirmed_tokens.iter() {
                                     seq.sampler.accept(*token);
@@ -899,20 +1747,23 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                             for pos in old_pos + 1..seq.confirmed_tokens.len() {
                                 let out_token = seq.confirmed_tokens[pos];
 
-                                if self.model.is_eog_token(out_token) {
-                                    info!("acceptance rate: {}", seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
+                                if self.model.is_eog_token(out_token) || seq.stop_token_ids.contains(&(out_token.0 as u32)) {
+                                    info!("[{}] acceptance rate: {}", seq.request_id, seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
+                                    metrics.record_completion(seq.prompt_tokens.len() as u64, (pos - seq.prompt_tokens.len()) as u64);
                                     remove_seq = true;
                                     break;
                                 }
 
-                                if seq.api_channel.send(out_token).is_err() {
-                                    info!("acceptance rate: {}", seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
+                                if seq.api_channel.send(CompletionsEvent::Token(out_token)).is_err() {
+                                    info!("[{}] acceptance rate: {}", seq.request_id, seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
                                     remove_seq = true;
                                     break;
                                 }
 
                                 if pos + 1 >= seq.maximum_tokens as usize {
-                                    info!("acceptance rate: {}", seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
+                                    let _ = seq.api_channel.send(CompletionsEvent::Length);
+                                    info!("[{}] acceptance rate: {}", seq.request_id, seq.total_accept_tokens as f32 / seq.total_draft_tokens as f32);
+                                    metrics.record_completion(seq.prompt_tokens.len() as u64, (pos + 1 - seq.prompt_tokens.len()) as u64);
                                     remove_seq = true;
                                     break;
                                 }
@@ -933,14 +1784,26 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
         }
 
         if !decode_seq_list.is_empty() {
-            ctx.decode(self.batch)?;
+            if let Err(e) = decode_retry.decode(ctx, self.batch) {
+                warn!("draft decode failed, falling back to non-speculative decoding: {:?}", e);
+                self.batch.clear();
+
+                for (seq_id, _) in decode_seq_list {
+                    self.fallback(ctx, seq_id, metrics);
+                }
+                return Ok(Poll::Ready(()));
+            }
             self.batch.clear();
-            
+            // seq_id -> branch heads
+            let mut tree_heads = Vec::new();
+
             for (seq_id, logits_pos) in decode_seq_list {
                 let seq = self.sequence_list[seq_id].as_mut().unwrap();
 
                 // first prefill
-                if seq.confirmed_tokens.len() == seq.prompt_tokens.len() && seq.unconfirmed_tokens.is_empty() {
+                let first_prefill = seq.confirmed_tokens.len() == seq.prompt_tokens.len() && seq.unconfirmed_tokens.is_empty();
+
+                if let Some(cache) = cache.as_deref_mut().filter(|_| first_prefill) {
                     unsafe {
                         let data_size = llama_cpp_sys_2::llama_state_seq_get_size(ctx.context.as_ptr(), seq_id as i32);
                         let mut data = vec![0u8; data_size];
@@ -951,11 +1814,20 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
                     }
                 }
 
+                if let Some(tree) = self.draft_tree {
+                    tree_heads.push((seq_id, draft_tree_heads(&mut seq.sampler, ctx, logits_pos, tree.width)?));
+                    continue;
+                }
+
                 debug!("draft sample");
                 let draft_token = seq.sampler.sample(ctx, logits_pos);
                 seq.sampler.accept(draft_token);
                 seq.unconfirmed_tokens.push(draft_token);
             }
+
+            if !tree_heads.is_empty() {
+                self.grow_draft_trees(ctx, tree_heads, decode_retry, metrics)?;
+            }
             Ok(Poll::Ready(()))
         } else {
             Ok(Poll::Pending)
@@ -966,22 +1838,29 @@ impl <'a> SpeculativeCompletionsDraftSequenceSlots<'a> {
 fn speculative_completions_draft_handler(
     model: &LlamaModel,
     backend: &LlamaBackend,
+    ctx_params: LlamaContextParams,
     to_target_handler: &flume::Sender<SpeculativeCompletionsTargetTask>,
     task_rx: &flume::Receiver<CompletionsTask>,
     n_tasks: u32,
     kv_cache_size_pre_task: u32,
     max_unconfirmed_tokens: usize,
+    draft_tree: Option<DraftTree>,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
+    prefix_cache_slots: usize,
+    cache_version: u64,
+    decode_retry: &DecodeRetry,
+    metrics: &Metrics,
+    active_tasks: &AtomicU32,
 ) -> Result<()> {
-    let mut ctx_params = LlamaContextParams::default()
+    let mut ctx_params = ctx_params
         .with_flash_attention(true)
         .with_offload_kqv(offload_kqv)
         .with_n_ctx(NonZeroU32::new(n_tasks * kv_cache_size_pre_task))
         .with_n_batch(n_tasks * kv_cache_size_pre_task);
 
-    ctx_params.context_params.n_seq_max = n_tasks;
+    ctx_params.context_params.n_seq_max = n_seq_max(n_tasks, draft_tree);
 
     if let Some(type_k) = type_k {
         ctx_params.context_params.type_k = type_k as ggml_type;
@@ -994,32 +1873,39 @@ fn speculative_completions_draft_handler(
     let mut ctx = model.new_context(backend, ctx_params)?;
     let mut batch = LlamaBatch::new(kv_cache_size_pre_task as usize * n_tasks as usize, 1);
 
-    let mut slots = SpeculativeCompletionsDraftSequenceSlots::new(n_tasks, &mut batch, model);
-    let mut trie_cache = RadixTrieKVCache::new(RAIDX_TRIE_KV_CACHE_MAX_SEQ);
+    let mut slots = SpeculativeCompletionsDraftSequenceSlots::new(n_tasks, &mut batch, model, draft_tree);
+    let mut trie_cache = if prefix_cache_slots > 0 {
+        Some(RadixTrieKVCache::new(prefix_cache_slots, cache_version))
+    } else {
+        None
+    };
 
     let select_task = RefCell::new(None);
 
     loop {
-        while slots.len() < n_tasks as usize {
+        while slots.len() < min(n_tasks, active_tasks.load(Ordering::Relaxed)) as usize {
             match task_rx.try_recv() {
                 Ok(mut task) => {
+                    if task.injections.take().is_some() {
+                        warn!("[{}] token injection is not supported with speculative decoding, ignored", task.request_id);
+                    }
+
+                    if task.control.take().is_some() {
+                        debug!("[{}] pause is not supported with speculative decoding, ignored", task.request_id);
+                    }
+
+                    if rejects_prompt_logprobs(&task) {
+                        continue;
+                    }
+
                     let (to_target, from_draft) = flume::unbounded();
                     let (to_draft, from_target) = flume::unbounded();
-                    task.seed = Some(task.seed.unwrap_or_else(|| rand::random()));
-                    task.maximum_tokens = {
-                        let out = min(
-                            task.maximum_tokens.map(|n_tokens| n_tokens + task.input_token_list.len() as u32).unwrap_or(kv_cache_size_pre_task),
-                            kv_cache_size_pre_task
-                        );
-                        Some(out)
-                    };
+                    task.sampler_params.seed = Some(task.sampler_params.seed.unwrap_or_else(|| rand::random()));
 
                     let target_task = SpeculativeCompletionsTargetTask {
-                        frequency_penalty: task.frequency_penalty,
-                        presence_penalty: task.presence_penalty,
-                        seed: task.seed.unwrap(),
-                        temperature: task.temperature,
-                        top_p: task.top_p,
+                        request_id: task.request_id.clone(),
+                        sampler_params: task.sampler_params.clone(),
+                        api_channel: task.to_api.clone(),
                         input_channel: from_draft,
                         output_channel: to_draft
                     };
@@ -1031,7 +1917,9 @@ fn speculative_completions_draft_handler(
                         model,
                         to_target,
                         from_target,
-                        max_unconfirmed_tokens
+                        max_unconfirmed_tokens,
+                        kv_cache_size_pre_task,
+                        metrics
                     );
 
                     slots.put(draft_seq)?;
@@ -1043,7 +1931,7 @@ fn speculative_completions_draft_handler(
             }
         }
 
-        if slots.poll(&mut ctx, select_task.take(), &mut trie_cache)? == Poll::Pending {
+        if slots.poll(&mut ctx, select_task.take(), trie_cache.as_mut(), decode_retry, metrics)? == Poll::Pending {
             let mut selector = flume::Selector::new();
 
             for (seq_id, seq_op) in slots.sequence_list.iter().enumerate() {
@@ -1059,7 +1947,7 @@ fn speculative_completions_draft_handler(
 
             let completions_task = Rc::new(RefCell::new(None));
 
-            if slots.len() < n_tasks as usize {
+            if slots.len() < min(n_tasks, active_tasks.load(Ordering::Relaxed)) as usize {
                 selector = selector.recv(&task_rx, {
                     let completions_task = completions_task.clone();
                     move |task_res| *completions_task.borrow_mut() = task_res.ok()
@@ -1068,23 +1956,26 @@ fn speculative_completions_draft_handler(
             selector.wait();
 
             if let Some(mut completions_task) = completions_task.take() {
+                if completions_task.injections.take().is_some() {
+                    warn!("[{}] token injection is not supported with speculative decoding, ignored", completions_task.request_id);
+                }
+
+                if completions_task.control.take().is_some() {
+                    debug!("[{}] pause is not supported with speculative decoding, ignored", completions_task.request_id);
+                }
+
+                if rejects_prompt_logprobs(&completions_task) {
+                    continue;
+                }
+
                 let (to_target, from_draft) = flume::unbounded();
                 let (to_draft, from_target) = flume::unbounded();
-                completions_task.seed = Some(completions_task.seed.unwrap_or_else(|| rand::random()));
-                completions_task.maximum_tokens = {
-                    let out = min(
-                        completions_task.maximum_tokens.map(|n_tokens| n_tokens + completions_task.input_token_list.len() as u32).unwrap_or(kv_cache_size_pre_task),
-                        kv_cache_size_pre_task
-                    );
-                    Some(out)
-                };
+                completions_task.sampler_params.seed = Some(completions_task.sampler_params.seed.unwrap_or_else(|| rand::random()));
 
                 let target_task = SpeculativeCompletionsTargetTask {
-                    frequency_penalty: completions_task.frequency_penalty,
-                    presence_penalty: completions_task.presence_penalty,
-                    seed: completions_task.seed.unwrap(),
-                    temperature: completions_task.temperature,
-                    top_p: completions_task.top_p,
+                    request_id: completions_task.request_id.clone(),
+                    sampler_params: completions_task.sampler_params.clone(),
+                    api_channel: completions_task.to_api.clone(),
                     input_channel: from_draft,
                     output_channel: to_draft
                 };
@@ -1096,7 +1987,9 @@ fn speculative_completions_draft_handler(
                     model,
                     to_target,
                     from_target,
-                    max_unconfirmed_tokens
+                    max_unconfirmed_tokens,
+                    kv_cache_size_pre_task,
+                    metrics
                 );
 
                 slots.put(draft_seq)?;
@@ -1105,27 +1998,41 @@ fn speculative_completions_draft_handler(
     }
 }
 
+// dropping the task closes its api channel, the api reports the error
+fn rejects_prompt_logprobs(task: &CompletionsTask) -> bool {
+    if task.prompt_logprobs {
+        warn!("[{}] prompt logprobs are not supported with speculative decoding", task.request_id);
+    }
+    task.prompt_logprobs
+}
+
 fn embedding_handler(
     model: &LlamaModel,
     backend: &LlamaBackend,
+    ctx_params: LlamaContextParams,
     task_rx: &flume::Receiver<EmbeddingTask>,
     n_tasks: u32,
     kv_cache_size_pre_task: u32,
+    max_batch_size: usize,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
+    decode_retry: &DecodeRetry,
+    active_tasks: &AtomicU32,
     _is_cancel: &AtomicBool,
 ) -> Result<()> {
     let n_embd = model.n_embd() as usize;
 
-    let mut ctx_params = LlamaContextParams::default()
+    let mut ctx_params = ctx_params
         .with_embeddings(true)
         .with_flash_attention(true)
         .with_offload_kqv(offload_kqv)
         .with_n_ctx(NonZeroU32::new(n_tasks * kv_cache_size_pre_task))
         .with_n_batch(n_tasks * kv_cache_size_pre_task);
 
-    ctx_params.context_params.n_seq_max = n_tasks;
+    // short inputs are packed into the kv cache of the parallel tasks, so a batch can hold more sequences than tasks
+    let max_seqs = max(n_tasks as usize, max_batch_size);
+    ctx_params.context_params.n_seq_max = max_seqs as u32;
 
     if let Some(type_k) = type_k {
         ctx_params.context_params.type_k = type_k as ggml_type;
@@ -1139,31 +2046,58 @@ fn embedding_handler(
     let mut batch = LlamaBatch::new(kv_cache_size_pre_task as usize * n_tasks as usize, 1);
     let pooling_type = unsafe {llama_cpp_sys_2::llama_pooling_type(ctx.context.as_ptr())};
 
-    let mut task_list = Vec::with_capacity(n_tasks as usize);
+    let mut task_list: Vec<EmbeddingTask> = Vec::with_capacity(max_seqs);
+    // a task that didn't fit into the previous batch
+    let mut pending = None;
 
     loop {
         if task_list.is_empty() {
-            match task_rx.recv_timeout(Duration::from_secs(1)) {
-                Ok(task) => task_list.push(task),
-                Err(RecvTimeoutError::Timeout) => {
-                    continue;
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    return Err(anyhow!("Task channel disconnected"));
+            let task = match pending.take() {
+                Some(task) => task,
+                None => match task_rx.recv_timeout(Duration::from_secs(1)) {
+                    Ok(task) => task,
+                    Err(RecvTimeoutError::Timeout) => {
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(anyhow!("Task channel disconnected"));
+                    }
                 }
             };
+
+            // the request failed to enqueue the rest of its inputs or was dropped
+            if task.to_api.is_disconnected() {
+                continue;
+            }
+            task_list.push(task);
         }
 
-        while task_list.len() < n_tasks as usize {
-            match task_rx.try_recv() {
-                Ok(task) => {
-                    task_list.push(task);
-                }
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => {
-                    return Err(anyhow!("Task channel disconnected"));
+        let token_budget = min(n_tasks, active_tasks.load(Ordering::Relaxed)) as usize * kv_cache_size_pre_task as usize;
+        let mut n_tokens = task_list.iter().map(|task| task.input_token_list.len()).sum::<usize>();
+
+        while task_list.len() < max_seqs {
+            let task = match pending.take() {
+                Some(task) => task,
+                None => match task_rx.try_recv() {
+                    Ok(task) => task,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(anyhow!("Task channel disconnected"));
+                    }
                 }
+            };
+
+            if task.to_api.is_disconnected() {
+                continue;
+            }
+
+            if n_tokens + task.input_token_list.len() > token_budget {
+                pending = Some(task);
+                break;
             }
+
+            n_tokens += task.input_token_list.len();
+            task_list.push(task);
         }
 
         for (seq_id, task) in task_list.iter().enumerate() {
@@ -1173,7 +2107,14 @@ fn embedding_handler(
         }
 
         ctx.clear_kv_cache();
-        ctx.decode(&mut batch)?;
+
+        // dropping the tasks closes their channels before the embeddings arrive, the api answers with an error
+        if let Err(e) = decode_retry.decode(&mut ctx, &mut batch) {
+            error!("embedding batch decode failed: {:?}", e);
+            batch.clear();
+            task_list.clear();
+            continue;
+        }
         let embeddings_len = task_list.len() * n_embd;
         batch.clear();
 
@@ -1187,12 +2128,12 @@ fn embedding_handler(
             for task in task_list.drain(..){
                 let (l, r) = out.split_at(n_embd);
                 out = r;
-                let _ = task.to_api.send(l.to_vec());
+                let _ = task.to_api.send((task.index, l.to_vec()));
             }
         } else {
             for (seq_id, task) in task_list.drain(..).enumerate() {
                 let out = ctx.embeddings_seq_ith(seq_id as i32)?.to_vec();
-                let _ = task.to_api.send(out);
+                let _ = task.to_api.send((task.index, out));
             }
         }
     }
@@ -1201,27 +2142,42 @@ fn embedding_handler(
 pub async fn run_embedding (
     model: Arc<LlamaModel>,
     backend: Arc<LlamaBackend>,
+    ctx_params: LlamaContextParams,
     task_rx: flume::Receiver<EmbeddingTask>,
     kv_cache_size_pre_task: u32,
     n_tasks: u32,
+    max_batch_size: usize,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
+    max_retries: u32,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    cpu_affinity: Option<Vec<usize>>,
 ) -> Result<()> {
     let is_cancel = Arc::new(AtomicBool::new(false));
+    let decode_retry = DecodeRetry { max_retries, metrics };
     let model = model.clone();
     let backend = backend.clone();
 
     tokio::task::spawn_blocking(move || {
+        if let Some(cpus) = &cpu_affinity {
+            affinity::set_current_thread(cpus);
+        }
+
         embedding_handler(
             &*model,
             &*backend,
+            ctx_params,
             &task_rx,
             n_tasks,
             kv_cache_size_pre_task,
+            max_batch_size,
             offload_kqv,
             type_k,
             type_v,
+            &decode_retry,
+            &*parallel_tasks,
             &*is_cancel
         )
     }).await?
@@ -1231,18 +2187,29 @@ pub async fn run_completions(
     model: Arc<LlamaModel>,
     draft_model: Option<Arc<LlamaModel>>,
     backend: Arc<LlamaBackend>,
+    ctx_params: LlamaContextParams,
+    draft_ctx_params: LlamaContextParams,
     task_rx: flume::Receiver<CompletionsTask>,
     kv_cache_size_pre_task: u32,
     n_tasks: u32,
     max_unconfirmed_tokens: usize,
     n_candidates: usize,
+    draft_tree: Option<DraftTree>,
     offload_kqv: bool,
     type_k: Option<KVCacheTypes>,
     type_v: Option<KVCacheTypes>,
     draft_type_k: Option<KVCacheTypes>,
-    draft_type_v: Option<KVCacheTypes>
+    draft_type_v: Option<KVCacheTypes>,
+    has_kv_cache: bool,
+    prefix_cache_slots: usize,
+    cache_version: u64,
+    max_retries: u32,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    cpu_affinity: Option<Vec<usize>>,
 ) -> Result<()> {
     let is_cancel = Arc::new(AtomicBool::new(false));
+    let decode_retry = DecodeRetry { max_retries, metrics: metrics.clone() };
 
     match draft_model {
         None => {
@@ -1250,20 +2217,33 @@ pub async fn run_completions(
             let backend = backend.clone();
 
             tokio::task::spawn_blocking(move || {
+                if let Some(cpus) = &cpu_affinity {
+                    affinity::set_current_thread(cpus);
+                }
+
                 completions_handler(
                     &*model,
                     &*backend,
+                    ctx_params,
                     &task_rx,
                     n_tasks,
                     kv_cache_size_pre_task,
                     offload_kqv,
                     type_k,
                     type_v,
+                    has_kv_cache,
+                    prefix_cache_slots,
+                    cache_version,
+                    &decode_retry,
+                    &*metrics,
+                    &*parallel_tasks,
                     &*is_cancel
                 )
             }).await?
         }
         Some(draft_model) => {
+            ensure!(has_kv_cache, "speculative decoding requires a model with KV cache");
+
             {
                 let ctx_params = LlamaContextParams::default();
                 let target_ctx = model.new_context(&*backend, ctx_params.clone())?;
@@ -1276,36 +2256,60 @@ pub async fn run_completions(
             let target_handle = tokio::task::spawn_blocking({
                 let model = model.clone();
                 let backend = backend.clone();
+                let decode_retry = decode_retry.clone();
+                let metrics = metrics.clone();
                 let is_cancel = Arc::new(AtomicBool::new(false));
+                let cpu_affinity = cpu_affinity.clone();
 
                 move || {
+                    if let Some(cpus) = &cpu_affinity {
+                        affinity::set_current_thread(cpus);
+                    }
+
                     speculative_completions_target_handler(
                         &*model,
                         &*backend,
+                        ctx_params,
                         &from_draft_handler,
                         n_tasks,
                         kv_cache_size_pre_task,
                         n_candidates,
+                        draft_tree,
                         offload_kqv,
                         type_k,
                         type_v,
+                        prefix_cache_slots,
+                        cache_version,
+                        &decode_retry,
+                        &*metrics,
                         &*is_cancel,
                     )
                 }
             });
 
             let draft_handle = tokio::task::spawn_blocking(move || {
+                if let Some(cpus) = &cpu_affinity {
+                    affinity::set_current_thread(cpus);
+                }
+
                 speculative_completions_draft_handler(
                     &*draft_model,
                     &*backend,
+                    draft_ctx_params,
                     &to_target_handler,
                     &task_rx,
                     n_tasks,
                     kv_cache_size_pre_task,
                     max_unconfirmed_tokens,
+                    draft_tree,
                     offload_kqv,
                     draft_type_k,
                     draft_type_v,
+                    prefix_cache_slots,
+                    cache_version,
+                    &decode_retry,
+                    &*metrics,
+                    &*parallel_tasks,
                 )
             });
 
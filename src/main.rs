@@ -1,23 +1,41 @@
 #[macro_use]
 extern crate log;
 
-use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
+use anyhow::{anyhow, ensure, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::llama_backend;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::model::{LlamaModel, Special};
 use llama_cpp_2::token::LlamaToken;
-use llama_cpp_sys_2::{ggml_backend_dev_t, ggml_backend_device_register, ggml_backend_reg_by_name, ggml_backend_reg_get_proc_address, llama_split_mode, GGML_TYPE_BF16, GGML_TYPE_F16, GGML_TYPE_F32, GGML_TYPE_IQ4_NL, GGML_TYPE_Q4_0, GGML_TYPE_Q4_1, GGML_TYPE_Q5_0, GGML_TYPE_Q5_1, GGML_TYPE_Q8_0};
+use llama_cpp_sys_2::{ggml_backend_dev_t, ggml_backend_device_register, ggml_backend_reg_by_name, ggml_backend_reg_get_proc_address, llama_rope_scaling_type, llama_split_mode, LLAMA_ROPE_SCALING_TYPE_LINEAR, LLAMA_ROPE_SCALING_TYPE_LONGROPE, LLAMA_ROPE_SCALING_TYPE_NONE, LLAMA_ROPE_SCALING_TYPE_YARN, LLAMA_SPLIT_MODE_LAYER, LLAMA_SPLIT_MODE_NONE, LLAMA_SPLIT_MODE_ROW, GGML_TYPE_BF16, GGML_TYPE_F16, GGML_TYPE_F32, GGML_TYPE_IQ4_NL, GGML_TYPE_Q4_0, GGML_TYPE_Q4_1, GGML_TYPE_Q5_0, GGML_TYPE_Q5_1, GGML_TYPE_Q8_0};
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
-use std::ffi::CString;
+use log4rs::encode::writer::simple::SimpleWriter;
+use log4rs::encode::Encode;
+use crate::api::LoadingState;
+use crate::infer::RequestControl;
+use crate::metadata::ModelFamily;
+use crate::metrics::{Metrics, TokenBudget};
+use crate::request_log::RequestLog;
+use crate::telemetry::SamplingTelemetry;
+use crate::sampler::SamplerParams;
+use crate::soft_prompt::SoftPrompt;
+use std::ffi::{c_void, CString};
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
+use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
 
 mod api;
 mod infer;
@@ -26,27 +44,106 @@ mod radixtrie_kv_cache;
 #[allow(unused)]
 mod metadata;
 mod ngran_cache;
+mod checksum;
+mod config;
+mod affinity;
+mod request_log;
+mod telemetry;
+mod quality;
+mod metrics;
+mod soft_prompt;
+#[cfg(feature = "grpc")]
+mod grpc;
+
+#[derive(Clone, Copy)]
+enum CompletionsEvent {
+    // the inference loop took the task off the queue, generation timings start here
+    Started(Instant),
+    Token(LlamaToken),
+    // prompt tokens restored from the prefix cache, sent before the first token
+    PromptCached(u32),
+    // number of tokens injected into the sequence after the last generated token
+    Injected(u32),
+    // the token was sampled above the confidence threshold, the sequence ends after it
+    Confident(LlamaToken),
+    // the sequence reached max_tokens or the end of its kv cache, the last token was sent before it
+    Length,
+    // log probability of a prompt token given the tokens before it, sent in prompt order from the second token on
+    PromptLogprob(f32),
+    // the sequence was dropped by the inference loop before it finished, the reason is in the server log
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum MaxTokens {
+    Finite(u32),
+    // generate until the kv cache of the task is full, max_tokens = -1 or omitted
+    FillContext,
+}
+
+impl MaxTokens {
+    // position the sequence ends at, at least one token is generated
+    fn end_pos(self, prompt_len: u32, kv_cache_size_pre_task: u32) -> u32 {
+        match self {
+            MaxTokens::Finite(n_tokens) => n_tokens.saturating_add(prompt_len).min(kv_cache_size_pre_task),
+            MaxTokens::FillContext => prompt_len + kv_cache_size_pre_task.saturating_sub(prompt_len).max(1),
+        }
+    }
+}
+
+impl From<Option<u32>> for MaxTokens {
+    fn from(max_tokens: Option<u32>) -> Self {
+        max_tokens.map(MaxTokens::Finite).unwrap_or(MaxTokens::FillContext)
+    }
+}
 
 struct CompletionsTask {
-    to_api: flume::Sender<LlamaToken>,
+    to_api: flume::Sender<CompletionsEvent>,
     input_token_list: Vec<LlamaToken>,
-    frequency_penalty: Option<f32>,
-    presence_penalty: Option<f32>,
-    seed: Option<i64>,
-    temperature: Option<f32>,
-    top_p: Option<f32>,
-    maximum_tokens: Option<u32>
+    sampler_params: SamplerParams,
+    maximum_tokens: MaxTokens,
+    soft_prompt: Option<Arc<SoftPrompt>>,
+    // tokens appended to the sequence mid generation, at most one injection is taken per generated token
+    injections: Option<flume::Receiver<Vec<LlamaToken>>>,
+    // leading prompt tokens up to the last assistant turn of a chat, the prefix cache looks them up by hash
+    history_len: Option<usize>,
+    // echoed in the X-Request-Id response header, prefixes the log lines of the task
+    request_id: String,
+    // set for streaming requests, lets the api pause and resume the generation
+    control: Option<Arc<RequestControl>>,
+    // only evaluates the prompt and reports the log probability of every prompt token, nothing is generated
+    prompt_logprobs: bool,
+    // the target model decodes the sequence on its own even if a draft model is loaded
+    disable_speculative: bool,
+    // set when the task is built, right before it is queued, for the queue wait metric
+    enqueued_at: Instant,
+    // end the sequence like an end-of-generation token, the token itself isn't sent
+    stop_token_ids: HashSet<u32>,
 }
 
 struct EmbeddingTask {
-    to_api: flume::Sender<Vec<f32>>,
+    // (index of the input in the request, embedding)
+    to_api: flume::Sender<(usize, Vec<f32>)>,
+    index: usize,
     input_token_list: Vec<LlamaToken>
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, ValueEnum)]
 enum SplitMode {
-    Layer = 1,
-    Row = 2
+    // keep the whole model on the main gpu
+    None = LLAMA_SPLIT_MODE_NONE as isize,
+    Layer = LLAMA_SPLIT_MODE_LAYER as isize,
+    Row = LLAMA_SPLIT_MODE_ROW as isize,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, ValueEnum)]
+enum RopeScalingType {
+    None = LLAMA_ROPE_SCALING_TYPE_NONE as isize,
+    // positions are divided by 1 / --rope-freq-scale
+    Linear = LLAMA_ROPE_SCALING_TYPE_LINEAR as isize,
+    Yarn = LLAMA_ROPE_SCALING_TYPE_YARN as isize,
+    // the rescaling factors come from the model file
+    Longrope = LLAMA_ROPE_SCALING_TYPE_LONGROPE as isize,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, ValueEnum)]
@@ -63,15 +160,76 @@ enum KVCacheTypes {
     Q5_1 = GGML_TYPE_Q5_1 as isize,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, ValueEnum)]
+enum TemplateFormat {
+    // --template, or the template of the model file
+    Jinja2,
+    #[value(name = "chatml")]
+    ChatML,
+    Llama2,
+    Alpaca,
+    // same as jinja2, --template is handed to the template engine as is
+    Raw,
+}
+
+const CHATML_TEMPLATE: &str = "\
+{%- for message in messages -%}\
+{{ '<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>\\n' }}\
+{%- endfor -%}\
+{%- if add_generation_prompt -%}{{ '<|im_start|>assistant\\n' }}{%- endif -%}";
+
+// the bos token of the first turn is added by the tokenizer
+const LLAMA2_TEMPLATE: &str = "\
+{%- if messages[0]['role'] == 'system' -%}\
+{%- set system = '<<SYS>>\\n' + messages[0]['content'] + '\\n<</SYS>>\\n\\n' -%}\
+{%- set loop_messages = messages[1:] -%}\
+{%- else -%}\
+{%- set system = '' -%}\
+{%- set loop_messages = messages -%}\
+{%- endif -%}\
+{%- for message in loop_messages -%}\
+{%- if message['role'] == 'user' -%}\
+{%- if loop.first -%}{{ '[INST] ' + system + message['content'] + ' [/INST]' }}\
+{%- else -%}{{ bos_token + '[INST] ' + message['content'] + ' [/INST]' }}{%- endif -%}\
+{%- elif message['role'] == 'assistant' -%}{{ ' ' + message['content'] + ' ' + eos_token }}\
+{%- endif -%}\
+{%- endfor -%}";
+
+const ALPACA_TEMPLATE: &str = "\
+{%- for message in messages -%}\
+{%- if message['role'] == 'system' -%}{{ message['content'] + '\\n\\n' }}\
+{%- elif message['role'] == 'user' -%}{{ '### Instruction:\\n' + message['content'] + '\\n\\n' }}\
+{%- elif message['role'] == 'assistant' -%}{{ '### Response:\\n' + message['content'] + eos_token + '\\n\\n' }}\
+{%- endif -%}\
+{%- endfor -%}\
+{%- if add_generation_prompt -%}{{ '### Response:\\n' }}{%- endif -%}";
+
+impl TemplateFormat {
+    // a named format defines the template, --template is only read by jinja2 and raw
+    fn builtin_template(self) -> Option<&'static str> {
+        match self {
+            TemplateFormat::Jinja2 | TemplateFormat::Raw => None,
+            TemplateFormat::ChatML => Some(CHATML_TEMPLATE),
+            TemplateFormat::Llama2 => Some(LLAMA2_TEMPLATE),
+            TemplateFormat::Alpaca => Some(ALPACA_TEMPLATE),
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(version)]
 struct Args {
+    /// Can be repeated, e.g. to listen on both 0.0.0.0 and [::]
     #[arg(short, long, default_value = "0.0.0.0:30021")]
-    bind_addr: SocketAddr,
+    bind_addr: Vec<SocketAddr>,
 
     #[arg(short, long)]
     model_path: PathBuf,
 
+    /// Verify the SHA-256 checksum of the model file before loading it
+    #[arg(long)]
+    model_checksum: Option<String>,
+
     #[arg(long)]
     model_main_gpu: Option<i32>,
 
@@ -93,6 +251,11 @@ struct Args {
     #[arg(long)]
     rpc_servers: Option<String>,
 
+    /// Soft prompt as <name>=<path> of a float32 npy tensor [n_virtual_tokens, n_embd], can be repeated.
+    /// Requests select one with soft_prompt_id
+    #[arg(long)]
+    soft_prompt: Vec<String>,
+
     #[arg(long)]
     model_name: String,
 
@@ -102,15 +265,36 @@ struct Args {
     #[arg(short, long, default_value_t = 512)]
     kv_cache_size_pre_task: u32,
 
+    /// Caps the context of every request, kv_cache_size_pre_task included
+    #[arg(long)]
+    context_size_limit: Option<u32>,
+
+    /// Tokens of the context size limit kept free for generation, longer prompts are rejected
+    #[arg(long, default_value_t = 16)]
+    context_safety_margin: u32,
+
     #[arg(short, long)]
     template: Option<String>,
 
+    /// Chat template syntax, the named formats don't need --template
+    #[arg(long, value_enum, default_value_t = TemplateFormat::Jinja2)]
+    template_format: TemplateFormat,
+
     #[arg(long, default_value_t = 8)]
     max_unconfirmed_tokens: usize,
 
     #[arg(long, default_value_t = 16)]
     n_candidates: usize,
 
+    /// Draft a tree with this many alternatives for the next token instead of a single sequence,
+    /// the target verifies all branches in one batch
+    #[arg(long)]
+    draft_tree_width: Option<usize>,
+
+    /// Tokens per branch of the draft tree
+    #[arg(long, default_value_t = 4)]
+    draft_tree_depth: usize,
+
     #[arg(long, default_value_t = false)]
     disable_offload_kqv: bool,
 
@@ -127,7 +311,258 @@ struct Args {
     draft_type_v: Option<KVCacheTypes>,
 
     #[arg(long, default_value_t = false)]
-    embedding: bool
+    embedding: bool,
+
+    /// Maximum inputs of an embedding request, inputs that fit into the kv cache are embedded in a single decode
+    #[arg(long, default_value_t = 64)]
+    max_embedding_batch_size: usize,
+
+    /// Tasks waiting for an inference slot, requests beyond it are rejected with 503. 0 disables the limit
+    #[arg(long, default_value_t = 1024)]
+    task_queue_capacity: usize,
+
+    /// Append one json line per api request to this file
+    #[arg(long)]
+    request_log_path: Option<PathBuf>,
+
+    /// Include the request and non-streaming response bodies in the request log, they may contain PII
+    #[arg(long, requires = "request_log_path")]
+    request_log_full_body: bool,
+
+    /// Append the top 10 token probabilities after all sampler stages and the selected token to this file as json lines,
+    /// for one of every --telemetry-sample-rate sampled tokens
+    #[arg(long)]
+    sampling_telemetry_path: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 100, requires = "sampling_telemetry_path")]
+    telemetry_sample_rate: u64,
+
+    /// Log the metadata of every api request and its response at info level, the prompt isn't included
+    #[arg(long)]
+    log_requests: bool,
+
+    /// Also log the prompt, messages or input of every request, they may contain PII
+    #[arg(long, requires = "log_requests")]
+    log_request_content: bool,
+
+    /// Reject api requests with 503 once this many are in flight, streaming requests count until their stream ends
+    #[arg(long)]
+    max_concurrent_requests: Option<usize>,
+
+    /// Don't fill in model family defaults the model file is missing
+    #[arg(long)]
+    no_auto_defaults: bool,
+
+    /// Serve the admin api (POST /admin/set-parallel-tasks) on this address, it has no authentication
+    /// and is not served on --bind-addr
+    #[arg(long)]
+    admin_bind_addr: Option<SocketAddr>,
+
+    /// Serve the gRPC api on this address as well
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_bind_addr: Option<SocketAddr>,
+
+    /// Generated tokens allowed within a sliding 60 minute window across all requests.
+    /// New requests get an X-Budget-Warning header from 80% on and are rejected at 100%
+    #[arg(long)]
+    token_budget_per_hour: Option<u64>,
+
+    /// Status of the requests rejected by an exhausted token budget, 402 or 503
+    #[arg(long, default_value_t = 503)]
+    budget_exhausted_code: u16,
+
+    /// Number of prompt states kept in the prefix cache, 0 disables prefix caching
+    #[arg(long, default_value_t = 64)]
+    prefix_cache_slots: usize,
+
+    /// Keep only the last <w> tokens per sequence in the kv cache of the sliding window attention layers.
+    /// The window of the model file takes precedence, the prefix cache is disabled
+    #[arg(long)]
+    sliding_window: Option<u32>,
+
+    /// Retries of a decode call that failed with a transient backend error
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Minimum interval between two queries of the gpu memory usage exposed in the metrics
+    #[arg(long, default_value_t = 5)]
+    metrics_vram_refresh_secs: u64,
+
+    /// Stack size of the runtime and inference threads, llama.cpp graph building can be deep for large contexts
+    #[arg(long, default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    thread_stack_size_mb: usize,
+
+    /// Fraction of the kv cache size pre task a chat prompt may fill before its oldest messages are summarized, 0 disables
+    #[arg(long, default_value_t = 0.9)]
+    auto_compact_threshold: f32,
+
+    /// Prompt used to summarize compacted messages, {conversation} is replaced with the messages
+    #[arg(long)]
+    compaction_prompt_template: Option<String>,
+
+    /// Content of a chat message with type "fim", {prefix} and {suffix} come from the message,
+    /// {fim_prefix}, {fim_suffix} and {fim_middle} are the FIM tokens of the model
+    #[arg(long)]
+    fim_template: Option<String>,
+
+    /// System message forced on every chat request and prepended to every completion prompt, @<path> reads it from a file
+    #[arg(long)]
+    system_prompt: Option<String>,
+
+    /// Refuse chat requests that bring their own system message instead of placing it after --system-prompt
+    #[arg(long)]
+    system_prompt_strict: bool,
+
+    /// Prompt run once before the api listener opens, so kernel compilation and allocations don't delay the first request
+    #[arg(long)]
+    warmup_prompt: Option<String>,
+
+    /// Maximum tokens generated by the warm-up prompt
+    #[arg(long, default_value_t = 16)]
+    warmup_max_tokens: u32,
+
+    #[arg(long, default_value_t = 120)]
+    warmup_timeout_secs: u64,
+
+    /// Generate from a fixed prompt with greedy sampling before the api listener opens and log the sha-256 of the output,
+    /// the server exits if it differs from --self-test-expected-hash
+    #[arg(long, conflicts_with = "embedding")]
+    self_test: bool,
+
+    #[arg(long, requires = "self_test")]
+    self_test_expected_hash: Option<String>,
+
+    #[arg(long, default_value_t = 32)]
+    self_test_max_tokens: u32,
+
+    /// Interval of the SSE heartbeat comments sent while waiting for the next token
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    sse_heartbeat_secs: u64,
+
+    /// Maximum generation time of a non-streaming request, the tokens generated so far are returned with finish_reason "timeout"
+    #[arg(long)]
+    non_streaming_response_timeout_secs: Option<u64>,
+
+    /// Send the body of a non-streaming /v1/completions response with chunked transfer encoding while it's generated,
+    /// the X-Time-To-First-Token-Ms and X-Total-Generation-Time-Ms headers are left out
+    #[arg(long)]
+    chunked_responses: bool,
+
+    /// Number of non-streaming responses kept for repeated requests with a seed and temperature 0 or top_k 1,
+    /// a hit skips the inference queue and has an X-Cache: HIT header, 0 disables the cache
+    #[arg(long, default_value_t = 0)]
+    response_cache_size: usize,
+
+    /// Highest max_tokens_per_second a streaming request may ask for
+    #[arg(long)]
+    max_tokens_per_second: Option<f32>,
+
+    /// RoPE scaling strategy, defaults to the one of the model file, or yarn if any --yarn-* argument is set
+    #[arg(long)]
+    rope_scaling_type: Option<RopeScalingType>,
+
+    /// RoPE base frequency, defaults to the one of the model file
+    #[arg(long)]
+    rope_freq_base: Option<f32>,
+
+    /// RoPE frequency scaling factor, linear scaling stretches the trained context by 1 / <scale>
+    #[arg(long)]
+    rope_freq_scale: Option<f32>,
+
+    #[arg(long)]
+    yarn_ext_factor: Option<f32>,
+
+    #[arg(long)]
+    yarn_attn_factor: Option<f32>,
+
+    #[arg(long)]
+    yarn_beta_fast: Option<f32>,
+
+    #[arg(long)]
+    yarn_beta_slow: Option<f32>,
+
+    #[arg(long)]
+    yarn_orig_ctx: Option<u32>,
+
+    /// Threads used for prompt evaluation (n_threads_batch), defaults to llama.cpp's default.
+    /// Prefill is compute bound and scales up to the number of physical cores, hyperthreads rarely help
+    #[arg(long)]
+    n_threads_prefill: Option<i32>,
+
+    /// Threads used for token generation (n_threads), defaults to llama.cpp's default.
+    /// Generation is usually memory bandwidth bound, fewer threads than physical cores can be faster
+    #[arg(long)]
+    n_threads_generation: Option<i32>,
+
+    /// Threads used for token generation of the draft model, defaults to --n-threads-generation
+    #[arg(long)]
+    draft_n_threads: Option<i32>,
+
+    /// Threads used for prompt evaluation of the draft model, defaults to --n-threads-prefill
+    #[arg(long)]
+    draft_n_threads_batch: Option<i32>,
+
+    /// Pin the inference threads to the cpus of a hex bitmask, bit N is cpu N, Linux only
+    #[arg(long, conflicts_with = "cpu_affinity_range")]
+    cpu_affinity_mask: Option<String>,
+
+    /// Pin the inference threads to an inclusive cpu range, e.g. 0-7, Linux only
+    #[arg(long)]
+    cpu_affinity_range: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compute the SHA-256 checksum of a model file
+    Checksum {
+        path: PathBuf
+    },
+    /// Export or check the server configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the resolved server configuration as JSON, e.g. `config dump -- -m model.gguf`
+    Dump {
+        /// Server arguments
+        #[arg(last = true)]
+        args: Vec<String>
+    },
+    /// Check that a configuration printed by `config dump` is valid without starting the server
+    Validate {
+        path: PathBuf
+    }
+}
+
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command
+}
+
+// PatternEncoder doesn't reject invalid patterns, it renders the parse errors into every line instead
+fn pattern_encoder(pattern: &str) -> Option<PatternEncoder> {
+    let encoder = PatternEncoder::new(pattern);
+    let mut out = SimpleWriter(Vec::new());
+
+    let record = log::Record::builder()
+        .args(format_args!("test"))
+        .level(log::Level::Info)
+        .target("hibiki")
+        .build();
+
+    encoder.encode(&mut out, &record).ok()?;
+
+    if String::from_utf8_lossy(&out.0).contains("{ERROR") {
+        return None;
+    }
+    Some(encoder)
 }
 
 fn logger_init() -> Result<()> {
@@ -135,14 +570,29 @@ fn logger_init() -> Result<()> {
         std::env::var("HIBIKI_LOG").as_deref().unwrap_or("INFO"),
     )?;
 
-    let pattern = if log_level >= LevelFilter::Debug {
+    let default_pattern = if log_level >= LevelFilter::Debug {
         "[{d(%Y-%m-%d %H:%M:%S)}] {h({l})} {f}:{L} - {m}{n}"
     } else {
         "[{d(%Y-%m-%d %H:%M:%S)}] {h({l})} {t} - {m}{n}"
     };
 
+    let encoder: Box<dyn Encode> = match std::env::var("HIBIKI_LOG_FORMAT") {
+        Ok(format) if format == "json" => Box::new(JsonEncoder::new()),
+        Ok(format) => {
+            match pattern_encoder(&format) {
+                Some(encoder) => Box::new(encoder),
+                None => {
+                    // the logger isn't initialized yet
+                    eprintln!("invalid HIBIKI_LOG_FORMAT pattern \"{}\", falling back to the default", format);
+                    Box::new(PatternEncoder::new(default_pattern))
+                }
+            }
+        }
+        Err(_) => Box::new(PatternEncoder::new(default_pattern)),
+    };
+
     let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(pattern)))
+        .encoder(encoder)
         .build();
 
     let config = log4rs::Config::builder()
@@ -192,13 +642,421 @@ fn add_rpc_devices(servers: &str) -> Result<()> {
     Ok(())
 }
 
-fn exec(args: Args) -> Result<()> {
+fn yarn_enabled(args: &Args) -> bool {
+    args.rope_scaling_type == Some(RopeScalingType::Yarn) || yarn_args_set(args)
+}
+
+fn yarn_args_set(args: &Args) -> bool {
+    args.yarn_ext_factor.is_some() ||
+        args.yarn_attn_factor.is_some() ||
+        args.yarn_beta_fast.is_some() ||
+        args.yarn_beta_slow.is_some() ||
+        args.yarn_orig_ctx.is_some()
+}
+
+fn context_params(args: &Args) -> LlamaContextParams {
+    let mut ctx_params = LlamaContextParams::default();
+
+    if yarn_enabled(args) {
+        ctx_params.context_params.rope_scaling_type = LLAMA_ROPE_SCALING_TYPE_YARN;
+    }
+
+    // the --yarn-* arguments only come with yarn, checked at startup
+    if let Some(t) = args.rope_scaling_type {
+        ctx_params.context_params.rope_scaling_type = t as llama_rope_scaling_type;
+    }
+
+    // each field is only set when given, a linear scaling with only a new base keeps the scale of the model file
+    if let Some(v) = args.rope_freq_base {
+        ctx_params.context_params.rope_freq_base = v;
+    }
+
+    if let Some(v) = args.rope_freq_scale {
+        ctx_params.context_params.rope_freq_scale = v;
+    }
+
+    if let Some(v) = args.yarn_ext_factor {
+        ctx_params.context_params.yarn_ext_factor = v;
+    }
+
+    if let Some(v) = args.yarn_attn_factor {
+        ctx_params.context_params.yarn_attn_factor = v;
+    }
+
+    if let Some(v) = args.yarn_beta_fast {
+        ctx_params.context_params.yarn_beta_fast = v;
+    }
+
+    if let Some(v) = args.yarn_beta_slow {
+        ctx_params.context_params.yarn_beta_slow = v;
+    }
+
+    if let Some(v) = args.yarn_orig_ctx {
+        ctx_params.context_params.yarn_orig_ctx = v;
+    }
+
+    if let Some(v) = args.n_threads_prefill {
+        ctx_params = ctx_params.with_n_threads_batch(v);
+    }
+
+    if let Some(v) = args.n_threads_generation {
+        ctx_params = ctx_params.with_n_threads(v);
+    }
+
+    ctx_params
+}
+
+fn check_rope_scaling(args: &Args, n_ctx_train: u32) {
+    let kv = args.kv_cache_size_pre_task;
+
+    let scaling = match args.rope_scaling_type {
+        Some(RopeScalingType::None) => false,
+        Some(_) => true,
+        None => yarn_enabled(args) || args.rope_freq_scale.is_some(),
+    };
+
+    if kv > n_ctx_train && !scaling {
+        warn!(
+            "kv cache size pre task {} exceeds the trained context length {}, consider enabling YaRN with --yarn-orig-ctx",
+            kv,
+            n_ctx_train
+        );
+    }
+
+    if kv <= n_ctx_train && args.rope_scaling_type.is_some_and(|t| t != RopeScalingType::None) {
+        warn!(
+            "kv cache size pre task {} fits the trained context length {}, rope scaling only costs quality",
+            kv,
+            n_ctx_train
+        );
+    }
+
+    // linear scaling covers n_ctx_train / scale positions
+    if let (Some(RopeScalingType::Linear), Some(scale)) = (args.rope_scaling_type, args.rope_freq_scale) {
+        if kv as f32 * scale > n_ctx_train as f32 {
+            warn!(
+                "linear rope scaling with --rope-freq-scale {} covers {} positions, less than the kv cache size pre task {}",
+                scale,
+                (n_ctx_train as f32 / scale) as u32,
+                kv
+            );
+        }
+    }
+}
+
+// only fills in what neither the model file nor the command line sets
+fn apply_family_defaults(model: &LlamaModel, arch: &str, args: &Args, ctx_params: &mut LlamaContextParams) {
+    let family = match metadata::model_family(model, arch) {
+        Some(family) => family,
+        None => return,
+    };
+    debug!("detected model family {:?}", family);
+
+    match family {
+        ModelFamily::Llama3 => {
+            let key = format!("{}.rope.freq_base", arch);
+
+            if metadata::get_metadata_str(model, &key).is_none() && !yarn_enabled(args) && args.rope_freq_base.is_none() {
+                ctx_params.context_params.rope_freq_base = 500000.0;
+                debug!("{:?} default rope_freq_base = 500000, the model file has no {}", family, key);
+            }
+        }
+        // a model hparam llama.cpp reads from the file, no context parameter can set it
+        ModelFamily::Mistral => {
+            let key = format!("{}.attention.sliding_window", arch);
+
+            if metadata::get_metadata_str(model, &key).is_none() {
+                debug!("{:?} default sliding_window = 4096 not applied, it can only come from {} of the model file", family, key);
+            }
+        }
+        // prompts are always tokenized with the bos token
+        ModelFamily::Gemma => debug!("{:?} default add_bos = true", family),
+    }
+}
+
+// changes whenever a cached prompt state could have been computed differently, the model file is identified by
+// its checksum if given, its size and modification time otherwise
+fn prefix_cache_version(args: &Args, ctx_params: &LlamaContextParams) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+
+    for path in std::iter::once(&args.model_path).chain(args.draft_model_path.as_ref()) {
+        let meta = std::fs::metadata(path)?;
+        path.hash(&mut hasher);
+        meta.len().hash(&mut hasher);
+        meta.modified()?.hash(&mut hasher);
+    }
+
+    args.model_checksum.hash(&mut hasher);
+    args.kv_cache_size_pre_task.hash(&mut hasher);
+
+    for t in [args.type_k, args.type_v, args.draft_type_k, args.draft_type_v] {
+        t.map(|t| t as isize).hash(&mut hasher);
+    }
+
+    let p = &ctx_params.context_params;
+    p.rope_scaling_type.hash(&mut hasher);
+    p.swa_full.hash(&mut hasher);
+
+    for v in [p.rope_freq_base, p.rope_freq_scale, p.yarn_ext_factor, p.yarn_attn_factor, p.yarn_beta_fast, p.yarn_beta_slow] {
+        v.to_bits().hash(&mut hasher);
+    }
+    p.yarn_orig_ctx.hash(&mut hasher);
+
+    Ok(hasher.finish())
+}
+
+// the draft model is smaller, fewer threads leave more cores to the main model
+fn draft_context_params(args: &Args, ctx_params: &LlamaContextParams) -> LlamaContextParams {
+    let mut draft_ctx_params = ctx_params.clone();
+
+    if let Some(v) = args.draft_n_threads_batch {
+        draft_ctx_params = draft_ctx_params.with_n_threads_batch(v);
+    }
+
+    if let Some(v) = args.draft_n_threads {
+        draft_ctx_params = draft_ctx_params.with_n_threads(v);
+    }
+
+    draft_ctx_params
+}
+
+struct LoadingProgress<'a> {
+    tx: &'a watch::Sender<LoadingState>,
+    draft: bool,
+}
+
+unsafe extern "C" fn loading_progress_callback(progress: f32, user_data: *mut c_void) -> bool {
+    let loading = &*(user_data as *const LoadingProgress);
+
+    let state = if loading.draft {
+        LoadingState::LoadingDraftTensors { progress }
+    } else {
+        LoadingState::LoadingTensors { progress }
+    };
+
+    loading.tx.send_replace(state);
+    true
+}
+
+const VOCAB_SAMPLE_TOKENS: i32 = 16;
+
+// draft tokens are fed into the main model directly, so both models must share the same token ids
+fn check_vocab_compatibility(model: &LlamaModel, draft_model: &LlamaModel) -> Result<()> {
+    let n_vocab = model.n_vocab();
+    let draft_n_vocab = draft_model.n_vocab();
+
+    if n_vocab != draft_n_vocab {
+        return Err(anyhow!(
+            "Draft model vocabulary size {} differs from main model {}; speculative decoding requires identical vocabularies.",
+            draft_n_vocab,
+            n_vocab
+        ));
+    }
+
+    let mut sample_tokens = vec![model.token_bos(), model.token_eos()];
+    sample_tokens.extend((0..VOCAB_SAMPLE_TOKENS).map(|i| LlamaToken(i * (n_vocab / VOCAB_SAMPLE_TOKENS))));
+
+    for token in sample_tokens {
+        let text = model.token_to_bytes(token, Special::Tokenize)?;
+        let draft_text = draft_model.token_to_bytes(token, Special::Tokenize)?;
+
+        if text != draft_text {
+            return Err(anyhow!(
+                "Token {} decodes to {:?} in the main model but {:?} in the draft model; speculative decoding requires identical vocabularies.",
+                token.0,
+                String::from_utf8_lossy(&text),
+                String::from_utf8_lossy(&draft_text)
+            ));
+        }
+    }
+    Ok(())
+}
+
+// a bind failure ends the startup, the http api doesn't run without the grpc api it was configured with
+#[cfg(feature = "grpc")]
+fn spawn_grpc(
+    bind_addr: SocketAddr,
+    model: Arc<LlamaModel>,
+    kv_cache_size_pre_task: u32,
+    backend: grpc::Backend,
+    metrics: Arc<Metrics>,
+    parallel_tasks: Arc<AtomicU32>,
+    serving: watch::Receiver<bool>,
+) -> Result<()> {
+    let serve = grpc::bind(bind_addr, model, kv_cache_size_pre_task, backend, metrics, parallel_tasks, serving)?;
+
+    tokio::spawn(async move {
+        if let Err(e) = serve.await {
+            error!("grpc server error: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+fn parse_tensor_split(split: &str, model_path: &Path) -> Result<Vec<f32>> {
+    let mut split_list = Vec::new();
+    for x in split.split(",") {
+        split_list.push(f32::from_str(x.trim())?);
+    }
+
+    let gpus = metrics::query_gpu_memory();
+
+    // llama.cpp reads one value per device, a shorter list would be read out of bounds
+    if split_list.len() != gpus.len() {
+        error!(
+            "tensor split {} has {} values but {} gpus are available, missing values are 0 and extra values are ignored",
+            split,
+            split_list.len(),
+            gpus.len()
+        );
+        split_list.resize(gpus.len(), 0.0);
+    }
+
+    ensure!(split_list.iter().all(|x| *x >= 0.0), "tensor split values must not be negative");
+
+    let sum: f32 = split_list.iter().sum();
+
+    if !(0.99..=1.01).contains(&sum) {
+        warn!("tensor split {} sums to {}, values are normalized by llama.cpp", split, sum);
+    }
+
+    for (i, x) in split_list.iter().enumerate() {
+        if *x == 0.0 {
+            warn!("tensor split assigns no layers to gpu {}", i);
+        }
+    }
+
+    // the weights dominate the memory usage, kv cache and compute buffers come on top
+    let model_size = std::fs::metadata(model_path)?.len();
+
+    if sum > 0.0 {
+        for (i, (x, gpu)) in split_list.iter().zip(gpus.iter()).enumerate() {
+            let required = (model_size as f64 * (*x / sum) as f64) as u64;
+
+            if required > gpu.free as u64 {
+                warn!(
+                    "gpu {} needs about {}MB for its share of the model but only has {}MB free, consider redistributing the tensor split",
+                    i,
+                    required / 1024 / 1024,
+                    gpu.free / 1024 / 1024
+                );
+            }
+        }
+    }
+    Ok(split_list)
+}
+
+#[cfg(unix)]
+fn check_stack_rlimit(stack_size: usize) {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_STACK, &mut limit) } != 0 {
+        return;
+    }
+
+    // threads spawned with an explicit stack size ignore the limit, the main thread doesn't
+    if limit.rlim_cur != libc::RLIM_INFINITY &&
+        (limit.rlim_cur as usize) < stack_size &&
+        limit.rlim_max != libc::RLIM_INFINITY &&
+        (limit.rlim_max as usize) < stack_size {
+        warn!(
+            "stack rlimit {}MB is smaller than the requested thread stack size {}MB and can't be raised",
+            limit.rlim_cur / 1024 / 1024,
+            stack_size / 1024 / 1024
+        );
+    }
+}
+
+fn task_queue<T>(capacity: usize) -> (flume::Sender<T>, flume::Receiver<T>) {
+    if capacity == 0 {
+        warn!("task queue is unbounded, backpressure is disabled");
+        flume::unbounded()
+    } else {
+        flume::bounded(capacity)
+    }
+}
+
+// inference already runs while the warm-up is waited for, the listener only opens after it
+async fn warmup_then<T>(
+    warmup: Option<impl Future<Output = Result<()>>>,
+    timeout: Duration,
+    serving: &watch::Sender<bool>,
+    api: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    if let Some(warmup) = warmup {
+        tokio::time::timeout(timeout, warmup).await
+            .map_err(|_| anyhow!("warm-up did not finish within {:?}", timeout))??;
+    }
+
+    serving.send_replace(true);
+    let res = api.await;
+    serving.send_replace(false);
+    res
+}
+
+fn dedup_bind_addrs(bind_addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut out = Vec::with_capacity(bind_addrs.len());
+
+    for addr in bind_addrs {
+        if out.contains(addr) {
+            warn!("duplicate bind address {} ignored", addr);
+            continue;
+        }
+        out.push(*addr);
+    }
+    out
+}
+
+fn exec(mut args: Args) -> Result<()> {
     logger_init()?;
+    args.bind_addr = dedup_bind_addrs(&args.bind_addr);
+
+    // the admin api is unauthenticated, it must not end up on a public address
+    ensure!(
+        !args.admin_bind_addr.is_some_and(|addr| args.bind_addr.contains(&addr)),
+        "--admin-bind-addr must differ from --bind-addr"
+    );
+
+    // the other strategies would silently drop the yarn parameters
+    ensure!(
+        !yarn_args_set(&args) || matches!(args.rope_scaling_type, None | Some(RopeScalingType::Yarn)),
+        "--yarn-* arguments can only be combined with --rope-scaling-type yarn"
+    );
+
+    if let Some(limit) = args.context_size_limit {
+        ensure!(limit > args.context_safety_margin, "--context-size-limit must be greater than --context-safety-margin");
+
+        if args.kv_cache_size_pre_task > limit {
+            info!("kv cache size pre task {} is capped to the context size limit {}", args.kv_cache_size_pre_task, limit);
+            args.kv_cache_size_pre_task = limit;
+        }
+    }
+    let context_limit = args.context_size_limit.map(|size| api::ContextLimit {
+        size,
+        safety_margin: args.context_safety_margin,
+    });
+
+    if let Some(checksum) = &args.model_checksum {
+        checksum::verify(&args.model_path, checksum)?;
+    }
+
+    let thread_stack_size = args.thread_stack_size_mb * 1024 * 1024;
+
+    #[cfg(unix)]
+    check_stack_rlimit(thread_stack_size);
+
+    // inference runs on the blocking pool, which uses the same stack size
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(thread_stack_size)
+        .build()?;
 
-    let rt = tokio::runtime::Runtime::new()?;
+    let (loading_tx, loading_rx) = watch::channel(LoadingState::LoadingTensors { progress: 0.0 });
+    let (progress_shutdown_tx, progress_shutdown_rx) = oneshot::channel();
+    let progress_server = rt.spawn(api::run_loading_progress(args.bind_addr.clone(), loading_rx.clone(), progress_shutdown_rx));
 
-    if let Some(rpc_servers) = args.rpc_servers {
-        add_rpc_devices(&rpc_servers)?;
+    if let Some(rpc_servers) = &args.rpc_servers {
+        add_rpc_devices(rpc_servers)?;
     }
 
     let backend = llama_backend::LlamaBackend::init()?;
@@ -215,18 +1073,109 @@ fn exec(args: Args) -> Result<()> {
         model_params.params.split_mode = split_mode as llama_split_mode;
     }
 
-    if let Some(split) = &args.model_tensor_split_rate {
-        let mut split_list = Vec::new();
-        for x in split.split(",") {
-            split_list.push(f32::from_str(x)?);
-        }
+    // must outlive the model loading, llama.cpp only keeps the pointer
+    let model_tensor_split = match &args.model_tensor_split_rate {
+        Some(split) => Some(parse_tensor_split(split, &args.model_path)?),
+        None => None,
+    };
+
+    if let Some(split_list) = &model_tensor_split {
         model_params.params.tensor_split = split_list.as_ptr();
     }
 
+    let loading = LoadingProgress { tx: &loading_tx, draft: false };
+    model_params.params.progress_callback = Some(loading_progress_callback);
+    model_params.params.progress_callback_user_data = &loading as *const LoadingProgress as *mut c_void;
+
     let model = LlamaModel::load_from_file(&backend, &args.model_path, &model_params)?;
     let model = Arc::new(model);
 
-    let draft_model = if let Some(draft_model_path) = args.draft_model_path {
+    check_rope_scaling(&args, model.n_ctx_train());
+
+    let arch = metadata::get_metadata_raw(&model, "general.architecture");
+
+    // llama.cpp has no context parameter for ALiBi, the slopes come from the model hparams
+    if metadata::uses_alibi(&model, &arch) {
+        info!("model architecture {} uses ALiBi position biases", arch);
+        ensure!(
+            !yarn_enabled(&args) && args.rope_scaling_type.is_none() && args.rope_freq_base.is_none() && args.rope_freq_scale.is_none(),
+            "rope scaling arguments (--rope-scaling-type, --rope-freq-base, --rope-freq-scale, --yarn-*) can't be combined with an ALiBi model"
+        );
+    }
+    let has_kv_cache = !metadata::is_recurrent_architecture(&arch);
+
+    if !has_kv_cache {
+        warn!(
+            "model architecture {} uses a recurrent state instead of a KV cache, prefix caching and speculative decoding are disabled",
+            arch
+        );
+    }
+
+    let cpu_affinity = match (&args.cpu_affinity_mask, &args.cpu_affinity_range) {
+        (Some(mask), _) => Some(affinity::parse_mask(mask)?),
+        (None, Some(range)) => Some(affinity::parse_range(range)?),
+        (None, None) => None,
+    };
+
+    if cpu_affinity.is_some() && !cfg!(target_os = "linux") {
+        warn!("cpu affinity is only supported on Linux, ignored");
+    }
+
+    let mut ctx_params = context_params(&args);
+
+    if !args.no_auto_defaults {
+        apply_family_defaults(&model, &arch, &args, &mut ctx_params);
+    }
+
+    let mut prefix_cache_slots = args.prefix_cache_slots;
+
+    if let Some(window) = args.sliding_window {
+        // the window is a model hparam, the flag only bounds the cache of the layers using it
+        match metadata::sliding_window(&model, &arch) {
+            None => warn!("model architecture {} has no sliding window attention, --sliding-window is ignored", arch),
+            Some(model_window) => {
+                if model_window != window {
+                    warn!("--sliding-window {} differs from the window {} of the model file, using {}", window, model_window, model_window);
+                }
+
+                ensure!(
+                    args.kv_cache_size_pre_task >= model_window,
+                    "kv cache size pre task {} must be at least the sliding window {}",
+                    args.kv_cache_size_pre_task,
+                    model_window
+                );
+
+                ctx_params.context_params.swa_full = false;
+                info!("kv cache of the sliding window layers keeps the last {} tokens per sequence", model_window);
+
+                // tokens that slid out of the window can't be restored from a prompt state
+                if prefix_cache_slots > 0 {
+                    warn!("prefix caching is disabled with --sliding-window");
+                    prefix_cache_slots = 0;
+                }
+            }
+        }
+    }
+
+    let cache_version = prefix_cache_version(&args, &ctx_params)?;
+    info!("prefix cache version {:016x}", cache_version);
+
+    let draft_ctx_params = draft_context_params(&args, &ctx_params);
+    let soft_prompts = soft_prompt::load_all(&args.soft_prompt)?;
+
+    // the draft model can't see the embeddings the target was conditioned on
+    ensure!(
+        soft_prompts.is_empty() || args.draft_model_path.is_none(),
+        "soft prompts are not supported with speculative decoding"
+    );
+
+    let draft_model_path = if has_kv_cache {
+        args.draft_model_path
+    } else {
+        None
+    };
+
+    let draft_model = if let Some(draft_model_path) = draft_model_path {
         let mut draft_model_params = LlamaModelParams::default()
             .with_n_gpu_layers(u32::MAX);
 
@@ -238,71 +1187,287 @@ fn exec(args: Args) -> Result<()> {
             draft_model_params.params.split_mode = split_mode as llama_split_mode;
         }
 
-        if let Some(split) = &args.draft_model_tensor_split_rate {
-            let mut split_list = Vec::new();
-            for x in split.split(",") {
-                split_list.push(f32::from_str(x)?);
-            }
+        let draft_model_tensor_split = match &args.draft_model_tensor_split_rate {
+            Some(split) => Some(parse_tensor_split(split, &draft_model_path)?),
+            None => None,
+        };
+
+        if let Some(split_list) = &draft_model_tensor_split {
             draft_model_params.params.tensor_split = split_list.as_ptr();
         }
 
+        let loading = LoadingProgress { tx: &loading_tx, draft: true };
+        draft_model_params.params.progress_callback = Some(loading_progress_callback);
+        draft_model_params.params.progress_callback_user_data = &loading as *const LoadingProgress as *mut c_void;
+
         let draft_model = LlamaModel::load_from_file(&backend, &draft_model_path, &draft_model_params)?;
+        check_vocab_compatibility(&model, &draft_model)?;
         Some(Arc::new(draft_model))
     } else {
         None
     };
 
+    loading_tx.send_replace(LoadingState::Ready);
+    let _ = progress_shutdown_tx.send(());
+    rt.block_on(progress_server)??;
+
+    let parallel_tasks = Arc::new(AtomicU32::new(args.parallel_tasks));
+    ensure!(matches!(args.budget_exhausted_code, 402 | 503), "--budget-exhausted-code must be 402 or 503");
+    ensure!(
+        !args.max_tokens_per_second.is_some_and(|v| v.is_nan() || v < api::MIN_TOKENS_PER_SECOND),
+        "--max-tokens-per-second must be at least {}",
+        api::MIN_TOKENS_PER_SECOND
+    );
+    ensure!(args.telemetry_sample_rate > 0, "--telemetry-sample-rate must be greater than 0");
+    let token_budget = args.token_budget_per_hour.map(|per_hour| TokenBudget::new(per_hour, args.budget_exhausted_code));
+
+    let sampling_telemetry = match &args.sampling_telemetry_path {
+        Some(path) => Some(rt.block_on(SamplingTelemetry::open(path, args.model_name.clone(), args.telemetry_sample_rate))?),
+        None => None,
+    };
+
+    let metrics = Arc::new(Metrics::new(
+        args.model_name.clone(),
+        Duration::from_secs(args.metrics_vram_refresh_secs),
+        token_budget,
+        sampling_telemetry,
+    ));
+    let warmup_timeout = Duration::from_secs(args.warmup_timeout_secs);
+    let log_requests = args.log_requests.then_some(api::LogRequests { content: args.log_request_content });
+    // readiness of the grpc health service, set once the api accepts requests
+    let serving = watch::Sender::new(false);
+
     rt.block_on(async {
+        let request_log = match &args.request_log_path {
+            Some(path) => Some(Arc::new(RequestLog::open(path, args.request_log_full_body).await?)),
+            None => None,
+        };
+
         if args.embedding {
-            let (tx, rx) = flume::bounded(1024);
+            // a batch packs its inputs into sequences of their own, next to one per parallel task
+            let n_seq_max = (args.parallel_tasks as usize).max(args.max_embedding_batch_size);
+
+            ensure!(
+                n_seq_max <= infer::LLAMA_MAX_SEQ as usize,
+                "--max-embedding-batch-size {} with --parallel-tasks {} needs {} sequences, llama.cpp supports at most {}",
+                args.max_embedding_batch_size,
+                args.parallel_tasks,
+                n_seq_max,
+                infer::LLAMA_MAX_SEQ
+            );
+
+            let (tx, rx) = task_queue(args.task_queue_capacity);
+
+            #[cfg(feature = "grpc")]
+            if let Some(grpc_bind_addr) = args.grpc_bind_addr {
+                spawn_grpc(
+                    grpc_bind_addr,
+                    model.clone(),
+                    args.kv_cache_size_pre_task,
+                    grpc::Backend::Embedding(tx.clone()),
+                    metrics.clone(),
+                    parallel_tasks.clone(),
+                    serving.subscribe(),
+                )?;
+            }
 
             let infer_handle = infer::run_embedding(
                 model.clone(),
                 backend,
+                ctx_params,
                 rx,
                 args.kv_cache_size_pre_task,
                 args.parallel_tasks,
+                args.max_embedding_batch_size,
                 !args.disable_offload_kqv,
                 args.type_k,
                 args.type_v,
+                args.max_retries,
+                metrics.clone(),
+                parallel_tasks.clone(),
+                cpu_affinity,
             );
 
-            let api_handle = api::run_embedding(
+            let warmup = args.warmup_prompt.clone().map(|prompt| {
+                api::warmup_embedding(model.clone(), tx.clone(), args.kv_cache_size_pre_task, prompt)
+            });
+
+            let api_handle = warmup_then(warmup, warmup_timeout, &serving, api::run_embedding(
                 args.bind_addr,
                 model,
                 args.model_name,
                 args.kv_cache_size_pre_task,
+                context_limit,
+                args.max_embedding_batch_size,
                 tx,
-            );
+                loading_rx,
+                metrics,
+                parallel_tasks,
+                request_log,
+                log_requests,
+                args.max_concurrent_requests,
+                args.admin_bind_addr,
+            ));
 
             tokio::try_join!(infer_handle, api_handle)?;
         } else {
-            let (tx, rx) = flume::bounded(1024);
+            let (tx, rx) = task_queue(args.task_queue_capacity);
+
+            #[cfg(feature = "grpc")]
+            if let Some(grpc_bind_addr) = args.grpc_bind_addr {
+                spawn_grpc(
+                    grpc_bind_addr,
+                    model.clone(),
+                    args.kv_cache_size_pre_task,
+                    grpc::Backend::Completions(tx.clone()),
+                    metrics.clone(),
+                    parallel_tasks.clone(),
+                    serving.subscribe(),
+                )?;
+            }
+
+            let draft_tree = match args.draft_tree_width {
+                None => None,
+                Some(width) => {
+                    ensure!(width > 0 && args.draft_tree_depth > 0, "draft tree width and depth must be greater than 0");
+                    Some(infer::DraftTree { width, depth: args.draft_tree_depth })
+                }
+            };
+
+            let speculative = draft_model.is_some();
+
+            // the branches of a draft tree only get sequences with a draft model
+            let seq_tree = draft_tree.filter(|_| draft_model.is_some());
+            let n_seq_max = infer::n_seq_max(args.parallel_tasks, seq_tree);
+
+            ensure!(
+                n_seq_max <= infer::LLAMA_MAX_SEQ,
+                "--parallel-tasks {} with a draft tree width of {} needs {} sequences, llama.cpp supports at most {}",
+                args.parallel_tasks,
+                seq_tree.map(|tree| tree.width).unwrap_or(0),
+                n_seq_max,
+                infer::LLAMA_MAX_SEQ
+            );
 
             let infer_handle = infer::run_completions(
                 model.clone(),
                 draft_model,
                 backend,
+                ctx_params,
+                draft_ctx_params,
                 rx,
                 args.kv_cache_size_pre_task,
                 args.parallel_tasks,
                 args.max_unconfirmed_tokens,
                 args.n_candidates,
+                draft_tree,
                 !args.disable_offload_kqv,
                 args.type_k,
                 args.type_v,
                 args.draft_type_k,
-                args.draft_type_v
+                args.draft_type_v,
+                has_kv_cache,
+                prefix_cache_slots,
+                cache_version,
+                args.max_retries,
+                metrics.clone(),
+                parallel_tasks.clone(),
+                cpu_affinity,
+            );
+
+            let compaction = if args.auto_compact_threshold > 0.0 {
+                let prompt_template = args.compaction_prompt_template
+                    .unwrap_or_else(|| api::DEFAULT_COMPACTION_PROMPT_TEMPLATE.to_string());
+
+                ensure!(prompt_template.contains("{conversation}"), "compaction prompt template must contain {{conversation}}");
+
+                Some(api::Compaction {
+                    threshold: args.auto_compact_threshold,
+                    prompt_template,
+                })
+            } else {
+                None
+            };
+
+            let template = match args.template_format.builtin_template() {
+                None => args.template,
+                Some(builtin) => {
+                    ensure!(args.template.is_none(), "--template can't be combined with a named --template-format");
+                    Some(builtin.to_string())
+                }
+            };
+
+            let fim_template = args.fim_template
+                .unwrap_or_else(|| api::DEFAULT_FIM_TEMPLATE.to_string());
+
+            ensure!(
+                fim_template.contains("{prefix}") && fim_template.contains("{suffix}"),
+                "fim template must contain {{prefix}} and {{suffix}}"
             );
 
-            let api_handle = api::run_completions(
+            ensure!(args.system_prompt.is_some() || !args.system_prompt_strict, "--system-prompt-strict requires --system-prompt");
+
+            let system_prompt = match &args.system_prompt {
+                None => None,
+                Some(arg) => {
+                    let content = match arg.strip_prefix('@') {
+                        Some(path) => std::fs::read_to_string(path).map_err(|e| anyhow!("read system prompt {} failed: {}", path, e))?,
+                        None => arg.clone(),
+                    };
+
+                    Some(api::SystemPrompt {
+                        content,
+                        strict: args.system_prompt_strict,
+                    })
+                }
+            };
+
+            let self_test = args.self_test.then(|| {
+                api::self_test_completions(model.clone(), tx.clone(), args.self_test_max_tokens, args.self_test_expected_hash.clone())
+            });
+
+            let warmup = args.warmup_prompt.clone().map(|prompt| {
+                api::warmup_completions(model.clone(), tx.clone(), args.kv_cache_size_pre_task, prompt, args.warmup_max_tokens)
+            });
+
+            // the self-test runs first, a failed one ends the server before the warm-up
+            let warmup = (self_test.is_some() || warmup.is_some()).then(|| async move {
+                if let Some(self_test) = self_test {
+                    self_test.await?;
+                }
+
+                if let Some(warmup) = warmup {
+                    warmup.await?;
+                }
+                Ok(())
+            });
+
+            let api_handle = warmup_then(warmup, warmup_timeout, &serving, api::run_completions(
                 args.bind_addr,
                 model,
                 args.model_name,
                 args.kv_cache_size_pre_task,
                 tx,
-                args.template
-            );
+                template,
+                Duration::from_secs(args.sse_heartbeat_secs),
+                args.non_streaming_response_timeout_secs.map(Duration::from_secs),
+                args.chunked_responses,
+                speculative,
+                args.response_cache_size,
+                args.max_tokens_per_second,
+                context_limit,
+                soft_prompts,
+                compaction,
+                fim_template,
+                system_prompt,
+                loading_rx,
+                metrics,
+                parallel_tasks,
+                request_log,
+                log_requests,
+                args.max_concurrent_requests,
+                args.admin_bind_addr,
+            ));
 
             tokio::try_join!(infer_handle, api_handle)?;
         }
@@ -311,10 +1476,45 @@ fn exec(args: Args) -> Result<()> {
     })
 }
 
+fn exec_command(command: Command) -> Result<()> {
+    logger_init()?;
+
+    match command {
+        Command::Checksum { path } => {
+            let checksum = checksum::sha256_file(&path)?;
+            println!("{}  {}", checksum, path.display());
+        }
+        Command::Config { command: ConfigCommand::Dump { args } } => {
+            let mut command = Args::command();
+            let matches = command.try_get_matches_from_mut(std::iter::once(String::from("hibiki")).chain(args))?;
+            println!("{}", serde_json::to_string_pretty(&config::dump(&command, &matches))?);
+        }
+        Command::Config { command: ConfigCommand::Validate { path } } => {
+            let content = std::fs::read_to_string(&path)?;
+            let config: serde_json::Value = serde_json::from_str(&content)?;
+            let args = config::to_cli_args(&config)?;
+
+            Args::try_parse_from(std::iter::once(String::from("hibiki")).chain(args))?;
+            println!("{}: ok", path.display());
+        }
+    }
+    Ok(())
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
+    // the server arguments are top-level, subcommands are only recognized as the first argument
+    let is_subcommand = std::env::args()
+        .nth(1)
+        .map(|arg| Cli::command().find_subcommand(arg).is_some())
+        .unwrap_or(false);
+
+    let res = if is_subcommand {
+        exec_command(Cli::parse().command)
+    } else {
+        exec(Args::parse())
+    };
 
-    match exec(args) {
+    match res {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             eprintln!("{:?}", e);
@@ -1,5 +1,6 @@
 use std::cmp::min;
 use std::ffi::{c_char, CStr, CString};
+use std::ptr::null_mut;
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::token::LlamaToken;
@@ -74,6 +75,163 @@ pub fn get_metadata_raw(model: &LlamaModel, key: &str) -> String {
     }
 }
 
+// Retrieves the full value of a metadata string, unlike `get_metadata_raw` this works for long values such as the chat template
+pub fn get_metadata_str(model: &LlamaModel, key: &str) -> Option<String> {
+    let c_key = CString::new(key).ok()?;
+
+    let len = unsafe {
+        llama_cpp_sys_2::llama_model_meta_val_str(
+            model.as_ptr(),
+            c_key.as_ptr(),
+            null_mut(),
+            0,
+        )
+    };
+
+    let len = usize::try_from(len).ok()?;
+    let mut val = vec![0u8; len + 1];
+
+    unsafe {
+        llama_cpp_sys_2::llama_model_meta_val_str(
+            model.as_ptr(),
+            c_key.as_ptr(),
+            val.as_mut_ptr() as *mut c_char,
+            val.len(),
+        )
+    };
+
+    CStr::from_bytes_until_nul(&val)
+        .ok()
+        .map(|val| val.to_string_lossy().to_string())
+}
+
+// Architectures that keep a recurrent state instead of a KV cache
+const RECURRENT_ARCHITECTURES: [&str; 4] = ["mamba", "rwkv6", "rwkv6qwen2", "rwkv7"];
+
+pub fn is_recurrent_architecture(arch: &str) -> bool {
+    RECURRENT_ARCHITECTURES.contains(&arch)
+}
+
+// llama_ftype values stored in general.file_type
+fn file_type_name(file_type: u32) -> Option<&'static str> {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn file_type(model: &LlamaModel) -> Option<u32> {
+    get_metadata_str(model, "general.file_type")?.parse().ok()
+}
+
+// the name used in gguf file names, e.g. "Q4_K_M"
+pub fn quantization_name(model: &LlamaModel) -> Option<&'static str> {
+    file_type_name(file_type(model)?)
+}
+
+// e.g. "Q4_K_M (imatrix)", the tensor types themselves aren't exposed by llama.h so this relies on general.file_type
+pub fn quantization_summary(model: &LlamaModel) -> String {
+    let mut summary = match file_type(model) {
+        Some(file_type) => file_type_name(file_type)
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("unknown ({})", file_type)),
+        None => String::from("unknown"),
+    };
+
+    if get_metadata_str(model, "quantize.imatrix.file").is_some() {
+        summary.push_str(" (imatrix)");
+    }
+    summary
+}
+
+pub fn quantization_version(model: &LlamaModel) -> Option<u32> {
+    get_metadata_str(model, "general.quantization_version")?.parse().ok()
+}
+
+// average over all weights, so mixed quantizations such as Q4_K_M land between their tensor types
+pub fn bits_per_weight(model: &LlamaModel) -> f32 {
+    let (size, n_params) = unsafe {
+        (
+            llama_cpp_sys_2::llama_model_size(model.as_ptr()),
+            llama_cpp_sys_2::llama_model_n_params(model.as_ptr()),
+        )
+    };
+
+    if n_params == 0 {
+        return 0.0;
+    }
+    (size as f64 * 8.0 / n_params as f64) as f32
+}
+
+const ALIBI_ARCHITECTURES: [&str; 2] = ["mpt", "bloom"];
+
+pub fn uses_alibi(model: &LlamaModel, arch: &str) -> bool {
+    let max_alibi_bias = get_metadata_str(model, &format!("{}.attention.max_alibi_bias", arch))
+        .and_then(|v| v.parse::<f32>().ok())
+        .unwrap_or(0.0);
+
+    max_alibi_bias > 0.0 || ALIBI_ARCHITECTURES.contains(&arch)
+}
+
+// window of the sliding window attention layers, None for a model attending to the whole context
+pub fn sliding_window(model: &LlamaModel, arch: &str) -> Option<u32> {
+    get_metadata_str(model, &format!("{}.attention.sliding_window", arch))
+        .and_then(|v| v.parse::<u32>().ok())
+        .filter(|&v| v > 0)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ModelFamily {
+    Llama3,
+    Mistral,
+    Gemma,
+}
+
+// general.name tells llama 3 apart from older llama models sharing the architecture
+pub fn model_family(model: &LlamaModel, arch: &str) -> Option<ModelFamily> {
+    let name = get_metadata_str(model, "general.name").unwrap_or_default().to_lowercase();
+
+    let family = match arch {
+        "llama" if name.contains("llama-3") || name.contains("llama 3") => ModelFamily::Llama3,
+        "llama" if name.contains("mistral") => ModelFamily::Mistral,
+        "gemma" | "gemma2" | "gemma3" => ModelFamily::Gemma,
+        _ => return None,
+    };
+    Some(family)
+}
+
 pub struct ModelMetadata {
     /// The size of this model's vocabulary, in tokens.
     pub vocabulary_size: usize,
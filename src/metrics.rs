@@ -0,0 +1,471 @@
+use crate::sampler::Sampler;
+use crate::telemetry::SamplingTelemetry;
+use llama_cpp_2::token::LlamaToken;
+use llama_cpp_sys_2::{ggml_backend_dev_count, ggml_backend_dev_get, ggml_backend_dev_memory, ggml_backend_dev_type, GGML_BACKEND_DEVICE_TYPE_GPU};
+use std::collections::VecDeque;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(60);
+// time constant of the tokens per second moving average
+const THROUGHPUT_EMA_TAU: Duration = Duration::from_secs(10);
+
+struct Throughput {
+    // (finished at, generated tokens) of the requests completed within the window
+    window: VecDeque<(Instant, u64)>,
+    ema: f64,
+    last_read: Option<Instant>,
+}
+
+impl Throughput {
+    fn prune(&mut self, now: Instant) {
+        while let Some((t, _)) = self.window.front() {
+            if now.duration_since(*t) <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.window.pop_front();
+        }
+    }
+
+    // pruned here as well, the window would grow without bound if the metrics are never read
+    fn record(&mut self, now: Instant, generated_tokens: u64) {
+        self.prune(now);
+        self.window.push_back((now, generated_tokens));
+    }
+
+    fn tokens_per_second(&mut self, now: Instant) -> f64 {
+        self.prune(now);
+
+        let window_rate = self.window.iter().map(|(_, n)| *n).sum::<u64>() as f64 / THROUGHPUT_WINDOW.as_secs_f64();
+
+        self.ema = match self.last_read {
+            None => window_rate,
+            Some(last_read) => {
+                let dt = now.duration_since(last_read).as_secs_f64();
+                let alpha = 1.0 - (-dt / THROUGHPUT_EMA_TAU.as_secs_f64()).exp();
+                self.ema + alpha * (window_rate - self.ema)
+            }
+        };
+        self.last_read = Some(now);
+        self.ema
+    }
+}
+
+const BUDGET_WINDOW: Duration = Duration::from_secs(3600);
+const BUDGET_BUCKET: Duration = Duration::from_secs(60);
+
+// generated tokens of the last hour, summed per minute so the window stays small under load
+pub struct TokenBudget {
+    pub per_hour: u64,
+    // status of the requests rejected once the budget is used up, 402 or 503
+    pub exhausted_status: u16,
+    // (bucket start, generated tokens)
+    buckets: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl TokenBudget {
+    pub fn new(per_hour: u64, exhausted_status: u16) -> Self {
+        TokenBudget {
+            per_hour,
+            exhausted_status,
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record(&self, tokens: u64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        match buckets.back_mut() {
+            Some((start, n)) if now.duration_since(*start) < BUDGET_BUCKET => *n += tokens,
+            _ => buckets.push_back((now, tokens)),
+        }
+    }
+
+    // until enough of the oldest buckets leave the window to get under the budget again
+    pub fn retry_after(&self) -> Duration {
+        let now = Instant::now();
+        let buckets = self.buckets.lock().unwrap();
+        let mut used = buckets.iter()
+            .filter(|(start, _)| now.duration_since(*start) <= BUDGET_WINDOW)
+            .map(|(_, n)| *n)
+            .sum::<u64>();
+
+        for (start, n) in buckets.iter().filter(|(start, _)| now.duration_since(*start) <= BUDGET_WINDOW) {
+            used -= n;
+
+            if used < self.per_hour {
+                return (*start + BUDGET_WINDOW).saturating_duration_since(now);
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn used(&self) -> u64 {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        while let Some((start, _)) = buckets.front() {
+            if now.duration_since(*start) <= BUDGET_WINDOW {
+                break;
+            }
+            buckets.pop_front();
+        }
+        buckets.iter().map(|(_, n)| *n).sum()
+    }
+}
+
+// upper bounds in seconds, from 50ms to 10s
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    // observations per bucket, not cumulative, the last one is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let idx = LATENCY_BUCKETS.iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut count = 0;
+
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+
+            let le = LATENCY_BUCKETS.get(i).map(|le| le.to_string()).unwrap_or_else(|| String::from("+Inf"));
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+pub struct GpuMemory {
+    pub free: usize,
+    pub total: usize,
+}
+
+// empty when no gpu backend device is available
+pub fn query_gpu_memory() -> Vec<GpuMemory> {
+    let mut list = Vec::new();
+
+    unsafe {
+        for i in 0..ggml_backend_dev_count() {
+            let dev = ggml_backend_dev_get(i);
+
+            if ggml_backend_dev_type(dev) != GGML_BACKEND_DEVICE_TYPE_GPU {
+                continue;
+            }
+
+            let mut free = 0;
+            let mut total = 0;
+            ggml_backend_dev_memory(dev, &mut free, &mut total);
+            list.push(GpuMemory { free, total });
+        }
+    }
+    list
+}
+
+pub struct Metrics {
+    model_name: String,
+    vram_refresh: Duration,
+    vram: Mutex<Option<(Instant, Vec<GpuMemory>)>>,
+    pub decode_retries: AtomicU64,
+    pub queue_dropped: AtomicU64,
+    pub concurrent_requests: AtomicUsize,
+    pub kv_cache_prefill_tokens: AtomicU64,
+    pub kv_cache_hit_tokens: AtomicU64,
+    pub speculative_fallback: AtomicU64,
+    tokens_generated: AtomicU64,
+    prompt_tokens: AtomicU64,
+    throughput: Mutex<Throughput>,
+    queue_wait: Histogram,
+    // api requests, streams included, for the retry estimate of rejected requests
+    request_duration_micros: AtomicU64,
+    requests_finished: AtomicU64,
+    pub token_budget: Option<TokenBudget>,
+    sampling_telemetry: Option<SamplingTelemetry>,
+}
+
+fn write_metric(out: &mut String, name: &str, labels: &str, help: &str, metric_type: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_metric(out, name, "", help, "counter", value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: impl Display) {
+    write_metric(out, name, "", help, "gauge", value);
+}
+
+impl Metrics {
+    pub fn new(
+        model_name: String,
+        vram_refresh: Duration,
+        token_budget: Option<TokenBudget>,
+        sampling_telemetry: Option<SamplingTelemetry>,
+    ) -> Self {
+        Metrics {
+            model_name,
+            vram_refresh,
+            vram: Mutex::new(None),
+            decode_retries: AtomicU64::new(0),
+            queue_dropped: AtomicU64::new(0),
+            concurrent_requests: AtomicUsize::new(0),
+            kv_cache_prefill_tokens: AtomicU64::new(0),
+            kv_cache_hit_tokens: AtomicU64::new(0),
+            speculative_fallback: AtomicU64::new(0),
+            tokens_generated: AtomicU64::new(0),
+            prompt_tokens: AtomicU64::new(0),
+            throughput: Mutex::new(Throughput {
+                window: VecDeque::new(),
+                ema: 0.0,
+                last_read: None,
+            }),
+            queue_wait: Histogram::new(),
+            request_duration_micros: AtomicU64::new(0),
+            requests_finished: AtomicU64::new(0),
+            token_budget,
+            sampling_telemetry,
+        }
+    }
+
+    // every token sampled by the target model, for --sampling-telemetry-path
+    pub fn record_sample(&self, request_id: &str, sampler: &Sampler, token: LlamaToken) {
+        if let Some(telemetry) = &self.sampling_telemetry {
+            telemetry.record(request_id, sampler, token);
+        }
+    }
+
+    // only called for requests that ran to completion, cancelled ones are not counted
+    pub fn record_completion(&self, prompt_tokens: u64, generated_tokens: u64) {
+        self.prompt_tokens.fetch_add(prompt_tokens, Ordering::Relaxed);
+        self.tokens_generated.fetch_add(generated_tokens, Ordering::Relaxed);
+        self.throughput.lock().unwrap().record(Instant::now(), generated_tokens);
+
+        if let Some(budget) = &self.token_budget {
+            budget.record(generated_tokens);
+        }
+    }
+
+    // time a completions task spent in the inference queue before the inference loop took it
+    pub fn record_queue_wait(&self, wait: Duration) {
+        self.queue_wait.observe(wait);
+    }
+
+    pub fn record_request_duration(&self, duration: Duration) {
+        self.request_duration_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.requests_finished.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // seconds until one of `waiting` requests may run with `parallel_tasks` slots, rounded up,
+    // 1 before any request has finished
+    pub fn retry_after(&self, waiting: usize, parallel_tasks: u32) -> u64 {
+        let finished = self.requests_finished.load(Ordering::Relaxed);
+
+        if finished == 0 {
+            return 1;
+        }
+
+        let mean = self.request_duration_micros.load(Ordering::Relaxed) as f64 / finished as f64 / 1_000_000.0;
+        let secs = mean * (waiting + 1) as f64 / parallel_tasks.max(1) as f64;
+        (secs.ceil() as u64).max(1)
+    }
+
+    // prometheus text exposition format
+    pub fn render(&self, queue_depth: usize, queue_capacity: usize, parallel_tasks: u32) -> String {
+        let mut out = String::new();
+
+        write_counter(
+            &mut out,
+            "hibiki_decode_retries_total",
+            "Number of decode calls retried after a transient backend error",
+            self.decode_retries.load(Ordering::Relaxed),
+        );
+
+        write_gauge(
+            &mut out,
+            "hibiki_queue_depth",
+            "Number of tasks waiting in the inference queue",
+            queue_depth as u64,
+        );
+
+        write_gauge(
+            &mut out,
+            "hibiki_queue_capacity",
+            "Capacity of the inference queue, 0 if unbounded",
+            queue_capacity as u64,
+        );
+
+        write_gauge(
+            &mut out,
+            "hibiki_concurrent_requests",
+            "Number of api requests in flight, streaming requests count until their stream ends",
+            self.concurrent_requests.load(Ordering::Relaxed),
+        );
+
+        write_gauge(
+            &mut out,
+            "hibiki_parallel_tasks",
+            "Number of tasks the inference loop may run at once",
+            parallel_tasks,
+        );
+
+        self.queue_wait.render(
+            &mut out,
+            "hibiki_queue_wait_seconds",
+            "Time completions tasks waited in the inference queue, a high p95 calls for more --parallel-tasks",
+        );
+
+        write_counter(
+            &mut out,
+            "hibiki_queue_dropped_total",
+            "Number of requests rejected because the inference queue was full",
+            self.queue_dropped.load(Ordering::Relaxed),
+        );
+
+        let prefill_tokens = self.kv_cache_prefill_tokens.load(Ordering::Relaxed);
+        let hit_tokens = self.kv_cache_hit_tokens.load(Ordering::Relaxed);
+
+        write_counter(
+            &mut out,
+            "hibiki_kv_cache_prefill_tokens_total",
+            "Number of prompt tokens submitted for prefill",
+            prefill_tokens,
+        );
+
+        write_counter(
+            &mut out,
+            "hibiki_kv_cache_hit_tokens_total",
+            "Number of prompt tokens restored from the prefix cache",
+            hit_tokens,
+        );
+
+        // stays 0 when the prefix cache is disabled
+        let hit_rate = if prefill_tokens == 0 {
+            0.0
+        } else {
+            hit_tokens as f64 / prefill_tokens as f64
+        };
+
+        write_gauge(
+            &mut out,
+            "hibiki_kv_cache_hit_rate",
+            "Fraction of prompt tokens restored from the prefix cache",
+            hit_rate,
+        );
+
+        if let Some(budget) = &self.token_budget {
+            write_gauge(
+                &mut out,
+                "hibiki_token_budget_used",
+                "Number of tokens generated within the last hour, out of --token-budget-per-hour",
+                budget.used(),
+            );
+        }
+
+        write_counter(
+            &mut out,
+            "hibiki_speculative_fallback_total",
+            "Number of sequences that fell back to non-speculative decoding after a draft model error",
+            self.speculative_fallback.load(Ordering::Relaxed),
+        );
+
+        let model_label = format!("{{model=\"{}\"}}", escape_label_value(&self.model_name));
+        let tokens_per_second = self.throughput.lock().unwrap().tokens_per_second(Instant::now());
+
+        write_metric(
+            &mut out,
+            "hibiki_tokens_per_second",
+            &model_label,
+            "Moving average of generated tokens per second over the last 60 seconds",
+            "gauge",
+            tokens_per_second,
+        );
+
+        write_metric(
+            &mut out,
+            "hibiki_tokens_generated_total",
+            &model_label,
+            "Number of tokens generated by completed requests",
+            "counter",
+            self.tokens_generated.load(Ordering::Relaxed),
+        );
+
+        write_metric(
+            &mut out,
+            "hibiki_prompt_tokens_total",
+            &model_label,
+            "Number of prompt tokens of completed requests",
+            "counter",
+            self.prompt_tokens.load(Ordering::Relaxed),
+        );
+
+        self.render_vram(&mut out);
+        out
+    }
+
+    fn render_vram(&self, out: &mut String) {
+        let mut vram = self.vram.lock().unwrap();
+        let now = Instant::now();
+
+        let stale = match &*vram {
+            None => true,
+            Some((t, _)) => now.duration_since(*t) >= self.vram_refresh,
+        };
+
+        if stale {
+            *vram = Some((now, query_gpu_memory()));
+        }
+
+        let (_, gpus) = vram.as_ref().unwrap();
+
+        if gpus.is_empty() {
+            return;
+        }
+
+        let name = "hibiki_gpu_vram_used_bytes";
+        let _ = writeln!(out, "# HELP {} Device memory in use on the gpu", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (i, gpu) in gpus.iter().enumerate() {
+            let _ = writeln!(out, "{}{{gpu=\"{}\"}} {}", name, i, gpu.total - gpu.free);
+        }
+
+        let name = "hibiki_gpu_vram_free_bytes";
+        let _ = writeln!(out, "# HELP {} Free device memory on the gpu", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (i, gpu) in gpus.iter().enumerate() {
+            let _ = writeln!(out, "{}{{gpu=\"{}\"}} {}", name, i, gpu.free);
+        }
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,41 @@
+use llama_cpp_2::token::LlamaToken;
+use std::collections::HashSet;
+
+// share of the n-grams that already appeared earlier in the tokens
+fn repetition_ratio(tokens: &[i32], n: usize) -> f32 {
+    if tokens.len() < n {
+        return 0.0;
+    }
+
+    let mut seen = HashSet::new();
+    let mut repeated = 0;
+    let total = tokens.len() - n + 1;
+
+    for ngram in tokens.windows(n) {
+        if !seen.insert(ngram) {
+            repeated += 1;
+        }
+    }
+    repeated as f32 / total as f32
+}
+
+// higher is better, 1.0 for tokens without any repeated 3-gram or 4-gram
+pub fn score(tokens: &[LlamaToken]) -> f32 {
+    let tokens = tokens.iter().map(|t| t.0).collect::<Vec<_>>();
+    1.0 - (repetition_ratio(&tokens, 3) + repetition_ratio(&tokens, 4)) / 2.0
+}
+
+// index of the best candidate, the first one wins a tie
+pub fn best_candidate<'a>(candidates: impl IntoIterator<Item = &'a [LlamaToken]>) -> Option<usize> {
+    let mut best: Option<(usize, f32)> = None;
+
+    for (i, tokens) in candidates.into_iter().enumerate() {
+        let score = score(tokens);
+
+        match best {
+            Some((_, best_score)) if score <= best_score => (),
+            _ => best = Some((i, score)),
+        }
+    }
+    best.map(|(i, _)| i)
+}
@@ -1,31 +1,73 @@
 use anyhow::Result;
 use chrono::Utc;
 use llama_cpp_sys_2::llama_token;
+use lru::LruCache;
 use radix_trie::{Trie, TrieCommon};
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+struct CacheEntry {
+    seq: Vec<llama_token>,
+    access_time: i64,
+    seq_data: Vec<u8>,
+    hash: u64,
+    version: u64,
+}
+
+fn token_hash(tokens: &[llama_token]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
 
 pub struct RadixTrieKVCache {
     // seq -> seq_id
     trie: Trie<Vec<llama_token>, i32>,
-    // seq_id -> (seq, access_time, seq_data)
-    seq_ids: Vec<Option<(Vec<llama_token>, AtomicI64, Vec<u8>)>>,
+    // seq_id -> entry, ordered by recency
+    entries: LruCache<i32, CacheEntry>,
+    // hash of the tokens of an entry -> seq_id, the prompt of an earlier turn is a prefix of a re-submitted chat history
+    history: HashMap<u64, i32>,
+    // token length -> number of entries of that length, the prefix lengths worth hashing
+    lengths: BTreeMap<usize, usize>,
+    capacity: usize,
+    // hash of the model and context parameters the states were computed with, entries of another version are dropped
+    version: u64,
+    // seq ids of dropped entries
+    free: Vec<i32>,
 }
 
 impl RadixTrieKVCache {
-    pub fn new(seq_len: usize) -> RadixTrieKVCache {
+    pub fn new(seq_len: usize, version: u64) -> RadixTrieKVCache {
         RadixTrieKVCache {
             trie: Trie::new(),
-            seq_ids: {
-                let mut seq_ids = Vec::with_capacity(seq_len);
-                for _ in 0..seq_len {
-                    seq_ids.push(None);
-                }
-                seq_ids
-            },
+            entries: LruCache::unbounded(),
+            history: HashMap::new(),
+            lengths: BTreeMap::new(),
+            capacity: seq_len,
+            version,
+            free: Vec::new(),
         }
     }
 
-    pub fn get(&self, seq: &[llama_token]) -> Option<(&[u8], usize)> {
+    // the trie walk is quadratic in the prompt length, the longest entry the chat history starts with is found by hash instead
+    fn get_history(&self, history: &[llama_token]) -> Option<(i32, usize)> {
+        self.lengths.range(..=history.len()).rev().find_map(|(len, _)| {
+            let prefix = &history[..*len];
+            let seq_id = *self.history.get(&token_hash(prefix))?;
+            let entry = self.entries.peek(&seq_id)?;
+
+            // a hash collision must not restore the state of another conversation
+            if entry.seq != prefix {
+                debug!("chat history hash collision");
+                return None;
+            }
+            Some((seq_id, *len))
+        })
+    }
+
+    // the walk starts after the prefix that is already known to match
+    fn get_descendant(&self, seq: &[llama_token], start: Option<(i32, usize)>) -> Option<(i32, usize)> {
         let get_descendant = |seq: &[llama_token]| {
             let seq_id = self
                 .trie
@@ -37,9 +79,9 @@ impl RadixTrieKVCache {
             Some(seq_id)
         };
 
-        let mut last = None;
+        let mut last = start;
 
-        for i in 1..seq.len() {
+        for i in start.map(|(_, len)| len + 1).unwrap_or(1)..seq.len() {
             let res = get_descendant(&seq[0..i]);
 
             if res.is_none() {
@@ -48,44 +90,141 @@ impl RadixTrieKVCache {
 
             last = res.map(|seq_id| (seq_id, i));
         }
+        last
+    }
+
+    // history_len is the number of leading tokens that belong to the chat history of the prompt
+    pub fn get(&mut self, seq: &[llama_token], history_len: Option<usize>) -> Option<(&[u8], usize)> {
+        let history = history_len
+            .filter(|len| *len > 0 && *len <= seq.len())
+            .and_then(|len| self.get_history(&seq[..len]));
 
-        let (seq_id, sub_pos) = last?;
-        let (_sub_seq, access_time, seq_data) = self.seq_ids[seq_id as usize].as_ref()?;
-        access_time.store(Utc::now().timestamp(), Ordering::Relaxed);
-        Some((seq_data.as_slice(), sub_pos))
+        let (seq_id, sub_pos) = self.get_descendant(seq, history)?;
+
+        if self.entries.peek(&seq_id)?.version != self.version {
+            debug!("prefix cache entry of another version, recompute");
+            let entry = self.entries.pop(&seq_id)?;
+            self.trie.remove(&entry.seq);
+            self.remove_history(&entry, seq_id);
+            self.free.push(seq_id);
+            return None;
+        }
+
+        // promotes the entry to most recently used
+        let entry = self.entries.get_mut(&seq_id)?;
+        entry.access_time = Utc::now().timestamp();
+        Some((entry.seq_data.as_slice(), sub_pos))
     }
 
-    pub fn insert(&mut self, tokens: Vec<llama_token>, seq_data: Vec<u8>) -> Result<i32> {
-        let mut seq_id = None;
+    // least recently used first, entries accessed within the same second give way to the shortest prefix
+    fn evict(&mut self) -> Option<i32> {
+        let oldest = self.entries.peek_lru()?.1.access_time;
 
-        for (id, x) in &mut self.seq_ids.iter_mut().enumerate() {
-            if x.is_none() {
-                seq_id = Some(id as i32);
-                break;
+        let seq_id = self
+            .entries
+            .iter()
+            .rev()
+            .take_while(|(_, entry)| entry.access_time == oldest)
+            .min_by_key(|(_, entry)| entry.seq.len())
+            .map(|(seq_id, _)| *seq_id)?;
+
+        let entry = self.entries.pop(&seq_id)?;
+        self.trie.remove(&entry.seq);
+        self.remove_history(&entry, seq_id);
+        Some(seq_id)
+    }
+
+    fn remove_history(&mut self, entry: &CacheEntry, seq_id: i32) {
+        // a colliding entry may have taken over the hash
+        if self.history.get(&entry.hash) == Some(&seq_id) {
+            self.history.remove(&entry.hash);
+        }
+
+        if let Some(n) = self.lengths.get_mut(&entry.seq.len()) {
+            *n -= 1;
+
+            if *n == 0 {
+                self.lengths.remove(&entry.seq.len());
             }
         }
+    }
 
-        let seq_id = match seq_id {
-            None => {
-                let item = self
-                    .seq_ids
-                    .iter_mut()
-                    .min_by_key(|v| v.as_ref().unwrap().1.load(Ordering::Relaxed))
-                    .unwrap();
-
-                let (seq, _, _) = item.take().unwrap();
-                let seq_id = self.trie.remove(&seq).unwrap();
-                seq_id
+    pub fn insert(&mut self, tokens: Vec<llama_token>, seq_data: Vec<u8>) -> Result<i32> {
+        let access_time = Utc::now().timestamp();
+
+        // refresh the existing entry, a second one would leave a stale seq_id behind in the trie
+        if let Some(seq_id) = self.trie.get(&tokens).copied() {
+            if let Some(entry) = self.entries.get_mut(&seq_id) {
+                entry.access_time = access_time;
+                entry.seq_data = seq_data;
+                entry.version = self.version;
+                return Ok(seq_id);
             }
-            Some(v) => v,
+        }
+
+        // seq ids are only freed by eviction and version mismatches, so while the cache is filling up they are contiguous
+        let seq_id = if let Some(seq_id) = self.free.pop() {
+            seq_id
+        } else if self.entries.len() < self.capacity {
+            self.entries.len() as i32
+        } else {
+            self.evict().ok_or_else(|| anyhow::anyhow!("prefix cache has no capacity"))?
         };
 
         self.trie.insert(tokens.clone(), seq_id);
-        self.seq_ids[seq_id as usize] = Some((
-            tokens,
-            AtomicI64::new(Utc::now().timestamp()),
-            seq_data,
-        ));
+
+        let hash = token_hash(&tokens);
+        self.history.insert(hash, seq_id);
+        *self.lengths.entry(tokens.len()).or_default() += 1;
+
+        self.entries.put(
+            seq_id,
+            CacheEntry {
+                seq: tokens,
+                access_time,
+                seq_data,
+                hash,
+                version: self.version,
+            },
+        );
         Ok(seq_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resubmitted_history_hits_previous_prompt() {
+        let mut cache = RadixTrieKVCache::new(4, 0);
+        // the first turn has no assistant message, its prompt is stored without a history
+        cache.insert(vec![1, 2, 3], vec![1]).unwrap();
+
+        // the next prompt renders the first one, the reply and a new user turn
+        let prompt = [1, 2, 3, 4, 5, 6, 7];
+        let (data, len) = cache.get(&prompt, Some(5)).unwrap();
+        assert_eq!((data, len), (&[1u8][..], 3));
+    }
+
+    #[test]
+    fn longer_trie_match_wins_over_history() {
+        let mut cache = RadixTrieKVCache::new(4, 0);
+        cache.insert(vec![1, 2, 3], vec![1]).unwrap();
+        cache.insert(vec![1, 2, 3, 4, 5, 9], vec![2]).unwrap();
+
+        let prompt = [1, 2, 3, 4, 5, 6, 7];
+        let (data, len) = cache.get(&prompt, Some(5)).unwrap();
+        assert_eq!((data, len), (&[2u8][..], 5));
+    }
+
+    #[test]
+    fn evicted_entry_leaves_no_history() {
+        let mut cache = RadixTrieKVCache::new(1, 0);
+        cache.insert(vec![1, 2, 3], vec![1]).unwrap();
+        cache.insert(vec![7, 8], vec![2]).unwrap();
+
+        assert!(cache.history.len() == 1 && !cache.lengths.contains_key(&3));
+        assert!(cache.get(&[1, 2, 3, 4], Some(3)).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+// set on a response by the handlers that know the token counts, streaming responses have none
+#[derive(Clone, Copy)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(Serialize)]
+pub struct RequestLogEntry {
+    timestamp: String,
+    request_id: Option<String>,
+    method: String,
+    path: String,
+    request_body_hash: String,
+    response_status: u16,
+    latency_ms: u64,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_body: Option<String>,
+}
+
+impl RequestLogEntry {
+    pub fn new(
+        request_id: Option<String>,
+        method: String,
+        path: String,
+        request_body: &[u8],
+        response_status: u16,
+        latency_ms: u64,
+        usage: Option<TokenUsage>,
+    ) -> Self {
+        RequestLogEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            request_id,
+            method,
+            path,
+            request_body_hash: format!("{:x}", Sha256::digest(request_body)),
+            response_status,
+            latency_ms,
+            prompt_tokens: usage.map(|u| u.prompt_tokens),
+            completion_tokens: usage.map(|u| u.completion_tokens),
+            request_body: None,
+            response_body: None,
+        }
+    }
+
+    pub fn with_bodies(mut self, request_body: &[u8], response_body: Option<&[u8]>) -> Self {
+        self.request_body = Some(String::from_utf8_lossy(request_body).to_string());
+        self.response_body = response_body.map(|body| String::from_utf8_lossy(body).to_string());
+        self
+    }
+}
+
+// one json object per line, a single writer task keeps the lines of concurrent requests apart
+pub struct RequestLog {
+    tx: flume::Sender<RequestLogEntry>,
+    // bodies may contain PII, they are only logged when asked for
+    pub full_body: bool,
+}
+
+impl RequestLog {
+    pub async fn open(path: &Path, full_body: bool) -> Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let (tx, rx) = flume::unbounded::<RequestLogEntry>();
+        let path = path.to_path_buf();
+
+        tokio::spawn(async move {
+            while let Ok(entry) = rx.recv_async().await {
+                let mut line = match serde_json::to_vec(&entry) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("serialize request log entry failed: {}", e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+
+                if let Err(e) = file.write_all(&line).await {
+                    error!("write request log {} failed: {}", path.display(), e);
+                }
+            }
+        });
+
+        Ok(RequestLog { tx, full_body })
+    }
+
+    // never blocks the request path
+    pub fn write(&self, entry: RequestLogEntry) {
+        let _ = self.tx.send(entry);
+    }
+}
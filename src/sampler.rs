@@ -1,10 +1,264 @@
+use anyhow::anyhow;
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::token::LlamaToken;
-use llama_cpp_sys_2::{llama_token_data_array, HibikiCommonSampler};
+use llama_cpp_sys_2::{llama_sampler, llama_token_data, llama_token_data_array, HibikiCommonSampler, LLAMA_DEFAULT_SEED};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{c_char, CString};
+use std::str::FromStr;
+
+// defaults of common_params_sampling, used by the stages the common sampler can't be configured with
+const DEFAULT_TOP_K: i32 = 40;
+const DEFAULT_TOP_P: f32 = 0.95;
+const DEFAULT_MIN_P: f32 = 0.05;
+const DEFAULT_TYPICAL_P: f32 = 1.0;
+const DEFAULT_TEMPERATURE: f32 = 0.8;
+const DEFAULT_PENALTY_LAST_N: i32 = 64;
+const DEFAULT_REPEAT_PENALTY: f32 = 1.0;
+const DEFAULT_DRY_MULTIPLIER: f32 = 0.0;
+const DEFAULT_DRY_BASE: f32 = 1.75;
+const DEFAULT_DRY_ALLOWED_LENGTH: i32 = 2;
+const DEFAULT_DRY_PENALTY_LAST_N: i32 = -1;
+const DEFAULT_DRY_SEQUENCE_BREAKERS: [&str; 4] = ["\n", ":", "\"", "*"];
+const DEFAULT_XTC_PROBABILITY: f32 = 0.0;
+const DEFAULT_XTC_THRESHOLD: f32 = 0.1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SamplerStage {
+    TopNSigma,
+    Penalties,
+    Dry,
+    TopK,
+    TypicalP,
+    TopP,
+    MinP,
+    TopA,
+    Xtc,
+    Temperature,
+}
+
+// same order as the common sampler, top-nσ and top-a are no-ops unless requested
+pub const DEFAULT_SAMPLER_ORDER: [SamplerStage; 10] = [
+    SamplerStage::TopNSigma,
+    SamplerStage::Penalties,
+    SamplerStage::Dry,
+    SamplerStage::TopK,
+    SamplerStage::TypicalP,
+    SamplerStage::TopP,
+    SamplerStage::MinP,
+    SamplerStage::TopA,
+    SamplerStage::Xtc,
+    SamplerStage::Temperature,
+];
+
+impl FromStr for SamplerStage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let stage = match s {
+            "top_n_sigma" => SamplerStage::TopNSigma,
+            "penalties" => SamplerStage::Penalties,
+            "dry" => SamplerStage::Dry,
+            "top_k" => SamplerStage::TopK,
+            "typical_p" => SamplerStage::TypicalP,
+            "top_p" => SamplerStage::TopP,
+            "min_p" => SamplerStage::MinP,
+            "top_a" => SamplerStage::TopA,
+            "xtc" => SamplerStage::Xtc,
+            "temperature" => SamplerStage::Temperature,
+            _ => return Err(anyhow!("unknown sampler stage: {}", s)),
+        };
+        Ok(stage)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SamplerParams {
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    // penalty_repeat and penalty_last_n of llama_sampler_init_penalties, the same sampler as the two penalties above,
+    // so a request with both sets gets all three penalties over the repeat_last_n window
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<u32>,
+    // presence penalty only for tokens within the last n, separate from the window of the frequency penalty
+    pub presence_penalty_context_window: Option<u32>,
+    // the penalty history starts over after every newline token, penalties only count within a paragraph
+    pub penalty_reset_on_newline: Option<bool>,
+    pub seed: Option<i64>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub min_p: Option<f32>,
+    pub top_a: Option<f32>,
+    pub top_n_sigma: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub dry_multiplier: Option<f32>,
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    // stops the sequence after a token sampled with a higher probability, not a sampler stage
+    pub confidence_threshold: Option<f32>,
+}
+
+impl SamplerParams {
+    // the common sampler only exposes a few parameters and a fixed order
+    fn needs_chain(&self) -> bool {
+        self.sampler_order.is_some() ||
+            self.top_k.is_some() ||
+            self.min_p.is_some() ||
+            self.top_a.is_some_and(|a| a > 0.0) ||
+            self.top_n_sigma.is_some() ||
+            self.typical_p.is_some() ||
+            self.dry_multiplier.is_some() ||
+            self.repeat_penalty.is_some() ||
+            self.repeat_last_n.is_some() ||
+            self.presence_penalty_context_window.is_some() ||
+            // the common sampler can only be reset as a whole, its rng included
+            self.penalty_reset_on_newline()
+    }
+
+    fn penalty_reset_on_newline(&self) -> bool {
+        self.penalty_reset_on_newline.unwrap_or(false)
+    }
+}
+
+// presence penalty over a sliding window of the accepted tokens
+struct PresenceWindow {
+    penalty: f32,
+    size: usize,
+    history: VecDeque<i32>,
+    // occurrences within the window
+    counts: HashMap<i32, u32>,
+}
+
+impl PresenceWindow {
+    fn new(penalty: f32, size: u32) -> Self {
+        PresenceWindow {
+            penalty,
+            size: size as usize,
+            history: VecDeque::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    unsafe fn apply(&self, cur_p: &mut llama_token_data_array) {
+        if self.penalty == 0.0 || self.counts.is_empty() {
+            return;
+        }
+
+        let candidates = std::slice::from_raw_parts_mut(cur_p.data, cur_p.size);
+
+        for td in candidates.iter_mut() {
+            if self.counts.contains_key(&td.id) {
+                td.logit -= self.penalty;
+            }
+        }
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        if self.size == 0 {
+            return;
+        }
+
+        if self.history.len() == self.size {
+            let old = self.history.pop_front().unwrap();
+
+            if let Entry::Occupied(mut count) = self.counts.entry(old) {
+                *count.get_mut() -= 1;
+
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+        }
+
+        self.history.push_back(token.0);
+        *self.counts.entry(token.0).or_insert(0) += 1;
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.counts.clear();
+    }
+}
+
+enum ChainStage {
+    Native(*mut llama_sampler),
+    TopA(f32),
+    TopNSigma(Option<f32>),
+    PresenceWindow(PresenceWindow),
+}
+
+impl ChainStage {
+    unsafe fn apply(&self, cur_p: &mut llama_token_data_array) {
+        match self {
+            ChainStage::Native(s) => llama_cpp_sys_2::llama_sampler_apply(*s, cur_p),
+            ChainStage::TopA(a) => top_a_apply(cur_p, *a),
+            ChainStage::TopNSigma(n) => top_n_sigma_apply(cur_p, *n),
+            ChainStage::PresenceWindow(w) => w.apply(cur_p),
+        }
+    }
+
+    unsafe fn accept(&mut self, token: LlamaToken) {
+        match self {
+            ChainStage::Native(s) => llama_cpp_sys_2::llama_sampler_accept(*s, token.0),
+            ChainStage::PresenceWindow(w) => w.accept(token),
+            _ => (),
+        }
+    }
+
+    unsafe fn reset(&mut self) {
+        match self {
+            ChainStage::Native(s) => llama_cpp_sys_2::llama_sampler_reset(*s),
+            ChainStage::PresenceWindow(w) => w.reset(),
+            _ => (),
+        }
+    }
+
+    unsafe fn free(&self) {
+        if let ChainStage::Native(s) = self {
+            llama_cpp_sys_2::llama_sampler_free(*s);
+        }
+    }
+}
+
+enum SamplerInner {
+    Common(*mut HibikiCommonSampler),
+    Chain {
+        chain: Vec<ChainStage>,
+        // indices of the stages that keep a penalty history
+        penalty_stages: Vec<usize>,
+        // set for penalty_reset_on_newline
+        newline_token: Option<LlamaToken>,
+        n_vocab: i32,
+        cur: Vec<llama_token_data>,
+        cur_p: llama_token_data_array,
+    },
+}
+
+// llama.cpp seeds are 32 bit, a request seed is reduced to its low 32 bits, negative seeds in two's complement (-2 is 0xfffffffe).
+// None for no seed or low bits equal to LLAMA_DEFAULT_SEED, e.g. -1, which llama.cpp replaces with a random seed
+pub fn effective_seed(seed: Option<i64>) -> Option<u32> {
+    seed.map(|v| v as u32).filter(|&v| v != LLAMA_DEFAULT_SEED)
+}
+
+const DRAFT_SEED_MASK: u32 = 0xDEADBEEF;
+
+// the draft model samples with a seed derived from the request seed, reproducible but not the target's own
+pub fn draft_seed(seed: Option<i64>) -> Option<i64> {
+    let seed = effective_seed(seed)?;
+
+    // LLAMA_DEFAULT_SEED would be replaced with a random seed
+    let draft_seed = match seed ^ DRAFT_SEED_MASK {
+        LLAMA_DEFAULT_SEED => 0,
+        v => v,
+    };
+    Some(draft_seed as i64)
+}
 
 pub struct Sampler {
-    inner: *mut HibikiCommonSampler
+    inner: SamplerInner,
+    // the seed in effect, a random one if the request has none
+    pub seed: u32,
 }
 
 unsafe impl Send for Sampler {}
@@ -13,68 +267,455 @@ unsafe impl Sync for Sampler {}
 impl Sampler {
     pub fn new(
         model: &LlamaModel,
-        frequency_penalty: Option<f32>,
-        presence_penalty: Option<f32>,
-        seed: Option<i64>,
-        temperature: Option<f32>,
-        top_p: Option<f32>,
+        params: &SamplerParams,
     ) -> Sampler {
+        let seed = effective_seed(params.seed).unwrap_or_else(|| rand::random_range(0..LLAMA_DEFAULT_SEED));
+
+        let inner = if params.needs_chain() {
+            Self::new_chain(model, params, seed)
+        } else {
+            Self::new_common(model, params, seed as i32)
+        };
+
+        Sampler { inner, seed }
+    }
+
+    fn new_common(
+        model: &LlamaModel,
+        params: &SamplerParams,
+        seed: i32,
+    ) -> SamplerInner {
         unsafe {
-            let params = llama_cpp_sys_2::hibiki_common_params_sampling_init();
+            let sampling_params = llama_cpp_sys_2::hibiki_common_params_sampling_init();
 
-            if let Some(f) = frequency_penalty {
-                llama_cpp_sys_2::hibiki_common_params_sampling_set_frequency_penalty(params, f);
+            if let Some(f) = params.frequency_penalty {
+                llama_cpp_sys_2::hibiki_common_params_sampling_set_frequency_penalty(sampling_params, f);
             }
 
-            if let Some(p) = presence_penalty {
-                llama_cpp_sys_2::hibiki_common_params_sampling_set_presence_penalty(params, p);
+            if let Some(p) = params.presence_penalty {
+                llama_cpp_sys_2::hibiki_common_params_sampling_set_presence_penalty(sampling_params, p);
             }
 
-            llama_cpp_sys_2::hibiki_common_params_sampling_set_seed(params, seed.map(|v| v as i32).unwrap_or_else(|| rand::random()));
+            llama_cpp_sys_2::hibiki_common_params_sampling_set_seed(sampling_params, seed);
 
-            if let Some(t) = temperature {
-                llama_cpp_sys_2::hibiki_common_params_sampling_set_temperature(params, t);
+            if let Some(t) = params.temperature {
+                llama_cpp_sys_2::hibiki_common_params_sampling_set_temperature(sampling_params, t);
             }
 
-            if let Some(t) = top_p {
-                llama_cpp_sys_2::hibiki_common_params_sampling_set_top_p(params, t);
+            if let Some(t) = params.top_p {
+                llama_cpp_sys_2::hibiki_common_params_sampling_set_top_p(sampling_params, t);
             }
 
-            let inner = llama_cpp_sys_2::hibiki_common_sampler_init(model.as_ptr(), params);
-            llama_cpp_sys_2::hibiki_common_params_sampling_free(params);
+            let inner = llama_cpp_sys_2::hibiki_common_sampler_init(model.as_ptr(), sampling_params);
+            llama_cpp_sys_2::hibiki_common_params_sampling_free(sampling_params);
 
-            Sampler { inner }
+            SamplerInner::Common(inner)
         }
     }
 
-    pub fn sample(&mut self, ctx: &mut LlamaContext, idx: i32) -> LlamaToken {
+    fn new_chain(
+        model: &LlamaModel,
+        params: &SamplerParams,
+        seed: u32,
+    ) -> SamplerInner {
+        let order = params.sampler_order.as_deref().unwrap_or(&DEFAULT_SAMPLER_ORDER);
+
         unsafe {
-            let token = llama_cpp_sys_2 ::hibiki_common_sampler_sample(self.inner, ctx.context.as_ptr(), idx, false);
-            LlamaToken(token)
+            let mut chain = Vec::with_capacity(order.len() + 2);
+            let mut penalty_stages = Vec::new();
+
+            for stage in order {
+                if *stage == SamplerStage::Penalties {
+                    penalty_stages.push(chain.len());
+                }
+                chain.push(stage_init(model, params, *stage, seed));
+
+                // the windowed presence penalty runs right after the penalties it was taken out of
+                if let (SamplerStage::Penalties, Some(size)) = (stage, params.presence_penalty_context_window) {
+                    penalty_stages.push(chain.len());
+                    chain.push(ChainStage::PresenceWindow(PresenceWindow::new(params.presence_penalty.unwrap_or(0.0), size)));
+                }
+            }
+
+            chain.push(ChainStage::Native(llama_cpp_sys_2::llama_sampler_init_dist(seed)));
+
+            SamplerInner::Chain {
+                chain,
+                penalty_stages,
+                newline_token: params.penalty_reset_on_newline().then(|| model.token_nl()),
+                n_vocab: model.n_vocab(),
+                cur: Vec::new(),
+                cur_p: llama_token_data_array {
+                    data: std::ptr::null_mut(),
+                    size: 0,
+                    selected: -1,
+                    sorted: false,
+                },
+            }
+        }
+    }
+
+    pub fn sample(&mut self, ctx: &mut LlamaContext, idx: i32) -> LlamaToken {
+        match &mut self.inner {
+            SamplerInner::Common(inner) => unsafe {
+                let token = llama_cpp_sys_2 ::hibiki_common_sampler_sample(*inner, ctx.context.as_ptr(), idx, false);
+                LlamaToken(token)
+            }
+            SamplerInner::Chain { chain, n_vocab, cur, cur_p } => unsafe {
+                let logits = llama_cpp_sys_2::llama_get_logits_ith(ctx.context.as_ptr(), idx);
+
+                cur.clear();
+                cur.extend((0..*n_vocab).map(|id| llama_token_data {
+                    id,
+                    logit: *logits.add(id as usize),
+                    p: 0.0,
+                }));
+
+                *cur_p = llama_token_data_array {
+                    data: cur.as_mut_ptr(),
+                    size: cur.len(),
+                    selected: -1,
+                    sorted: false,
+                };
+
+                for stage in chain.iter() {
+                    stage.apply(cur_p);
+                }
+
+                let token = (*cur_p.data.add(cur_p.selected as usize)).id;
+
+                // speculative decoding looks up the draft token in the sorted candidates
+                sort_candidates(cur_p);
+
+                LlamaToken(token)
+            }
         }
     }
 
     pub fn accept(&mut self, token: LlamaToken) {
         unsafe {
-            llama_cpp_sys_2::hibiki_common_sampler_accept(self.inner, token.0, false);
+            match &mut self.inner {
+                SamplerInner::Common(inner) => llama_cpp_sys_2::hibiki_common_sampler_accept(*inner, token.0, false),
+                SamplerInner::Chain { chain, penalty_stages, newline_token, .. } => {
+                    chain.iter_mut().for_each(|stage| stage.accept(token));
+
+                    // the newline itself isn't penalized in the next paragraph
+                    if *newline_token == Some(token) {
+                        penalty_stages.iter().for_each(|i| chain[*i].reset());
+                    }
+                }
+            }
         }
     }
 
     pub fn reset(&mut self) {
         unsafe {
-            llama_cpp_sys_2::hibiki_common_sampler_reset(self.inner);
+            match &mut self.inner {
+                SamplerInner::Common(inner) => llama_cpp_sys_2::hibiki_common_sampler_reset(*inner),
+                SamplerInner::Chain { chain, .. } => chain.iter_mut().for_each(|stage| stage.reset()),
+            }
         }
     }
 
     pub fn get_candidates(&self) -> &llama_token_data_array{
-        unsafe {
-            &*llama_cpp_sys_2::hibiki_common_sampler_get_candidates(self.inner)
+        match &self.inner {
+            SamplerInner::Common(inner) => unsafe {
+                &*llama_cpp_sys_2::hibiki_common_sampler_get_candidates(*inner)
+            }
+            SamplerInner::Chain { cur_p, .. } => cur_p
+        }
+    }
+
+    // (token id, probability) of the n most likely candidates of the last sample, after the whole pipeline
+    pub fn top_candidates(&self, n: usize) -> Vec<(i32, f32)> {
+        let candidates = self.get_candidates();
+
+        if candidates.data.is_null() {
+            return Vec::new();
+        }
+
+        let candidates = unsafe { std::slice::from_raw_parts(candidates.data, candidates.size) };
+        let mut top = candidates.iter().map(|td| (td.id, td.p)).collect::<Vec<_>>();
+
+        // the candidates can be the whole vocabulary, only the top n are sorted
+        if n < top.len() {
+            top.select_nth_unstable_by(n, |a, b| b.1.total_cmp(&a.1));
+            top.truncate(n);
         }
+
+        top.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+        top
+    }
+
+    // probability of the last sampled token after the whole pipeline
+    pub fn selected_probability(&self) -> Option<f32> {
+        let candidates = self.get_candidates();
+
+        if candidates.selected < 0 || candidates.selected as usize >= candidates.size {
+            return None;
+        }
+        unsafe { Some((*candidates.data.add(candidates.selected as usize)).p) }
     }
 }
 
 impl Drop for Sampler {
     fn drop(&mut self) {
-        unsafe {llama_cpp_sys_2::hibiki_common_sampler_free(self.inner);}
+        unsafe {
+            match &self.inner {
+                SamplerInner::Common(inner) => llama_cpp_sys_2::hibiki_common_sampler_free(*inner),
+                SamplerInner::Chain { chain, .. } => chain.iter().for_each(|stage| stage.free()),
+            }
+        }
+    }
+}
+
+unsafe fn stage_init(
+    model: &LlamaModel,
+    params: &SamplerParams,
+    stage: SamplerStage,
+    seed: u32,
+) -> ChainStage {
+    let s = match stage {
+        SamplerStage::Penalties => penalties_init(params),
+        SamplerStage::Dry => {
+            let breakers = DEFAULT_DRY_SEQUENCE_BREAKERS.map(|s| CString::new(s).unwrap());
+            let mut breaker_ptrs = breakers.each_ref().map(|s| s.as_ptr() as *const c_char);
+
+            llama_cpp_sys_2::llama_sampler_init_dry(
+                llama_cpp_sys_2::llama_model_get_vocab(model.as_ptr()),
+                model.n_ctx_train() as i32,
+                params.dry_multiplier.unwrap_or(DEFAULT_DRY_MULTIPLIER),
+                DEFAULT_DRY_BASE,
+                DEFAULT_DRY_ALLOWED_LENGTH,
+                DEFAULT_DRY_PENALTY_LAST_N,
+                breaker_ptrs.as_mut_ptr(),
+                breaker_ptrs.len(),
+            )
+        }
+        SamplerStage::TopK => llama_cpp_sys_2::llama_sampler_init_top_k(params.top_k.unwrap_or(DEFAULT_TOP_K)),
+        SamplerStage::TypicalP => llama_cpp_sys_2::llama_sampler_init_typical(params.typical_p.unwrap_or(DEFAULT_TYPICAL_P), 0),
+        SamplerStage::TopP => llama_cpp_sys_2::llama_sampler_init_top_p(params.top_p.unwrap_or(DEFAULT_TOP_P), 0),
+        SamplerStage::MinP => llama_cpp_sys_2::llama_sampler_init_min_p(params.min_p.unwrap_or(DEFAULT_MIN_P), 0),
+        SamplerStage::TopA => return ChainStage::TopA(params.top_a.unwrap_or(0.0)),
+        SamplerStage::TopNSigma => return ChainStage::TopNSigma(params.top_n_sigma),
+        SamplerStage::Xtc => llama_cpp_sys_2::llama_sampler_init_xtc(DEFAULT_XTC_PROBABILITY, DEFAULT_XTC_THRESHOLD, 0, seed),
+        SamplerStage::Temperature => llama_cpp_sys_2::llama_sampler_init_temp_ext(params.temperature.unwrap_or(DEFAULT_TEMPERATURE), 0.0, 1.0),
+    };
+    ChainStage::Native(s)
+}
+
+// repeat: divides positive logits (multiplies negative ones) of the tokens within the window
+// frequency: subtracts penalty * occurrences, presence: subtracts penalty once
+unsafe fn penalties_init(params: &SamplerParams) -> *mut llama_sampler {
+    llama_cpp_sys_2::llama_sampler_init_penalties(
+        params.repeat_last_n.map(|n| n as i32).unwrap_or(DEFAULT_PENALTY_LAST_N),
+        params.repeat_penalty.unwrap_or(DEFAULT_REPEAT_PENALTY),
+        params.frequency_penalty.unwrap_or(0.0),
+        // applied by the PresenceWindow stage instead
+        if params.presence_penalty_context_window.is_some() { 0.0 } else { params.presence_penalty.unwrap_or(0.0) },
+    )
+}
+
+// sorts by logit descending and keeps the selected token pointing at the same candidate
+unsafe fn sort_candidates(cur_p: &mut llama_token_data_array) {
+    if cur_p.sorted {
+        return;
+    }
+
+    let candidates = std::slice::from_raw_parts_mut(cur_p.data, cur_p.size);
+    let selected = usize::try_from(cur_p.selected).ok().map(|i| candidates[i].id);
+
+    candidates.sort_unstable_by(|a, b| b.logit.total_cmp(&a.logit));
+
+    cur_p.selected = selected
+        .and_then(|id| candidates.iter().position(|td| td.id == id))
+        .map(|i| i as i64)
+        .unwrap_or(-1);
+    cur_p.sorted = true;
+}
+
+// removes tokens whose probability is below top_a * p_max^2
+unsafe fn top_a_apply(cur_p: &mut llama_token_data_array, top_a: f32) {
+    if top_a <= 0.0 || cur_p.size <= 1 {
+        return;
+    }
+
+    sort_candidates(cur_p);
+    let candidates = std::slice::from_raw_parts_mut(cur_p.data, cur_p.size);
+
+    let max_logit = candidates[0].logit;
+    let sum = candidates.iter().map(|td| (td.logit - max_logit).exp()).sum::<f32>();
+
+    for td in candidates.iter_mut() {
+        td.p = (td.logit - max_logit).exp() / sum;
+    }
+
+    let max_p = candidates[0].p;
+    let threshold = top_a * max_p * max_p;
+    let keep = candidates.iter().take_while(|td| td.p >= threshold).count();
+
+    cur_p.size = std::cmp::max(keep, 1);
+}
+
+// masks tokens whose logit is more than n standard deviations below the max logit,
+// the cutoff follows the spread of the raw logits instead of a fixed probability
+unsafe fn top_n_sigma_apply(cur_p: &mut llama_token_data_array, n: Option<f32>) {
+    let n = match n {
+        Some(n) if n >= 0.0 && cur_p.size > 1 => n,
+        _ => return,
+    };
+
+    let candidates = std::slice::from_raw_parts_mut(cur_p.data, cur_p.size);
+    let finite = || candidates.iter().map(|td| td.logit).filter(|l| l.is_finite());
+
+    let count = finite().count();
+
+    if count <= 1 {
+        return;
+    }
+
+    let max = finite().fold(f32::NEG_INFINITY, f32::max);
+    let mean = finite().sum::<f32>() / count as f32;
+    let variance = finite().map(|l| (l - mean) * (l - mean)).sum::<f32>() / count as f32;
+    let threshold = max - n * variance.sqrt();
+
+    for td in candidates.iter_mut() {
+        if td.logit < threshold {
+            td.logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // logits after the stage, the candidate array points into a vector of the given logits
+    fn apply_top_n_sigma(logits: &[f32], n: f32) -> Vec<f32> {
+        let mut cur = logits.iter()
+            .enumerate()
+            .map(|(id, logit)| llama_token_data { id: id as i32, logit: *logit, p: 0.0 })
+            .collect::<Vec<_>>();
+
+        let mut cur_p = llama_token_data_array {
+            data: cur.as_mut_ptr(),
+            size: cur.len(),
+            selected: -1,
+            sorted: false,
+        };
+
+        unsafe { top_n_sigma_apply(&mut cur_p, Some(n)) };
+        cur.iter().map(|td| td.logit).collect()
+    }
+
+    // logits after a native sampler that accepted the history
+    fn apply_native(sampler: *mut llama_sampler, history: &[i32], logits: &[f32]) -> Vec<f32> {
+        let mut cur = logits.iter()
+            .enumerate()
+            .map(|(id, logit)| llama_token_data { id: id as i32, logit: *logit, p: 0.0 })
+            .collect::<Vec<_>>();
+
+        let mut cur_p = llama_token_data_array {
+            data: cur.as_mut_ptr(),
+            size: cur.len(),
+            selected: -1,
+            sorted: false,
+        };
+
+        unsafe {
+            history.iter().for_each(|token| llama_cpp_sys_2::llama_sampler_accept(sampler, *token));
+            llama_cpp_sys_2::llama_sampler_apply(sampler, &mut cur_p);
+            llama_cpp_sys_2::llama_sampler_free(sampler);
+        }
+        cur.iter().map(|td| td.logit).collect()
+    }
+
+    #[test]
+    fn repeat_penalty_matches_llama_cpp() {
+        let params = SamplerParams {
+            repeat_penalty: Some(1.3),
+            repeat_last_n: Some(64),
+            ..SamplerParams::default()
+        };
+
+        let logits = vec![2.6, -2.6, 1.0, 0.5, 3.0];
+        // token 4 falls out of the 64 token window, 0 and 1 are within it
+        let history = std::iter::once(4).chain(std::iter::repeat(3).take(64 - 2)).chain([0, 1]).collect::<Vec<_>>();
+        assert_eq!(history.len(), 65);
+
+        let ours = apply_native(unsafe { penalties_init(&params) }, &history, &logits);
+        let native = apply_native(unsafe { llama_cpp_sys_2::llama_sampler_init_penalties(64, 1.3, 0.0, 0.0) }, &history, &logits);
+        assert_eq!(ours, native);
+
+        assert!((ours[0] - 2.0).abs() < 1e-5);
+        assert!((ours[1] + 3.38).abs() < 1e-5);
+        assert_eq!(ours[2], 1.0);
+        assert!((ours[3] - 0.5 / 1.3).abs() < 1e-5);
+        assert_eq!(ours[4], 3.0);
+    }
+
+    fn kept(logits: &[f32]) -> usize {
+        logits.iter().filter(|l| l.is_finite()).count()
+    }
+
+    #[test]
+    fn effective_seed_folds_to_32_bits() {
+        assert_eq!(effective_seed(None), None);
+        assert_eq!(effective_seed(Some(0)), Some(0));
+        assert_eq!(effective_seed(Some(42)), Some(42));
+        assert_eq!(effective_seed(Some(-2)), Some(0xFFFF_FFFE));
+        assert_eq!(effective_seed(Some((1 << 32) + 42)), Some(42));
+
+        // low bits of LLAMA_DEFAULT_SEED ask llama.cpp for a random seed
+        assert_eq!(effective_seed(Some(-1)), None);
+        assert_eq!(effective_seed(Some(i64::MAX)), None);
+        assert_eq!(effective_seed(Some(i64::MIN)), Some(0));
+    }
+
+    #[test]
+    fn draft_seed_is_derived_from_the_request_seed() {
+        assert_eq!(draft_seed(None), None);
+        assert_eq!(draft_seed(Some(-1)), None);
+
+        assert_eq!(draft_seed(Some(42)), Some((42 ^ DRAFT_SEED_MASK) as i64));
+        assert_eq!(draft_seed(Some(42)), draft_seed(Some(42)));
+        assert_ne!(draft_seed(Some(42)), Some(42));
+        assert_ne!(draft_seed(Some(42)), draft_seed(Some(43)));
+    }
+
+    #[test]
+    fn draft_seed_avoids_the_random_seed() {
+        // the mask maps this seed onto LLAMA_DEFAULT_SEED
+        let seed = (LLAMA_DEFAULT_SEED ^ DRAFT_SEED_MASK) as i64;
+        assert_eq!(draft_seed(Some(seed)), Some(0));
+    }
+
+    #[test]
+    fn top_n_sigma_sharp_distribution() {
+        let mut logits = vec![0.0; 100];
+        logits[7] = 30.0;
+
+        let out = apply_top_n_sigma(&logits, 1.0);
+        assert_eq!(kept(&out), 1);
+        assert_eq!(out[7], 30.0);
+    }
+
+    #[test]
+    fn top_n_sigma_flat_distribution() {
+        let logits = vec![1.5; 100];
+        assert_eq!(apply_top_n_sigma(&logits, 1.0), logits);
+
+        // a spread without outliers keeps everything within n sigma of the max
+        let logits = (0..100).map(|i| i as f32 * 0.01).collect::<Vec<_>>();
+        let out = apply_top_n_sigma(&logits, 1.0);
+        assert!(kept(&out) > 1 && kept(&out) < 100);
+        assert!(out[99].is_finite());
+        assert!(out[0].is_infinite());
+    }
+
+    #[test]
+    fn top_n_sigma_larger_n_keeps_more() {
+        let logits = (0..100).map(|i| (i as f32).sqrt()).collect::<Vec<_>>();
+        assert!(kept(&apply_top_n_sigma(&logits, 0.5)) < kept(&apply_top_n_sigma(&logits, 2.0)));
     }
 }
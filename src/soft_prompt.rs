@@ -0,0 +1,105 @@
+use anyhow::{anyhow, ensure, Result};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_sys_2::{llama_batch_free, llama_batch_init, llama_decode};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+// learned embeddings prepended to the prompt instead of token ids
+pub struct SoftPrompt {
+    pub name: String,
+    pub n_tokens: usize,
+    pub n_embd: usize,
+    data: Vec<f32>,
+}
+
+impl SoftPrompt {
+    // loads a little endian float32 npy tensor of shape [n_virtual_tokens, n_embd]
+    pub fn load(name: String, path: &Path) -> Result<SoftPrompt> {
+        let buf = std::fs::read(path)?;
+        ensure!(buf.len() >= 10 && buf.starts_with(NPY_MAGIC), "{} is not a npy file", path.display());
+
+        let (header_start, header_len) = match buf[6] {
+            1 => (10, u16::from_le_bytes([buf[8], buf[9]]) as usize),
+            _ => {
+                ensure!(buf.len() >= 12, "{} is not a npy file", path.display());
+                (12, u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize)
+            }
+        };
+
+        let data_start = header_start + header_len;
+        ensure!(buf.len() >= data_start, "{} has a truncated npy header", path.display());
+        let header = std::str::from_utf8(&buf[header_start..data_start])?;
+
+        ensure!(header.contains("'descr': '<f4'"), "soft prompt {} must be a little endian float32 tensor", name);
+        ensure!(header.contains("'fortran_order': False"), "soft prompt {} must be stored in C order", name);
+
+        let shape = header
+            .split_once("'shape': (")
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map(|(shape, _)| shape)
+            .ok_or_else(|| anyhow!("soft prompt {} has no shape", name))?
+            .split(',')
+            .map(|dim| dim.trim())
+            .filter(|dim| !dim.is_empty())
+            .map(|dim| dim.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        ensure!(shape.len() == 2, "soft prompt {} must have shape [n_virtual_tokens, n_embd], got {:?}", name, shape);
+
+        let data = buf[data_start..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+
+        ensure!(data.len() == shape[0] * shape[1], "soft prompt {} data doesn't match its shape {:?}", name, shape);
+        ensure!(shape[0] > 0, "soft prompt {} is empty", name);
+
+        let soft_prompt = SoftPrompt {
+            name,
+            n_tokens: shape[0],
+            n_embd: shape[1],
+            data,
+        };
+        Ok(soft_prompt)
+    }
+
+    // decodes the embeddings at positions 0..n_tokens of the sequence, the prompt tokens follow them
+    pub fn decode(&self, ctx: &mut LlamaContext, seq_id: i32) -> Result<()> {
+        unsafe {
+            let mut batch = llama_batch_init(self.n_tokens as i32, self.n_embd as i32, 1);
+            std::ptr::copy_nonoverlapping(self.data.as_ptr(), batch.embd, self.data.len());
+
+            for i in 0..self.n_tokens {
+                *batch.pos.add(i) = i as i32;
+                *batch.n_seq_id.add(i) = 1;
+                **batch.seq_id.add(i) = seq_id;
+                *batch.logits.add(i) = 0;
+            }
+            batch.n_tokens = self.n_tokens as i32;
+
+            let res = llama_decode(ctx.context.as_ptr(), batch);
+            llama_batch_free(batch);
+            ensure!(res == 0, "soft prompt {} decode failed: {}", self.name, res);
+        }
+        Ok(())
+    }
+}
+
+// args are <name>=<path>
+pub fn load_all(args: &[String]) -> Result<HashMap<String, Arc<SoftPrompt>>> {
+    let mut soft_prompts = HashMap::new();
+
+    for arg in args {
+        let (name, path) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid soft prompt {}, expected <name>=<path>", arg))?;
+
+        let soft_prompt = SoftPrompt::load(name.to_string(), Path::new(path))?;
+        info!("soft prompt {} loaded, {} virtual tokens", name, soft_prompt.n_tokens);
+        soft_prompts.insert(name.to_string(), Arc::new(soft_prompt));
+    }
+    Ok(soft_prompts)
+}
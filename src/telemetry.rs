@@ -0,0 +1,100 @@
+use crate::sampler::Sampler;
+use anyhow::Result;
+use chrono::Utc;
+use llama_cpp_2::token::LlamaToken;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+
+const TOP_TOKENS: usize = 10;
+
+#[derive(Serialize)]
+struct TokenProbability {
+    id: i32,
+    p: f32,
+}
+
+#[derive(Serialize)]
+struct SampleRecord {
+    timestamp: String,
+    model: String,
+    request_id: String,
+    selected_token: i32,
+    // after every sampler stage, most likely first
+    top_tokens: Vec<TokenProbability>,
+}
+
+// --sampling-telemetry-path, one json line for every sample_rate sampled tokens across all sequences,
+// a single writer task keeps the file io off the inference loop
+pub struct SamplingTelemetry {
+    tx: flume::Sender<SampleRecord>,
+    model_name: String,
+    sample_rate: u64,
+    sampled_tokens: AtomicU64,
+}
+
+impl SamplingTelemetry {
+    pub async fn open(path: &Path, model_name: String, sample_rate: u64) -> Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let (tx, rx) = flume::unbounded::<SampleRecord>();
+        let path = path.to_path_buf();
+
+        tokio::spawn(async move {
+            while let Ok(record) = rx.recv_async().await {
+                let mut line = match serde_json::to_vec(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("serialize sampling telemetry record failed: {}", e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+
+                if let Err(e) = file.write_all(&line).await {
+                    error!("write sampling telemetry {} failed: {}", path.display(), e);
+                    continue;
+                }
+
+                // a tokio file buffers the write, the record would be lost on shutdown
+                if let Err(e) = file.flush().await {
+                    error!("flush sampling telemetry {} failed: {}", path.display(), e);
+                }
+            }
+        });
+
+        Ok(SamplingTelemetry {
+            tx,
+            model_name,
+            sample_rate: sample_rate.max(1),
+            sampled_tokens: AtomicU64::new(0),
+        })
+    }
+
+    // called for every sampled token, only the recorded ones read the candidates
+    pub fn record(&self, request_id: &str, sampler: &Sampler, token: LlamaToken) {
+        if self.sampled_tokens.fetch_add(1, Ordering::Relaxed) % self.sample_rate != 0 {
+            return;
+        }
+
+        let top_tokens = sampler.top_candidates(TOP_TOKENS)
+            .into_iter()
+            .map(|(id, p)| TokenProbability { id, p })
+            .collect();
+
+        let record = SampleRecord {
+            timestamp: Utc::now().to_rfc3339(),
+            model: self.model_name.clone(),
+            request_id: request_id.to_string(),
+            selected_token: token.0,
+            top_tokens,
+        };
+
+        let _ = self.tx.send(record);
+    }
+}
@@ -0,0 +1,122 @@
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub const MODEL_NAME: &str = "test";
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(300);
+
+// one server at a time, every test loads its own copy of the model
+static SERVER_LOCK: Mutex<()> = Mutex::new(());
+
+// the hibiki binary serving the model of HIBIKI_TEST_MODEL_PATH, killed on drop
+pub struct TestServer {
+    child: Child,
+    pub addr: SocketAddr,
+    pub client: reqwest::Client,
+    _lock: MutexGuard<'static, ()>,
+}
+
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+impl TestServer {
+    // None when HIBIKI_TEST_MODEL_PATH isn't set, the test is skipped
+    pub async fn start(extra_args: &[&str]) -> Option<TestServer> {
+        let model_path = match std::env::var_os("HIBIKI_TEST_MODEL_PATH") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                eprintln!("HIBIKI_TEST_MODEL_PATH is not set, skipped");
+                return None;
+            }
+        };
+
+        // a failed test poisons the lock but leaves nothing behind
+        let lock = SERVER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let addr = free_addr();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_hibiki"))
+            .arg("--model-path").arg(&model_path)
+            .arg("--model-name").arg(MODEL_NAME)
+            .arg("--bind-addr").arg(addr.to_string())
+            .arg("--parallel-tasks").arg("1")
+            .arg("--kv-cache-size-pre-task").arg("512")
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start hibiki");
+
+        let server = TestServer {
+            child,
+            addr,
+            client: reqwest::Client::new(),
+            _lock: lock,
+        };
+
+        server.wait_ready().await;
+        Some(server)
+    }
+
+    // the loading progress server answers first, /v1/models only once the model is loaded
+    async fn wait_ready(&self) {
+        let start = Instant::now();
+
+        while start.elapsed() < STARTUP_TIMEOUT {
+            if let Ok(resp) = self.client.get(self.url("/v1/models")).send().await {
+                if resp.status().is_success() {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        panic!("hibiki not ready after {:?}", STARTUP_TIMEOUT);
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub async fn post_json(&self, path: &str, body: serde_json::Value) -> reqwest::Response {
+        self.client.post(self.url(path)).json(&body).send().await.unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// a small draft model with the vocabulary of HIBIKI_TEST_MODEL_PATH, tests of speculative decoding are skipped without it
+pub fn draft_model_path() -> Option<String> {
+    let path = std::env::var("HIBIKI_TEST_DRAFT_MODEL_PATH").ok();
+
+    if path.is_none() {
+        eprintln!("HIBIKI_TEST_DRAFT_MODEL_PATH is not set, skipped");
+    }
+    path
+}
+
+// data fields of the sse events in a complete response body
+pub fn sse_data(body: &str) -> Vec<String> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.trim_start().to_string())
+        .collect()
+}
+
+macro_rules! test_server {
+    ($($arg:expr),*) => {
+        match crate::common::TestServer::start(&[$($arg),*]).await {
+            Some(server) => server,
+            None => return,
+        }
+    };
+}
+
+pub(crate) use test_server;
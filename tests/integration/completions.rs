@@ -0,0 +1,92 @@
+use crate::common::{sse_data, test_server, MODEL_NAME};
+use serde_json::json;
+
+#[tokio::test]
+async fn basic_completion() {
+    let server = test_server!();
+
+    let resp = server.post_json("/v1/completions", json!({
+        "model": MODEL_NAME,
+        "prompt": "The capital of France is",
+        "max_tokens": 8,
+        "seed": 1,
+    })).await;
+
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+
+    assert_eq!(body["object"], "text_completion");
+    assert!(body["choices"][0]["text"].is_string());
+
+    let completion_tokens = body["usage"]["completion_tokens"].as_u64().unwrap();
+    assert!(completion_tokens <= 8);
+    assert!(body["usage"]["prompt_tokens"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn streaming_completion() {
+    let server = test_server!();
+
+    let resp = server.post_json("/v1/completions", json!({
+        "model": MODEL_NAME,
+        "prompt": "Once upon a time",
+        "max_tokens": 8,
+        "seed": 1,
+        "stream": true,
+        "stream_options": {"include_usage": true},
+    })).await;
+
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/event-stream"));
+
+    let body = resp.text().await.unwrap();
+
+    // clients compare the sentinel byte for byte
+    assert!(body.ends_with("\n\ndata: [DONE]\n\n"));
+
+    let events = sse_data(&body);
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+
+    let chunks = events[..events.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+        .collect::<Vec<_>>();
+
+    let text_chunks = chunks.iter()
+        .filter(|chunk| chunk["object"] == "text_completion" && chunk["usage"].is_null())
+        .collect::<Vec<_>>();
+    // up to 8 tokens and the finish chunk
+    assert!(text_chunks.len() > 1 && text_chunks.len() <= 9);
+
+    let usage = chunks.iter().find(|chunk| !chunk["usage"].is_null()).unwrap();
+    let completion_tokens = usage["usage"]["completion_tokens"].as_u64().unwrap();
+
+    // only the last text chunk has a finish_reason, "length" once max_tokens is reached
+    let (finish, tokens) = text_chunks.split_last().unwrap();
+    let finish_reason = if completion_tokens == 8 { "length" } else { "stop" };
+    assert_eq!(finish["choices"][0]["finish_reason"], finish_reason);
+    assert_eq!(finish["choices"][0]["text"], "");
+    assert!(tokens.iter().all(|chunk| chunk["choices"][0]["finish_reason"].is_null()));
+
+    // the timing summary is a field of the finish chunk, not a chunk of its own
+    assert!(finish["timing"].is_object());
+    assert!(chunks.iter().all(|chunk| chunk["object"] != "generation.timing"));
+}
+
+#[tokio::test]
+async fn chat_completion() {
+    let server = test_server!();
+
+    let resp = server.post_json("/v1/chat/completions", json!({
+        "model": MODEL_NAME,
+        "messages": [{"role": "user", "content": "Hello"}],
+        "max_tokens": 8,
+        "seed": 1,
+    })).await;
+
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+
+    assert_eq!(body["object"], "chat.completion");
+    assert_eq!(body["choices"][0]["message"]["role"], "assistant");
+}
@@ -0,0 +1,46 @@
+use crate::common::{test_server, MODEL_NAME};
+use serde_json::json;
+
+#[tokio::test]
+async fn unknown_model() {
+    let server = test_server!();
+
+    let resp = server.post_json("/v1/completions", json!({
+        "model": "does-not-exist",
+        "prompt": "Hello",
+        "max_tokens": 1,
+    })).await;
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["param"], "model");
+}
+
+#[tokio::test]
+async fn bad_json() {
+    let server = test_server!();
+
+    let resp = server.client.post(server.url("/v1/completions"))
+        .header("content-type", "application/json")
+        .body("{\"prompt\": ")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+}
+
+#[tokio::test]
+async fn missing_prompt() {
+    let server = test_server!();
+
+    let resp = server.post_json("/v1/completions", json!({"model": MODEL_NAME, "max_tokens": 1})).await;
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["param"], "prompt");
+}
@@ -0,0 +1,9 @@
+// runs the hibiki binary against a small model, e.g. a 1B gguf:
+// HIBIKI_TEST_MODEL_PATH=model.gguf cargo test --test integration
+// without HIBIKI_TEST_MODEL_PATH every test returns early
+// HIBIKI_TEST_DRAFT_MODEL_PATH adds the speculative decoding tests
+mod common;
+mod completions;
+mod errors;
+mod models;
+mod sampling;
@@ -0,0 +1,37 @@
+use crate::common::{sse_data, test_server, MODEL_NAME};
+
+#[tokio::test]
+async fn models() {
+    let server = test_server!();
+
+    let resp = server.client.get(server.url("/v1/models")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["object"], "list");
+    assert_eq!(body["data"][0]["id"], MODEL_NAME);
+}
+
+#[tokio::test]
+async fn model_info() {
+    let server = test_server!();
+
+    let resp = server.client.get(server.url("/v1/model/info")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert!(body.is_object());
+}
+
+// the health check of a loaded server, the stream ends with the ready event
+#[tokio::test]
+async fn loading_progress_ready() {
+    let server = test_server!();
+
+    let resp = server.client.get(server.url("/v1/model/loading-progress")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let events = sse_data(&resp.text().await.unwrap());
+    let last: serde_json::Value = serde_json::from_str(events.last().unwrap()).unwrap();
+    assert_eq!(last["stage"], "ready");
+}
@@ -0,0 +1,101 @@
+use crate::common::{draft_model_path, test_server, MODEL_NAME};
+use serde_json::json;
+
+#[tokio::test]
+async fn frequency_penalty_reduces_repetition() {
+    let server = test_server!();
+
+    let mut repeated = Vec::new();
+
+    // greedy decoding continues the run of the prompt, the penalty counts the prompt words as well
+    for frequency_penalty in [0.0, 2.0] {
+        let resp = server.post_json("/v1/completions", json!({
+            "model": MODEL_NAME,
+            "prompt": "apple apple apple apple apple apple apple apple apple apple apple apple apple apple apple apple",
+            "max_tokens": 32,
+            "seed": 7,
+            "temperature": 0,
+            "frequency_penalty": frequency_penalty,
+        })).await;
+
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let text = body["choices"][0]["text"].as_str().unwrap();
+        repeated.push(text.matches("apple").count());
+    }
+
+    assert!(repeated[0] > 0, "the prompt didn't repeat without penalty");
+    assert!(repeated[1] < repeated[0], "apples without penalty: {}, with penalty: {}", repeated[0], repeated[1]);
+}
+
+#[tokio::test]
+async fn penalty_out_of_range() {
+    let server = test_server!();
+
+    // repeat_last_n past the 512 tokens of the test context
+    let fields = [
+        ("frequency_penalty", json!(2.5)),
+        ("presence_penalty", json!(2.5)),
+        ("repeat_last_n", json!(2_000_000_000u32)),
+    ];
+
+    for (param, value) in fields {
+        let resp = server.post_json("/v1/completions", json!({
+            "model": MODEL_NAME,
+            "prompt": "Hello",
+            "max_tokens": 1,
+            param: value,
+        })).await;
+
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["param"], param);
+    }
+}
+
+// 0 is a regular seed, -1 and i64::MAX fold to the random seed of llama.cpp
+#[tokio::test]
+async fn seed_edge_values() {
+    let server = test_server!();
+
+    for seed in [0, -1, i64::MAX] {
+        let resp = server.post_json("/v1/completions", json!({
+            "model": MODEL_NAME,
+            "prompt": "Hello",
+            "max_tokens": 4,
+            "seed": seed,
+        })).await;
+
+        assert_eq!(resp.status(), 200, "seed {}", seed);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert!(body["choices"][0]["text"].is_string());
+    }
+}
+
+// the draft sampler is seeded from the request seed, so a seeded speculative run is reproducible
+#[tokio::test]
+async fn speculative_same_seed_same_output() {
+    let draft_model_path = match draft_model_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let server = test_server!("--draft-model-path", draft_model_path.as_str());
+
+    let mut outputs = Vec::new();
+
+    for _ in 0..2 {
+        let resp = server.post_json("/v1/completions", json!({
+            "model": MODEL_NAME,
+            "prompt": "Once upon a time",
+            "max_tokens": 32,
+            "seed": 1234,
+            "temperature": 0.8,
+        })).await;
+
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        outputs.push(body["choices"][0]["text"].as_str().unwrap().to_string());
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+}